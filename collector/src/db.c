@@ -2,6 +2,8 @@
 #include "db.h"
 #include "hmac.h"
 #include "base64.h"
+#include "metrics.h"
+#include "output.h"
 
 #include <libpq-fe.h>
 #include <stdio.h>
@@ -16,6 +18,27 @@
 #define BASE64_ENCODED_SIZE 89
 #define POSTGRES_DATA_PREPARED_STMT_NAME "1"
 #define POSTGRES_HEALTHCHECK_PREPARED_STMT_NAME "2"
+#define POSTGRES_LAG_PREPARED_STMT_NAME "3"
+#define POSTGRES_PREVIEW_PREPARED_STMT_NAME "4"
+#define POSTGRES_PENDING_PREPARED_STMT_NAME "5"
+
+// Tokens the queue has not emitted yet.
+#define PENDING_TOKENS                                                     \
+  "    FROM "                                                              \
+  "        jobs "                                                          \
+  "    JOIN tokens t "                                                     \
+  "        ON t.id > jobs.last_seq "                                       \
+  "        AND t.expires_at > EXTRACT(EPOCH FROM NOW()) "                  \
+  "        AND t.consumed_at IS NULL "                                     \
+  "        AND t.action IN ('activation', 'password_recovery') "           \
+  "    JOIN accounts a "                                                   \
+  "        ON a.id = t.account "                                           \
+  "        AND ( "                                                         \
+  "            (t.action = 'activation' AND a.status = 'provisioned') "    \
+  "            OR (t.action = 'password_recovery' AND a.status = 'active') " \
+  "        ) "                                                             \
+  "    WHERE "                                                             \
+  "        jobs.job_type = $1 "
 
 static const char *token_data =
     "WITH token_data AS ( "
@@ -28,21 +51,7 @@ static const char *token_data =
     "        t.action, "
     "        a.email, "
     "        a.login "
-    "    FROM "
-    "        jobs "
-    "    JOIN tokens t "
-    "        ON t.id > jobs.last_seq "
-    "        AND t.expires_at > EXTRACT(EPOCH FROM NOW()) "
-    "        AND t.consumed_at IS NULL "
-    "        AND t.action IN ('activation', 'password_recovery') "
-    "    JOIN accounts a "
-    "        ON a.id = t.account "
-    "        AND ( "
-    "            (t.action = 'activation' AND a.status = 'provisioned') "
-    "            OR (t.action = 'password_recovery' AND a.status = 'active') "
-    "        ) "
-    "    WHERE "
-    "        jobs.job_type = $1 "
+    PENDING_TOKENS
     "    ORDER BY id ASC "
     "    LIMIT $2 "
     "), "
@@ -61,13 +70,40 @@ static const char *token_data =
     "    td.email, "
     "    td.login, "
     "    td.secret, "
-    "    td.code "
+    "    td.code, "
+    "    td.expires_at "
     "FROM "
     "    token_data td";
 
-bool db_prepare_statement(PGconn *conn, const char *stmt_name, const char *query)
+// How far the queue is behind: the rows pending and the age of the oldest.
+static const char *token_lag =
+    "SELECT "
+    "    COUNT(*), "
+    "    COALESCE(EXTRACT(EPOCH FROM NOW())::bigint - MIN(t.created_at), 0) "
+    PENDING_TOKENS;
+
+// The action and address of the rows token_data would return, without
+// advancing the queue. Their tokens are left out, so that a dry run doesn't
+// print links that work.
+static const char *token_preview =
+    "SELECT "
+    "    t.action, "
+    "    a.email "
+    PENDING_TOKENS
+    "ORDER BY t.id ASC "
+    "LIMIT $2";
+
+static const char *token_pending =
+    "SELECT "
+    "    t.action, "
+    "    COUNT(*) AS count "
+    PENDING_TOKENS
+    "GROUP BY t.action "
+    "ORDER BY t.action";
+
+bool db_prepare_statement(PGconn *conn, const char *stmt_name, const char *query, int nparams)
 {
-  PGresult *res = PQprepare(conn, stmt_name, query, 2, NULL);
+  PGresult *res = PQprepare(conn, stmt_name, query, nparams, NULL);
   if (PQresultStatus(res) != PGRES_COMMAND_OK)
   {
     PQclear(res);
@@ -108,8 +144,9 @@ static int _db_dequeue(PGconn *conn, const char *queue, int limit)
   static char limitstr[12];
 
   PGresult *res = NULL;
-  int action_col, email_col, login_col, code_col, secret_col;
-  char *action, *email, *login, *code, *secret_text;
+  int action_col, email_col, login_col, code_col, secret_col, expires_col;
+  char *action, *email, *login, *code, *secret_text, *expires_at;
+  int action_id;
   unsigned char *secret = NULL;
   size_t secret_len;
   int nrows;
@@ -145,9 +182,10 @@ static int _db_dequeue(PGconn *conn, const char *queue, int limit)
   login_col = PQfnumber(res, "login");
   code_col = PQfnumber(res, "code");
   secret_col = PQfnumber(res, "secret");
+  expires_col = PQfnumber(res, "expires_at");
 
   if (action_col == -1 || email_col == -1 || login_col == -1 ||
-      code_col == -1 || secret_col == -1)
+      code_col == -1 || secret_col == -1 || expires_col == -1)
   {
     log_printf("FATAL: missing columns in the result set");
     PQclear(res);
@@ -163,35 +201,36 @@ static int _db_dequeue(PGconn *conn, const char *queue, int limit)
     login = PQgetvalue(res, i, login_col);
     code = PQgetvalue(res, i, code_col);
     secret_text = PQgetvalue(res, i, secret_col);
+    expires_at = PQgetvalue(res, i, expires_col);
 
     secret = PQunescapeBytea((unsigned char *)secret_text, &secret_len);
     if (!secret || secret_len != 32)
     {
       log_printf("WARN: skipping row; PQunescapeBytea failed or invalid secret length");
+      metrics_skipped();
       continue;
     }
 
     if (strcmp(action, "activation") == 0)
     {
-      printf("%d", 1);
+      action_id = 1;
     }
     else if (strcmp(action, "password_recovery") == 0)
     {
-      printf("%d", 2);
+      action_id = 2;
     }
     else
     {
-      printf("%d", 0);
+      action_id = 0;
     }
 
-    printf(",%s,%s,", email, login);
-
     signature_len = construct_signature_data(signature_buffer, action, secret, code);
 
     hmac_len = HMAC_RESULT_SIZE;
     if (!hmac_sign(signature_buffer, signature_len, hmac_result, &hmac_len))
     {
       log_printf("WARN: skipping row; HMAC signing failed");
+      metrics_skipped();
       PQfreemem(secret);
       continue;
     }
@@ -202,22 +241,19 @@ static int _db_dequeue(PGconn *conn, const char *queue, int limit)
     if (!base64_urlencode(base64_encoded, sizeof(base64_encoded), combined_buffer, 32 + hmac_len))
     {
       log_printf("WARN: skipping row; base64 encoding failed");
+      metrics_skipped();
       PQfreemem(secret);
       continue;
     }
 
-    printf("%s,%s", base64_encoded, code);
+    // The sender drops the row if the token expires before it is sent.
+    const char *values[OUTPUT_VALUES] = {email, login, base64_encoded, code};
+    output_row(action_id, expires_at, values);
 
     PQfreemem(secret);
-
-    if (i < nrows - 1)
-    {
-      printf(",");
-    }
   }
 
-  printf("\n");
-  fflush(stdout);
+  output_flush();
   PQclear(res);
 
   return nrows;
@@ -245,12 +281,148 @@ int db_dequeue(PGconn *conn, const char *queue, int remaining, int max_chunk_siz
       return result;
     }
     total += result;
+    metrics_consumed(result);
     remaining -= chunk_size;
     sleep_microseconds(10000); // 10ms
   }
   return total;
 }
 
+int db_preview(PGconn *conn, const char *queue, int limit)
+{
+  if (limit <= 0)
+  {
+    return 0;
+  }
+
+  char limitstr[12];
+  snprintf(limitstr, sizeof(limitstr), "%d", limit);
+  const char *params[2] = {queue, limitstr};
+
+  PGresult *res = PQexecPrepared(conn, POSTGRES_PREVIEW_PREPARED_STMT_NAME, 2, params, NULL, NULL, 0);
+  if (PQresultStatus(res) != PGRES_TUPLES_OK)
+  {
+    log_printf("ERROR: query execution failed: %s", PQerrorMessage(conn));
+    PQclear(res);
+    return -1;
+  }
+
+  int nrows = PQntuples(res);
+  for (int i = 0; i < nrows; i++)
+  {
+    printf("%s,%s\n", PQgetvalue(res, i, 0), PQgetvalue(res, i, 1));
+  }
+  fflush(stdout);
+  PQclear(res);
+
+  return nrows;
+}
+
+int db_report_pending(PGconn *conn, const char *queue)
+{
+  const char *params[1] = {queue};
+
+  PGresult *res = PQexecPrepared(conn, POSTGRES_PENDING_PREPARED_STMT_NAME, 1, params, NULL, NULL, 0);
+  if (PQresultStatus(res) != PGRES_TUPLES_OK)
+  {
+    log_printf("ERROR: query execution failed: %s", PQerrorMessage(conn));
+    PQclear(res);
+    return -1;
+  }
+
+  int total = 0;
+  for (int i = 0; i < PQntuples(res); i++)
+  {
+    log_printf("pending; action=%s rows=%s", PQgetvalue(res, i, 0), PQgetvalue(res, i, 1));
+    total += atoi(PQgetvalue(res, i, 1));
+  }
+  PQclear(res);
+
+  log_printf("pending; total=%d", total);
+
+  return total;
+}
+
+// Runs the query of a scheduled job and emits its rows for `action`, at most
+// `limit` to a line. The first column is the email address and the next ones
+// fill the fields after it in order.
+int db_run_job(PGconn *conn, int action, const char *query, int limit)
+{
+  PGresult *res = PQexecParams(conn, query, 0, NULL, NULL, NULL, NULL, 0);
+  if (PQresultStatus(res) != PGRES_TUPLES_OK)
+  {
+    log_printf("ERROR: scheduled query failed: %s", PQerrorMessage(conn));
+    PQclear(res);
+    return -1;
+  }
+
+  int ncols = PQnfields(res);
+  if (ncols < 1 || ncols > OUTPUT_VALUES)
+  {
+    log_printf("ERROR: scheduled query must return from 1 to %d columns, got %d", OUTPUT_VALUES, ncols);
+    PQclear(res);
+    return -1;
+  }
+
+  int nrows = PQntuples(res);
+  for (int i = 0; i < nrows; i++)
+  {
+    const char *values[OUTPUT_VALUES] = {"", "", "", ""};
+    for (int j = 0; j < ncols; j++)
+    {
+      values[j] = PQgetvalue(res, i, j);
+    }
+    output_row(action, NULL, values);
+
+    if (i == nrows - 1 || (i + 1) % limit == 0)
+    {
+      output_flush();
+    }
+  }
+  PQclear(res);
+
+  return nrows;
+}
+
+// The number of rows pending in the queue and the age in seconds of the
+// oldest of them.
+bool db_queue_lag(PGconn *conn, const char *queue, long *depth, long *age)
+{
+  const char *params[1] = {queue};
+
+  PGresult *res = PQexecPrepared(conn, POSTGRES_LAG_PREPARED_STMT_NAME, 1, params, NULL, NULL, 0);
+  if (PQresultStatus(res) != PGRES_TUPLES_OK)
+  {
+    log_printf("ERROR: query execution failed: %s", PQerrorMessage(conn));
+    PQclear(res);
+    return false;
+  }
+
+  *depth = atol(PQgetvalue(res, 0, 0));
+  *age = atol(PQgetvalue(res, 0, 1));
+  PQclear(res);
+
+  return true;
+}
+
+int db_try_lock(PGconn *conn, const char *key)
+{
+  const char *params[1] = {key};
+
+  PGresult *res = PQexecParams(conn, "SELECT pg_try_advisory_lock($1::bigint)", 1, NULL, params, NULL, NULL, 0);
+  if (PQresultStatus(res) != PGRES_TUPLES_OK)
+  {
+    log_printf("ERROR: failed to acquire advisory lock: %s", PQerrorMessage(conn));
+    PQclear(res);
+    return -1;
+  }
+
+  int acquired = strcmp(PQgetvalue(res, 0, 0), "t") == 0;
+  PQclear(res);
+
+  return acquired;
+}
+
 bool db_healthcheck(PGconn *conn)
 {
   if (!conn || PQstatus(conn) != CONNECTION_OK)
@@ -304,6 +476,9 @@ bool db_connect(PGconn **conn, const char *conninfo, const char *channel)
 
   return PQstatus(*conn) == CONNECTION_OK &&
          db_listen(*conn, channel) &&
-         db_prepare_statement(*conn, POSTGRES_HEALTHCHECK_PREPARED_STMT_NAME, "SELECT 1") &&
-         db_prepare_statement(*conn, POSTGRES_DATA_PREPARED_STMT_NAME, token_data);
+         db_prepare_statement(*conn, POSTGRES_HEALTHCHECK_PREPARED_STMT_NAME, "SELECT 1", 0) &&
+         db_prepare_statement(*conn, POSTGRES_DATA_PREPARED_STMT_NAME, token_data, 2) &&
+         db_prepare_statement(*conn, POSTGRES_LAG_PREPARED_STMT_NAME, token_lag, 1) &&
+         db_prepare_statement(*conn, POSTGRES_PREVIEW_PREPARED_STMT_NAME, token_preview, 2) &&
+         db_prepare_statement(*conn, POSTGRES_PENDING_PREPARED_STMT_NAME, token_pending, 1);
 }
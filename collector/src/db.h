@@ -7,5 +7,10 @@
 int db_dequeue(PGconn *conn, const char *queue, int limit, int max_chunk_size);
 bool db_connect(PGconn **conn, const char *conninfo, const char *channel);
 bool db_healthcheck(PGconn *conn);
+bool db_queue_lag(PGconn *conn, const char *queue, long *depth, long *age);
+int db_try_lock(PGconn *conn, const char *key);
+int db_run_job(PGconn *conn, int action, const char *query, int limit);
+int db_preview(PGconn *conn, const char *queue, int limit);
+int db_report_pending(PGconn *conn, const char *queue);
 
 #endif // DB_H
@@ -2,9 +2,15 @@
 
 #include <stdio.h>
 #include <stdarg.h>
+#include <string.h>
 #include <time.h>
 
-static void get_timestamp_utc(char *buffer, size_t size)
+#define LOG_MESSAGE_SIZE 4096
+
+static bool json_format = false;
+static unsigned long errors = 0;
+
+static void get_timestamp_utc(char *buffer, size_t size, bool iso8601)
 {
   struct timespec ts;
   clock_gettime(CLOCK_REALTIME, &ts);
@@ -12,7 +18,7 @@ static void get_timestamp_utc(char *buffer, size_t size)
   struct tm *utc_time = gmtime(&ts.tv_sec);
   if (utc_time)
   {
-    snprintf(buffer, size, "%04d/%02d/%02d %02d:%02d:%02d",
+    snprintf(buffer, size, iso8601 ? "%04d-%02d-%02dT%02d:%02d:%02dZ" : "%04d/%02d/%02d %02d:%02d:%02d",
              utc_time->tm_year + 1900,
              utc_time->tm_mon + 1,
              utc_time->tm_mday,
@@ -26,14 +32,110 @@ static void get_timestamp_utc(char *buffer, size_t size)
   }
 }
 
+// Log lines carry their level as a prefix of the message; lines without one
+// are informational. Returns the level and skips `*message` past the prefix.
+static const char *take_level(const char **message)
+{
+  static const char *prefixes[][2] = {
+      {"PANIC: ", "error"},
+      {"FATAL: ", "error"},
+      {"ERROR: ", "error"},
+      {"WARN: ", "warn"},
+  };
+
+  for (size_t i = 0; i < sizeof(prefixes) / sizeof(prefixes[0]); i++)
+  {
+    size_t len = strlen(prefixes[i][0]);
+    if (strncmp(*message, prefixes[i][0], len) == 0)
+    {
+      *message += len;
+      return prefixes[i][1];
+    }
+  }
+
+  return "info";
+}
+
+// Writes `value` as a JSON string.
+static void print_json_string(const char *value)
+{
+  fputc('"', stderr);
+  for (; *value; value++)
+  {
+    unsigned char c = (unsigned char)*value;
+    switch (c)
+    {
+    case '"':
+      fputs("\\\"", stderr);
+      break;
+    case '\\':
+      fputs("\\\\", stderr);
+      break;
+    case '\n':
+      fputs("\\n", stderr);
+      break;
+    case '\r':
+      fputs("\\r", stderr);
+      break;
+    case '\t':
+      fputs("\\t", stderr);
+      break;
+    default:
+      if (c < 0x20)
+      {
+        fprintf(stderr, "\\u%04x", c);
+      }
+      else
+      {
+        fputc(c, stderr);
+      }
+    }
+  }
+  fputc('"', stderr);
+}
+
+void log_set_json(bool json)
+{
+  json_format = json;
+}
+
+// The number of lines logged at the error level so far.
+unsigned long log_errors(void)
+{
+  return errors;
+}
+
 void log_printf(const char *format, ...)
 {
   va_list args;
   va_start(args, format);
-  char timestamp[20];
-  get_timestamp_utc(timestamp, sizeof(timestamp));
-  fprintf(stderr, "%s [PG] ", timestamp);
-  vfprintf(stderr, format, args);
-  fprintf(stderr, "\n");
+  char message[LOG_MESSAGE_SIZE];
+  vsnprintf(message, sizeof(message), format, args);
   va_end(args);
+
+  const char *text = message;
+  const char *level = take_level(&text);
+  if (strcmp(level, "error") == 0)
+  {
+    errors++;
+  }
+
+  char timestamp[32];
+  get_timestamp_utc(timestamp, sizeof(timestamp), json_format);
+
+  if (json_format)
+  {
+    // Messages such as libpq's errors end with a newline of their own.
+    size_t len = strlen(message);
+    while (len > 0 && message[len - 1] == '\n')
+    {
+      message[--len] = '\0';
+    }
+    fprintf(stderr, "{\"timestamp\":\"%s\",\"level\":\"%s\",\"component\":\"collector\",\"message\":", timestamp, level);
+    print_json_string(text);
+    fprintf(stderr, "}\n");
+    return;
+  }
+
+  fprintf(stderr, "%s [PG] %s\n", timestamp, message);
 }
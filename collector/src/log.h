@@ -1,6 +1,10 @@
 #ifndef LOG_H
 #define LOG_H
 
+#include <stdbool.h>
+
 void log_printf(const char *format, ...);
+void log_set_json(bool json);
+unsigned long log_errors(void);
 
 #endif // LOG_H
@@ -3,6 +3,10 @@
 #include "db.h"
 #include "hmac.h"
 #include "base64.h"
+#include "schedule.h"
+#include "metrics.h"
+#include "migrate.h"
+#include "output.h"
 
 #include <stdio.h>
 #include <stdlib.h>
@@ -25,12 +29,15 @@
 #define ENV_DB_CHANNEL_NAME "token_insert"
 #define ENV_DB_QUEUE_NAME "mailroom"
 #define ENV_DB_HEALTHCHECK_INTERVAL 270000
+#define ENV_MIGRATIONS_DIR "migrations"
 
 unsigned char hmac_secret[HMAC_SECRET_SIZE] = {0};
 size_t hmac_secretlen = 0;
 
 static volatile sig_atomic_t running = 1;
 
+static struct schedule schedule;
+
 static void signal_handler(int sig)
 {
   log_printf("signal %d received. exiting...", sig);
@@ -122,10 +129,20 @@ static int exit_code(PGconn *conn, int code)
   {
     PQfinish(conn);
   }
+  schedule_free(&schedule);
+  output_free();
   hmac_cleanup();
   return code;
 }
 
+static void sleep_ms(long ms)
+{
+  struct timespec ts;
+  ts.tv_sec = ms / 1000;
+  ts.tv_nsec = (ms % 1000) * 1000000;
+  nanosleep(&ts, NULL);
+}
+
 static long get_current_time_ms(void)
 {
   struct timespec ts;
@@ -137,11 +154,22 @@ static long get_current_time_ms(void)
   return ts.tv_sec * 1000 + ts.tv_nsec / 1000000;
 }
 
-int main(void)
+int main(int argc, char **argv)
 {
   signal(SIGINT, signal_handler);
   signal(SIGTERM, signal_handler);
 
+  const char *log_format = getenv("MAILROOM_LOG_FORMAT");
+  if (log_format && strcmp(log_format, "json") == 0)
+  {
+    log_set_json(true);
+  }
+  else if (log_format && strcmp(log_format, "text") != 0)
+  {
+    log_printf("MAILROOM_LOG_FORMAT must be text or json");
+    return EXIT_FAILURE;
+  }
+
   const char *conninfo = getenv("MAILROOM_DATABASE_URL");
   if (!conninfo)
   {
@@ -149,6 +177,34 @@ int main(void)
     return EXIT_FAILURE;
   }
 
+  // `collector migrate` creates or upgrades the tables mailroom reads, and
+  // exits.
+  if (argc > 1)
+  {
+    if (strcmp(argv[1], "migrate") != 0 || argc > 2)
+    {
+      log_printf("usage: collector [migrate]");
+      return EXIT_FAILURE;
+    }
+
+    const char *migrations_dir = getenv("MAILROOM_MIGRATIONS_DIR");
+    if (!migrations_dir)
+    {
+      migrations_dir = ENV_MIGRATIONS_DIR;
+    }
+
+    PGconn *conn = PQconnectdb(conninfo);
+    if (PQstatus(conn) != CONNECTION_OK)
+    {
+      log_printf("ERROR: connection failed: %s", PQerrorMessage(conn));
+      PQfinish(conn);
+      return EXIT_FAILURE;
+    }
+    bool migrated = migrate(conn, migrations_dir);
+    PQfinish(conn);
+    return migrated ? EXIT_SUCCESS : EXIT_FAILURE;
+  }
+
   const char *hmac_secrethex = getenv("MAILROOM_SECRET_KEY");
   if (!hmac_secrethex)
   {
@@ -192,7 +248,41 @@ int main(void)
 
   int batch_limit = parse_env_int("MAILROOM_BATCH_LIMIT", ENV_BATCH_LIMIT);
 
-  log_printf("configured; channel=%s queue=%s limit=%d timeout=%dms healthcheck-interval=%dms", channel_name, queue_name, batch_limit, timeout_ms, healthcheck_ms);
+  if (batch_limit < 1)
+  {
+    log_printf("MAILROOM_BATCH_LIMIT must be at least 1");
+    return EXIT_FAILURE;
+  }
+
+  // When set, only the instance holding this advisory lock processes the queue;
+  // the others stand by until the leader's session ends.
+  const char *leader_lock = getenv("MAILROOM_LEADER_LOCK");
+  if (leader_lock)
+  {
+    char *endptr;
+    errno = 0;
+    strtoll(leader_lock, &endptr, 10);
+    if (errno == ERANGE || endptr == leader_lock || *endptr != '\0')
+    {
+      log_printf("MAILROOM_LEADER_LOCK must be a 64-bit integer");
+      return EXIT_FAILURE;
+    }
+  }
+
+  // When set, the queries in this file are run on their cron schedules and
+  // their rows emitted along with the queue's.
+  const char *schedule_file = getenv("MAILROOM_SCHEDULE_FILE");
+  if (schedule_file && !schedule_load(&schedule, schedule_file))
+  {
+    log_printf("failed to load MAILROOM_SCHEDULE_FILE");
+    return EXIT_FAILURE;
+  }
+
+  // When set, the collector reports the pending rows and prints up to this
+  // many of them, then exits without advancing the queue.
+  int dry_run = parse_env_int("MAILROOM_DRY_RUN", -1);
+
+  log_printf("configured; channel=%s queue=%s limit=%d timeout=%dms healthcheck-interval=%dms leader-lock=%s scheduled-jobs=%d", channel_name, queue_name, batch_limit, timeout_ms, healthcheck_ms, leader_lock ? leader_lock : "none", schedule.count);
 
   if (!hmac_init())
   {
@@ -200,7 +290,47 @@ int main(void)
     return EXIT_FAILURE;
   }
 
-  int result;
+  if (dry_run >= 0)
+  {
+    PGconn *conn = NULL;
+    if (!db_connect(&conn, conninfo, channel_name))
+    {
+      log_printf("ERROR: connection failed: %s", PQerrorMessage(conn));
+      return exit_code(conn, EXIT_FAILURE);
+    }
+    log_printf("dry run; the queue is not advanced");
+    if (db_report_pending(conn, queue_name) < 0 || db_preview(conn, queue_name, dry_run) < 0)
+    {
+      return exit_code(conn, EXIT_FAILURE);
+    }
+    return exit_code(conn, EXIT_SUCCESS);
+  }
+
+  // When set, counters and the queue's lag are served at /metrics on this
+  // address.
+  const char *metrics_addr = getenv("MAILROOM_METRICS_ADDR");
+  int metrics_fd = -1;
+  if (metrics_addr)
+  {
+    metrics_fd = metrics_listen(metrics_addr);
+    if (metrics_fd < 0)
+    {
+      log_printf("MAILROOM_METRICS_ADDR must be an address such as 127.0.0.1:9100");
+      return exit_code(NULL, EXIT_FAILURE);
+    }
+    log_printf("metrics endpoint listening on %s", metrics_addr);
+  }
+
+  // When set, rows matching the rules in this file are dropped or written to
+  // other files and FIFOs instead of stdout.
+  const char *rules_file = getenv("MAILROOM_RULES_FILE");
+  if (rules_file && !output_load_rules(rules_file))
+  {
+    log_printf("failed to load MAILROOM_RULES_FILE");
+    return exit_code(NULL, EXIT_FAILURE);
+  }
+
+  int result = 0;
 
   PGconn *conn = NULL;
 
@@ -237,6 +367,35 @@ int main(void)
 
       log_printf("connected");
 
+      if (leader_lock)
+      {
+        log_printf("waiting for leader lock %s...", leader_lock);
+
+        while (running && (result = db_try_lock(conn, leader_lock)) == 0)
+        {
+          metrics_serve(metrics_fd, conn, queue_name);
+          if (!db_healthcheck(conn))
+          {
+            result = -1;
+            break;
+          }
+          sleep_ms(timeout_ms);
+        }
+
+        if (!running)
+        {
+          break;
+        }
+
+        if (result < 0)
+        {
+          log_printf("WARN: forcing reconnect...");
+          continue;
+        }
+
+        log_printf("acquired leader lock %s", leader_lock);
+      }
+
       while (running && (result = db_dequeue(conn, queue_name, batch_limit, batch_limit)) == batch_limit)
         ;
 
@@ -248,6 +407,10 @@ int main(void)
       FD_ZERO(&active_fds);
       sock = PQsocket(conn);
       FD_SET(sock, &active_fds);
+      if (metrics_fd >= 0)
+      {
+        FD_SET(metrics_fd, &active_fds);
+      }
 
       seen = 0;
       ready = 0;
@@ -278,6 +441,8 @@ int main(void)
       last_healthcheck = get_current_time_ms();
     }
 
+    schedule_run(&schedule, conn, batch_limit);
+
     // Process any pending notifications before select()
     while (running && (notify = PQnotifies(conn)) != NULL)
     {
@@ -308,12 +473,20 @@ int main(void)
       remaining_ms = 0;
     }
 
+    // Wake up for the next minute's jobs if the batch doesn't time out first.
+    long schedule_ms = schedule_wait_ms(&schedule);
+    bool scheduled = schedule_ms >= 0 && schedule_ms < remaining_ms;
+    if (scheduled)
+    {
+      remaining_ms = schedule_ms;
+    }
+
     tv.tv_sec = remaining_ms / 1000;
     tv.tv_usec = (remaining_ms % 1000) * 1000;
 
     read_fds = active_fds;
 
-    rc = select(sock + 1, &read_fds, NULL, NULL, &tv);
+    rc = select((sock > metrics_fd ? sock : metrics_fd) + 1, &read_fds, NULL, NULL, &tv);
 
     if (rc < 0)
     {
@@ -330,7 +503,13 @@ int main(void)
       break;
     }
     else if (rc == 0)
-    {                                // Timeout occurred;
+    {
+      if (scheduled)
+      {
+        continue;
+      }
+
+      // Timeout occurred;
       start = get_current_time_ms(); // Reset the timer
 
       if (seen > 0)
@@ -362,6 +541,11 @@ int main(void)
       }
     }
 
+    if (metrics_fd >= 0 && FD_ISSET(metrics_fd, &read_fds))
+    {
+      metrics_serve(metrics_fd, conn, queue_name);
+    }
+
     if (!FD_ISSET(sock, &read_fds))
     {
       continue;
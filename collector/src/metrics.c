@@ -0,0 +1,229 @@
+#include "log.h"
+#include "db.h"
+#include "metrics.h"
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <unistd.h>
+#include <arpa/inet.h>
+#include <netinet/in.h>
+#include <sys/socket.h>
+#include <sys/time.h>
+
+#define METRICS_ACTIONS 10
+#define METRICS_REQUEST_SIZE 1024
+#define METRICS_BODY_SIZE 4096
+
+// Counters since the collector started, served in the Prometheus text format.
+static unsigned long consumed = 0;
+static unsigned long emitted[METRICS_ACTIONS] = {0};
+static unsigned long skipped = 0;
+static unsigned long dropped = 0;
+
+// Records rows read from the queue.
+void metrics_consumed(int rows)
+{
+  consumed += rows;
+}
+
+// Records a row written to stdout for the action with the id `action`.
+void metrics_emitted(int action)
+{
+  if (action >= 0 && action < METRICS_ACTIONS)
+  {
+    emitted[action]++;
+  }
+}
+
+// Records a row read from the queue that could not be emitted.
+void metrics_skipped(void)
+{
+  skipped++;
+}
+
+// Records a row a rule dropped.
+void metrics_dropped(void)
+{
+  dropped++;
+}
+
+// Listens on an address such as 127.0.0.1:9100 without blocking. Returns the
+// socket, or -1.
+int metrics_listen(const char *addr)
+{
+  const char *colon = strrchr(addr, ':');
+  if (!colon || colon == addr || (size_t)(colon - addr) >= INET_ADDRSTRLEN)
+  {
+    return -1;
+  }
+
+  char host[INET_ADDRSTRLEN];
+  memcpy(host, addr, colon - addr);
+  host[colon - addr] = '\0';
+
+  char *endptr;
+  long port = strtol(colon + 1, &endptr, 10);
+  if (endptr == colon + 1 || *endptr != '\0' || port < 1 || port > 65535)
+  {
+    return -1;
+  }
+
+  struct sockaddr_in sin;
+  memset(&sin, 0, sizeof(sin));
+  sin.sin_family = AF_INET;
+  sin.sin_port = htons((unsigned short)port);
+  if (inet_pton(AF_INET, host, &sin.sin_addr) != 1)
+  {
+    return -1;
+  }
+
+  int fd = socket(AF_INET, SOCK_STREAM, 0);
+  if (fd < 0)
+  {
+    return -1;
+  }
+
+  int on = 1;
+  setsockopt(fd, SOL_SOCKET, SO_REUSEADDR, &on, sizeof(on));
+
+  if (bind(fd, (struct sockaddr *)&sin, sizeof(sin)) != 0 ||
+      listen(fd, 8) != 0 ||
+      fcntl(fd, F_SETFL, fcntl(fd, F_GETFL) | O_NONBLOCK) != 0)
+  {
+    log_printf("ERROR: failed to listen on %s: %s", addr, strerror(errno));
+    close(fd);
+    return -1;
+  }
+
+  return fd;
+}
+
+static int format_metrics(char *body, size_t size, PGconn *conn, const char *queue)
+{
+  size_t len = 0;
+
+#define APPEND(...)                                              \
+  do                                                             \
+  {                                                              \
+    int n = snprintf(body + len, size - len, __VA_ARGS__);       \
+    if (n < 0 || (size_t)n >= size - len)                        \
+    {                                                            \
+      return -1;                                                 \
+    }                                                            \
+    len += n;                                                    \
+  } while (0)
+
+  APPEND("# HELP mailroom_collector_rows_consumed_total Rows read from the queue.\n");
+  APPEND("# TYPE mailroom_collector_rows_consumed_total counter\n");
+  APPEND("mailroom_collector_rows_consumed_total %lu\n", consumed);
+
+  APPEND("# HELP mailroom_collector_rows_emitted_total Rows written to stdout or the outputs of rules.\n");
+  APPEND("# TYPE mailroom_collector_rows_emitted_total counter\n");
+  for (int i = 0; i < METRICS_ACTIONS; i++)
+  {
+    if (emitted[i] > 0)
+    {
+      APPEND("mailroom_collector_rows_emitted_total{action=\"%d\"} %lu\n", i, emitted[i]);
+    }
+  }
+
+  APPEND("# HELP mailroom_collector_rows_skipped_total Rows read from the queue that could not be emitted.\n");
+  APPEND("# TYPE mailroom_collector_rows_skipped_total counter\n");
+  APPEND("mailroom_collector_rows_skipped_total %lu\n", skipped);
+
+  APPEND("# HELP mailroom_collector_rows_dropped_total Rows dropped by a rule.\n");
+  APPEND("# TYPE mailroom_collector_rows_dropped_total counter\n");
+  APPEND("mailroom_collector_rows_dropped_total %lu\n", dropped);
+
+  APPEND("# HELP mailroom_collector_errors_total Errors logged.\n");
+  APPEND("# TYPE mailroom_collector_errors_total counter\n");
+  APPEND("mailroom_collector_errors_total %lu\n", log_errors());
+
+  // The lag is left out when the database can't tell it.
+  long depth, age;
+  if (conn && db_queue_lag(conn, queue, &depth, &age))
+  {
+    APPEND("# HELP mailroom_collector_queue_depth Rows waiting in the queue.\n");
+    APPEND("# TYPE mailroom_collector_queue_depth gauge\n");
+    APPEND("mailroom_collector_queue_depth %ld\n", depth);
+
+    APPEND("# HELP mailroom_collector_oldest_pending_seconds Age of the oldest row waiting in the queue.\n");
+    APPEND("# TYPE mailroom_collector_oldest_pending_seconds gauge\n");
+    APPEND("mailroom_collector_oldest_pending_seconds %ld\n", age);
+  }
+
+#undef APPEND
+
+  return (int)len;
+}
+
+static void respond(int client, const char *status, const char *body, int len)
+{
+  char header[256];
+  int header_len = snprintf(header, sizeof(header),
+                            "HTTP/1.1 %s\r\n"
+                            "Content-Type: text/plain; version=0.0.4\r\n"
+                            "Content-Length: %d\r\n"
+                            "Connection: close\r\n\r\n",
+                            status, len);
+  if (write(client, header, header_len) == header_len && len > 0)
+  {
+    if (write(client, body, len) != len)
+    {
+      log_printf("WARN: failed to write metrics response");
+    }
+  }
+}
+
+// Answers the scrapes waiting on `fd`. Only GET /metrics is served.
+void metrics_serve(int fd, PGconn *conn, const char *queue)
+{
+  int client;
+  while (fd >= 0 && (client = accept(fd, NULL, NULL)) >= 0)
+  {
+    // On BSD and macOS the client inherits O_NONBLOCK from the listening
+    // socket, and its request may not have arrived yet.
+    fcntl(client, F_SETFL, fcntl(client, F_GETFL) & ~O_NONBLOCK);
+
+    // A slow client holds up the collector for a second at most.
+    struct timeval timeout = {1, 0};
+    setsockopt(client, SOL_SOCKET, SO_RCVTIMEO, &timeout, sizeof(timeout));
+    setsockopt(client, SOL_SOCKET, SO_SNDTIMEO, &timeout, sizeof(timeout));
+
+    char request[METRICS_REQUEST_SIZE];
+    size_t len = 0;
+    ssize_t n;
+    while (len < sizeof(request) - 1 && (n = read(client, request + len, sizeof(request) - 1 - len)) > 0)
+    {
+      len += n;
+      request[len] = '\0';
+      if (strstr(request, "\r\n\r\n"))
+      {
+        break;
+      }
+    }
+    request[len] = '\0';
+
+    if (strncmp(request, "GET /metrics ", 13) != 0 && strncmp(request, "GET /metrics?", 13) != 0)
+    {
+      respond(client, "404 Not Found", NULL, 0);
+      close(client);
+      continue;
+    }
+
+    char body[METRICS_BODY_SIZE];
+    int body_len = format_metrics(body, sizeof(body), conn, queue);
+    if (body_len < 0)
+    {
+      respond(client, "500 Internal Server Error", NULL, 0);
+    }
+    else
+    {
+      respond(client, "200 OK", body, body_len);
+    }
+    close(client);
+  }
+}
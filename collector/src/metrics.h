@@ -0,0 +1,13 @@
+#ifndef METRICS_H
+#define METRICS_H
+
+#include <libpq-fe.h>
+
+void metrics_consumed(int rows);
+void metrics_emitted(int action);
+void metrics_skipped(void);
+void metrics_dropped(void);
+int metrics_listen(const char *addr);
+void metrics_serve(int fd, PGconn *conn, const char *queue);
+
+#endif // METRICS_H
@@ -0,0 +1,261 @@
+#include "log.h"
+#include "migrate.h"
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <dirent.h>
+
+// Held while migrating, so that collectors started together don't apply the
+// same migration twice.
+#define MIGRATION_LOCK "7426173816532461"
+#define MIGRATION_SUFFIX ".up.sql"
+#define MIGRATION_PATH_SIZE 4096
+
+struct migration
+{
+  long long version;
+  char *name;
+};
+
+static int compare_migrations(const void *a, const void *b)
+{
+  long long x = ((const struct migration *)a)->version;
+  long long y = ((const struct migration *)b)->version;
+  return (x > y) - (x < y);
+}
+
+static bool exec(PGconn *conn, const char *sql)
+{
+  PGresult *res = PQexec(conn, sql);
+  ExecStatusType status = PQresultStatus(res);
+  PQclear(res);
+
+  if (status != PGRES_COMMAND_OK && status != PGRES_TUPLES_OK)
+  {
+    log_printf("ERROR: query execution failed: %s", PQerrorMessage(conn));
+    return false;
+  }
+
+  return true;
+}
+
+static char *read_file(const char *path)
+{
+  FILE *file = fopen(path, "rb");
+  if (!file)
+  {
+    log_printf("ERROR: failed to open %s: %s", path, strerror(errno));
+    return NULL;
+  }
+
+  char *content = NULL;
+  size_t len = 0, n;
+  char chunk[4096];
+  while ((n = fread(chunk, 1, sizeof(chunk), file)) > 0)
+  {
+    char *grown = realloc(content, len + n + 1);
+    if (!grown)
+    {
+      free(content);
+      fclose(file);
+      log_printf("PANIC: out of memory");
+      return NULL;
+    }
+    content = grown;
+    memcpy(content + len, chunk, n);
+    len += n;
+  }
+  fclose(file);
+
+  if (!content)
+  {
+    content = calloc(1, 1);
+  }
+  else
+  {
+    content[len] = '\0';
+  }
+
+  return content;
+}
+
+static void free_migrations(struct migration *migrations, int count)
+{
+  for (int i = 0; i < count; i++)
+  {
+    free(migrations[i].name);
+  }
+  free(migrations);
+}
+
+// Lists the migrations in `dir` named like 1_outbox.up.sql, by version.
+static int list_migrations(const char *dir, struct migration **migrations)
+{
+  DIR *d = opendir(dir);
+  if (!d)
+  {
+    log_printf("ERROR: failed to open %s: %s", dir, strerror(errno));
+    return -1;
+  }
+
+  int count = 0;
+  size_t suffix_len = strlen(MIGRATION_SUFFIX);
+  struct dirent *entry;
+
+  *migrations = NULL;
+  while ((entry = readdir(d)) != NULL)
+  {
+    const char *name = entry->d_name;
+    size_t len = strlen(name);
+    char *endptr;
+    long long version = strtoll(name, &endptr, 10);
+
+    if (len <= suffix_len || strcmp(name + len - suffix_len, MIGRATION_SUFFIX) != 0 ||
+        endptr == name || *endptr != '_')
+    {
+      continue;
+    }
+
+    struct migration *grown = realloc(*migrations, (count + 1) * sizeof(struct migration));
+    if (grown)
+    {
+      *migrations = grown;
+      grown[count].name = strdup(name);
+    }
+    if (!grown || !grown[count].name)
+    {
+      log_printf("PANIC: out of memory");
+      closedir(d);
+      free_migrations(*migrations, count);
+      return -1;
+    }
+    (*migrations)[count].version = version;
+    count++;
+  }
+  closedir(d);
+
+  qsort(*migrations, count, sizeof(struct migration), compare_migrations);
+
+  for (int i = 1; i < count; i++)
+  {
+    if ((*migrations)[i].version == (*migrations)[i - 1].version)
+    {
+      log_printf("ERROR: %s and %s have the same version", (*migrations)[i - 1].name, (*migrations)[i].name);
+      free_migrations(*migrations, count);
+      return -1;
+    }
+  }
+
+  return count;
+}
+
+// Records the version the schema is at, as go-migrate does, so that either
+// tool can take over from the other. A dirty version is one whose migration
+// didn't finish.
+static bool set_version(PGconn *conn, long long version, bool dirty)
+{
+  char sql[256];
+  snprintf(sql, sizeof(sql),
+           "BEGIN; "
+           "TRUNCATE schema_migrations; "
+           "INSERT INTO schema_migrations (version, dirty) VALUES (%lld, %s); "
+           "COMMIT;",
+           version, dirty ? "true" : "false");
+  return exec(conn, sql);
+}
+
+static bool apply(PGconn *conn, const char *dir, const struct migration *migrations, int count)
+{
+  PGresult *res = PQexec(conn, "SELECT version, dirty FROM schema_migrations LIMIT 1");
+  if (PQresultStatus(res) != PGRES_TUPLES_OK)
+  {
+    log_printf("ERROR: query execution failed: %s", PQerrorMessage(conn));
+    PQclear(res);
+    return false;
+  }
+
+  long long current = -1;
+  if (PQntuples(res) > 0)
+  {
+    current = atoll(PQgetvalue(res, 0, 0));
+    if (strcmp(PQgetvalue(res, 0, 1), "t") == 0)
+    {
+      log_printf("ERROR: the migration to version %lld did not finish; fix the schema, then set dirty to false in schema_migrations", current);
+      PQclear(res);
+      return false;
+    }
+  }
+  PQclear(res);
+
+  int applied = 0;
+  for (int i = 0; i < count; i++)
+  {
+    if (migrations[i].version <= current)
+    {
+      continue;
+    }
+
+    char path[MIGRATION_PATH_SIZE];
+    snprintf(path, sizeof(path), "%s/%s", dir, migrations[i].name);
+    char *sql = read_file(path);
+    if (!sql)
+    {
+      return false;
+    }
+
+    log_printf("applying %s", migrations[i].name);
+    bool ok = set_version(conn, migrations[i].version, true) && exec(conn, sql) &&
+              set_version(conn, migrations[i].version, false);
+    free(sql);
+    if (!ok)
+    {
+      log_printf("ERROR: failed to apply %s", migrations[i].name);
+      return false;
+    }
+
+    current = migrations[i].version;
+    applied++;
+  }
+
+  if (current < 0)
+  {
+    log_printf("no migrations in %s", dir);
+  }
+  else
+  {
+    log_printf("schema at version %lld; %d migrations applied", current, applied);
+  }
+
+  return true;
+}
+
+// Applies the migrations in `dir` the database doesn't have yet, in order.
+bool migrate(PGconn *conn, const char *dir)
+{
+  struct migration *migrations = NULL;
+  int count = list_migrations(dir, &migrations);
+  bool ok = count >= 0 &&
+            exec(conn, "SET client_min_messages = warning") &&
+            exec(conn, "CREATE TABLE IF NOT EXISTS schema_migrations (version bigint NOT NULL PRIMARY KEY, dirty boolean NOT NULL)") &&
+            exec(conn, "SELECT pg_advisory_lock(" MIGRATION_LOCK ")");
+
+  if (ok)
+  {
+    ok = apply(conn, dir, migrations, count);
+    // A failed migration leaves its transaction open.
+    if (PQtransactionStatus(conn) != PQTRANS_IDLE)
+    {
+      exec(conn, "ROLLBACK");
+    }
+    exec(conn, "SELECT pg_advisory_unlock(" MIGRATION_LOCK ")");
+  }
+
+  if (count > 0)
+  {
+    free_migrations(migrations, count);
+  }
+
+  return ok;
+}
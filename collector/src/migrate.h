@@ -0,0 +1,9 @@
+#ifndef MIGRATE_H
+#define MIGRATE_H
+
+#include <libpq-fe.h>
+#include <stdbool.h>
+
+bool migrate(PGconn *conn, const char *dir);
+
+#endif // MIGRATE_H
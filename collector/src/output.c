@@ -0,0 +1,396 @@
+#include "log.h"
+#include "metrics.h"
+#include "output.h"
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <ctype.h>
+
+#define RULES_LINE_SIZE 1024
+#define RULE_TOKENS 4
+// Rules send rows here instead of to an output.
+#define DROP -1
+
+// The fields from FIELD_EMAIL on are the values of a row, in order.
+enum field
+{
+  FIELD_ACTION,
+  FIELD_DOMAIN,
+  FIELD_EMAIL,
+  FIELD_1,
+  FIELD_2,
+  FIELD_3,
+};
+
+enum operator
+{
+  OP_IS,
+  OP_STARTS_WITH,
+  OP_ENDS_WITH,
+  OP_CONTAINS,
+};
+
+struct rule
+{
+  enum field field;
+  enum operator op;
+  char *value;
+  // Index of the output the matching rows go to, or DROP.
+  int output;
+};
+
+// Where rows are written. The first output is stdout; the others are the
+// files and FIFOs rules route rows to.
+struct output
+{
+  char *path;
+  FILE *file;
+  // Rows written to the line being built.
+  int rows;
+};
+
+static struct rule *rules = NULL;
+static int rule_count = 0;
+static struct output *outputs = NULL;
+static int output_count = 0;
+
+static const char *field_names[] = {"action", "domain", "email", "field1", "field2", "field3"};
+static const char *operator_names[] = {"is", "starts-with", "ends-with", "contains"};
+
+static int lookup(const char *name, const char **names, int count)
+{
+  for (int i = 0; i < count; i++)
+  {
+    if (strcmp(name, names[i]) == 0)
+    {
+      return i;
+    }
+  }
+  return -1;
+}
+
+static bool ensure_stdout(void)
+{
+  if (output_count > 0)
+  {
+    return true;
+  }
+
+  outputs = calloc(1, sizeof(struct output));
+  if (!outputs)
+  {
+    return false;
+  }
+  outputs[0].file = stdout;
+  output_count = 1;
+
+  return true;
+}
+
+// Returns the index of the output writing to `path`, opening it first if no
+// rule routes to it yet.
+static int open_output(const char *path)
+{
+  if (strcmp(path, "stdout") == 0)
+  {
+    return 0;
+  }
+
+  for (int i = 1; i < output_count; i++)
+  {
+    if (strcmp(outputs[i].path, path) == 0)
+    {
+      return i;
+    }
+  }
+
+  struct output *grown = realloc(outputs, (output_count + 1) * sizeof(struct output));
+  if (!grown)
+  {
+    return -2;
+  }
+  outputs = grown;
+
+  // Opening a FIFO waits for its reader, such as a sender started with it
+  // as stdin.
+  log_printf("opening %s...", path);
+  FILE *file = fopen(path, "a");
+  if (!file)
+  {
+    log_printf("failed to open %s: %s", path, strerror(errno));
+    return -2;
+  }
+
+  outputs[output_count].path = strdup(path);
+  outputs[output_count].file = file;
+  outputs[output_count].rows = 0;
+
+  return output_count++;
+}
+
+// Parses a line such as "domain is tenant-a.example /run/tenant-a.fifo": the
+// field, the operator, the value and where the matching rows go. Returns 0,
+// -1 if the line is malformed or -2 if its output could not be opened.
+static int parse_rule(char *line, struct rule *rule)
+{
+  char *tokens[RULE_TOKENS];
+  char *rest = line;
+
+  for (int i = 0; i < RULE_TOKENS; i++)
+  {
+    while (isspace((unsigned char)*rest))
+    {
+      rest++;
+    }
+    tokens[i] = rest;
+    while (*rest && !isspace((unsigned char)*rest))
+    {
+      rest++;
+    }
+    if (tokens[i] == rest)
+    {
+      return -1;
+    }
+    if (*rest)
+    {
+      *rest++ = '\0';
+    }
+  }
+
+  while (isspace((unsigned char)*rest))
+  {
+    rest++;
+  }
+  if (*rest)
+  {
+    return -1;
+  }
+
+  int field = lookup(tokens[0], field_names, sizeof(field_names) / sizeof(field_names[0]));
+  int op = lookup(tokens[1], operator_names, sizeof(operator_names) / sizeof(operator_names[0]));
+  if (field < 0 || op < 0)
+  {
+    return -1;
+  }
+
+  int output = strcmp(tokens[3], "drop") == 0 ? DROP : open_output(tokens[3]);
+  if (output == -2)
+  {
+    return -2;
+  }
+
+  rule->field = (enum field)field;
+  rule->op = (enum operator)op;
+  rule->value = strdup(tokens[2]);
+  rule->output = output;
+
+  return rule->value ? 0 : -2;
+}
+
+bool output_load_rules(const char *path)
+{
+  if (!ensure_stdout())
+  {
+    log_printf("PANIC: out of memory");
+    return false;
+  }
+
+  FILE *file = fopen(path, "r");
+  if (!file)
+  {
+    log_printf("failed to open %s", path);
+    return false;
+  }
+
+  char line[RULES_LINE_SIZE];
+  int number = 0;
+  bool ok = true;
+
+  while (fgets(line, sizeof(line), file))
+  {
+    number++;
+
+    const char *start = line;
+    while (isspace((unsigned char)*start))
+    {
+      start++;
+    }
+    if (*start == '\0' || *start == '#')
+    {
+      continue;
+    }
+
+    struct rule *grown = realloc(rules, (rule_count + 1) * sizeof(struct rule));
+    if (!grown)
+    {
+      log_printf("PANIC: out of memory");
+      ok = false;
+      break;
+    }
+    rules = grown;
+
+    int parsed = parse_rule(line, &rules[rule_count]);
+    if (parsed == -1)
+    {
+      log_printf("line %d of %s must be a field, an operator, a value and drop or a path", number, path);
+    }
+    if (parsed < 0)
+    {
+      ok = false;
+      break;
+    }
+    rule_count++;
+  }
+
+  fclose(file);
+
+  return ok;
+}
+
+static bool matches(const struct rule *rule, int action, const char *values[OUTPUT_VALUES])
+{
+  char id[12];
+  const char *value;
+
+  switch (rule->field)
+  {
+  case FIELD_ACTION:
+    snprintf(id, sizeof(id), "%d", action);
+    value = id;
+    break;
+  case FIELD_DOMAIN:
+    value = strrchr(values[0], '@');
+    value = value ? value + 1 : "";
+    break;
+  default:
+    value = values[rule->field - FIELD_EMAIL];
+  }
+
+  size_t len = strlen(value), rule_len = strlen(rule->value);
+
+  switch (rule->op)
+  {
+  case OP_IS:
+    return strcmp(value, rule->value) == 0;
+  case OP_STARTS_WITH:
+    return strncmp(value, rule->value, rule_len) == 0;
+  case OP_ENDS_WITH:
+    return len >= rule_len && strcmp(value + len - rule_len, rule->value) == 0;
+  case OP_CONTAINS:
+    return strstr(value, rule->value) != NULL;
+  }
+
+  return false;
+}
+
+// Writes a field value, escaping the characters the sender would otherwise
+// read as separators.
+static void print_field(FILE *file, const char *value)
+{
+  for (; *value; value++)
+  {
+    switch (*value)
+    {
+    case '\n':
+      fputs("\\n", file);
+      break;
+    case ',':
+      fputs("\\,", file);
+      break;
+    case '\\':
+      fputs("\\\\", file);
+      break;
+    default:
+      fputc(*value, file);
+    }
+  }
+}
+
+// Adds a row to the line of the output the first matching rule names, or of
+// stdout. `deadline` may be NULL.
+void output_row(int action, const char *deadline, const char *values[OUTPUT_VALUES])
+{
+  int target = 0;
+  for (int i = 0; i < rule_count; i++)
+  {
+    if (matches(&rules[i], action, values))
+    {
+      target = rules[i].output;
+      break;
+    }
+  }
+
+  if (target == DROP)
+  {
+    metrics_dropped();
+    return;
+  }
+
+  if (!ensure_stdout())
+  {
+    log_printf("PANIC: out of memory");
+    return;
+  }
+
+  struct output *output = &outputs[target];
+  if (output->rows > 0)
+  {
+    fputc(',', output->file);
+  }
+
+  fprintf(output->file, "%d", action);
+  if (deadline)
+  {
+    // The sender drops the row if the deadline passes before it is sent.
+    fprintf(output->file, "@%s", deadline);
+  }
+  for (int i = 0; i < OUTPUT_VALUES; i++)
+  {
+    fputc(',', output->file);
+    print_field(output->file, values[i]);
+  }
+
+  output->rows++;
+  metrics_emitted(action);
+}
+
+// Ends the lines of the outputs rows were added to.
+void output_flush(void)
+{
+  for (int i = 0; i < output_count; i++)
+  {
+    if (outputs[i].rows == 0)
+    {
+      continue;
+    }
+
+    fputc('\n', outputs[i].file);
+    if (fflush(outputs[i].file) != 0)
+    {
+      log_printf("ERROR: failed to write to %s: %s", outputs[i].path ? outputs[i].path : "stdout", strerror(errno));
+    }
+    outputs[i].rows = 0;
+  }
+}
+
+void output_free(void)
+{
+  for (int i = 0; i < rule_count; i++)
+  {
+    free(rules[i].value);
+  }
+  free(rules);
+  rules = NULL;
+  rule_count = 0;
+
+  for (int i = 1; i < output_count; i++)
+  {
+    fclose(outputs[i].file);
+    free(outputs[i].path);
+  }
+  free(outputs);
+  outputs = NULL;
+  output_count = 0;
+}
@@ -0,0 +1,14 @@
+#ifndef OUTPUT_H
+#define OUTPUT_H
+
+#include <stdbool.h>
+
+// The email address and the fields after it in a row.
+#define OUTPUT_VALUES 4
+
+bool output_load_rules(const char *path);
+void output_row(int action, const char *deadline, const char *values[OUTPUT_VALUES]);
+void output_flush(void);
+void output_free(void);
+
+#endif // OUTPUT_H
@@ -0,0 +1,308 @@
+#include "log.h"
+#include "db.h"
+#include "schedule.h"
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <ctype.h>
+#include <time.h>
+
+#define SCHEDULE_LINE_SIZE 4096
+#define SCHEDULE_FIELDS 5
+
+// Parses a number from `*spec` and advances past it.
+static bool parse_number(const char **spec, int *value)
+{
+  if (!isdigit((unsigned char)**spec))
+  {
+    return false;
+  }
+
+  *value = 0;
+  while (isdigit((unsigned char)**spec))
+  {
+    *value = *value * 10 + (**spec - '0');
+    if (*value > 1000)
+    {
+      return false;
+    }
+    (*spec)++;
+  }
+
+  return true;
+}
+
+// Sets the bits of the values a cron field such as "*/15", "1,15" or
+// "9-17/2" matches, from `min` to `max`.
+static bool parse_field(const char *spec, int min, int max, uint64_t *bits)
+{
+  *bits = 0;
+
+  do
+  {
+    int from = min, to = max, step = 1;
+
+    if (*spec == '*')
+    {
+      spec++;
+    }
+    else
+    {
+      if (!parse_number(&spec, &from))
+      {
+        return false;
+      }
+      to = from;
+      if (*spec == '-')
+      {
+        spec++;
+        if (!parse_number(&spec, &to))
+        {
+          return false;
+        }
+      }
+    }
+
+    if (*spec == '/')
+    {
+      spec++;
+      if (!parse_number(&spec, &step) || step == 0)
+      {
+        return false;
+      }
+      if (from == to)
+      {
+        to = max;
+      }
+    }
+
+    if (from < min || to > max || from > to)
+    {
+      return false;
+    }
+
+    for (int value = from; value <= to; value += step)
+    {
+      *bits |= (uint64_t)1 << value;
+    }
+  } while (*spec++ == ',');
+
+  return *(spec - 1) == '\0';
+}
+
+// Parses a line such as "0 9 * * 1-5 3 SELECT email, login FROM ...": the
+// five fields of a cron schedule in UTC, the id of the action and the query.
+static bool parse_job(char *line, struct job *job)
+{
+  static const int mins[SCHEDULE_FIELDS] = {0, 0, 1, 1, 0};
+  static const int maxs[SCHEDULE_FIELDS] = {59, 23, 31, 12, 7};
+  uint64_t bits[SCHEDULE_FIELDS];
+  char *fields[SCHEDULE_FIELDS + 1];
+  char *rest = line;
+
+  for (int i = 0; i <= SCHEDULE_FIELDS; i++)
+  {
+    while (isspace((unsigned char)*rest))
+    {
+      rest++;
+    }
+    fields[i] = rest;
+    while (*rest && !isspace((unsigned char)*rest))
+    {
+      rest++;
+    }
+    if (!*rest)
+    {
+      return false;
+    }
+    *rest++ = '\0';
+  }
+
+  for (int i = 0; i < SCHEDULE_FIELDS; i++)
+  {
+    if (!parse_field(fields[i], mins[i], maxs[i], &bits[i]))
+    {
+      return false;
+    }
+  }
+
+  const char *action = fields[SCHEDULE_FIELDS];
+  if (strlen(action) != 1 || action[0] < '1' || action[0] > '9')
+  {
+    return false;
+  }
+
+  while (isspace((unsigned char)*rest))
+  {
+    rest++;
+  }
+  size_t len = strlen(rest);
+  while (len > 0 && isspace((unsigned char)rest[len - 1]))
+  {
+    rest[--len] = '\0';
+  }
+  if (len == 0)
+  {
+    return false;
+  }
+
+  job->minutes = bits[0];
+  job->hours = (uint32_t)bits[1];
+  job->days = (uint32_t)bits[2];
+  job->months = (uint16_t)bits[3];
+  // Both 0 and 7 stand for Sunday.
+  job->weekdays = (uint8_t)((bits[4] | bits[4] >> 7) & 0x7f);
+  job->any_day = strcmp(fields[2], "*") == 0;
+  job->any_weekday = strcmp(fields[4], "*") == 0;
+  job->action = action[0] - '0';
+  job->query = strdup(rest);
+
+  return job->query != NULL;
+}
+
+bool schedule_load(struct schedule *schedule, const char *path)
+{
+  schedule->jobs = NULL;
+  schedule->count = 0;
+  schedule->last_minute = time(NULL) / 60;
+
+  FILE *file = fopen(path, "r");
+  if (!file)
+  {
+    log_printf("failed to open %s", path);
+    return false;
+  }
+
+  char line[SCHEDULE_LINE_SIZE];
+  int number = 0;
+  bool ok = true;
+
+  while (ok && fgets(line, sizeof(line), file))
+  {
+    number++;
+
+    if (!strchr(line, '\n') && !feof(file))
+    {
+      log_printf("line %d of %s is longer than %d bytes", number, path, SCHEDULE_LINE_SIZE - 1);
+      ok = false;
+      break;
+    }
+
+    const char *start = line;
+    while (isspace((unsigned char)*start))
+    {
+      start++;
+    }
+    if (*start == '\0' || *start == '#')
+    {
+      continue;
+    }
+
+    struct job *jobs = realloc(schedule->jobs, (schedule->count + 1) * sizeof(struct job));
+    if (!jobs)
+    {
+      log_printf("PANIC: out of memory");
+      ok = false;
+      break;
+    }
+    schedule->jobs = jobs;
+
+    if (!parse_job(line, &schedule->jobs[schedule->count]))
+    {
+      log_printf("line %d of %s must be a cron schedule, an action id and a query", number, path);
+      ok = false;
+      break;
+    }
+    schedule->count++;
+  }
+
+  fclose(file);
+
+  if (!ok)
+  {
+    schedule_free(schedule);
+  }
+
+  return ok;
+}
+
+// Milliseconds until the next minute the jobs are checked in, or -1 without
+// jobs.
+long schedule_wait_ms(const struct schedule *schedule)
+{
+  if (schedule->count == 0)
+  {
+    return -1;
+  }
+
+  struct timespec ts;
+  clock_gettime(CLOCK_REALTIME, &ts);
+
+  return (60 - ts.tv_sec % 60) * 1000 - ts.tv_nsec / 1000000;
+}
+
+static bool is_due(const struct job *job, const struct tm *utc)
+{
+  bool day = job->days & (uint32_t)1 << utc->tm_mday;
+  bool weekday = job->weekdays & 1 << utc->tm_wday;
+
+  if (!(job->minutes & (uint64_t)1 << utc->tm_min) ||
+      !(job->hours & (uint32_t)1 << utc->tm_hour) ||
+      !(job->months & 1 << (utc->tm_mon + 1)))
+  {
+    return false;
+  }
+
+  if (job->any_day || job->any_weekday)
+  {
+    return day && weekday;
+  }
+
+  return day || weekday;
+}
+
+// Runs the jobs due in the current minute, once per minute.
+void schedule_run(struct schedule *schedule, PGconn *conn, int limit)
+{
+  time_t now = time(NULL);
+  long minute = now / 60;
+
+  if (schedule->count == 0 || minute == schedule->last_minute)
+  {
+    return;
+  }
+  schedule->last_minute = minute;
+
+  struct tm *utc = gmtime(&now);
+  if (!utc)
+  {
+    return;
+  }
+
+  for (int i = 0; i < schedule->count; i++)
+  {
+    if (!is_due(&schedule->jobs[i], utc))
+    {
+      continue;
+    }
+
+    log_printf("running scheduled job %d", i + 1);
+    int rows = db_run_job(conn, schedule->jobs[i].action, schedule->jobs[i].query, limit);
+    if (rows >= 0)
+    {
+      log_printf("scheduled job %d emitted %d rows", i + 1, rows);
+    }
+  }
+}
+
+void schedule_free(struct schedule *schedule)
+{
+  for (int i = 0; i < schedule->count; i++)
+  {
+    free(schedule->jobs[i].query);
+  }
+  free(schedule->jobs);
+  schedule->jobs = NULL;
+  schedule->count = 0;
+}
@@ -0,0 +1,37 @@
+#ifndef SCHEDULE_H
+#define SCHEDULE_H
+
+#include <libpq-fe.h>
+#include <stdbool.h>
+#include <stdint.h>
+
+// A query run on a cron schedule, whose rows are emitted for an action.
+struct job
+{
+  uint64_t minutes;
+  uint32_t hours;
+  uint32_t days;
+  uint16_t months;
+  uint8_t weekdays;
+  // Whether the day of the month or the week was restricted; cron runs a
+  // job on either when both were.
+  bool any_day;
+  bool any_weekday;
+  int action;
+  char *query;
+};
+
+struct schedule
+{
+  struct job *jobs;
+  int count;
+  // The minute since the epoch the jobs were last checked in.
+  long last_minute;
+};
+
+bool schedule_load(struct schedule *schedule, const char *path);
+long schedule_wait_ms(const struct schedule *schedule);
+void schedule_run(struct schedule *schedule, PGconn *conn, int limit);
+void schedule_free(struct schedule *schedule);
+
+#endif // SCHEDULE_H
@@ -0,0 +1,33 @@
+#ifndef CHECK_H
+#define CHECK_H
+
+#include "../src/config.h"
+
+#include <stdio.h>
+#include <stdlib.h>
+
+// Defined by main.c, which tests don't link.
+unsigned char hmac_secret[HMAC_SECRET_SIZE] = {0};
+size_t hmac_secretlen = 0;
+
+static int failures = 0;
+
+// Reports a failed condition and carries on, so that one run lists every
+// failure.
+#define CHECK(cond)                                                                \
+  do                                                                               \
+  {                                                                                \
+    if (!(cond))                                                                   \
+    {                                                                              \
+      fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond);     \
+      failures++;                                                                  \
+    }                                                                              \
+  } while (0)
+
+static int check_result(const char *name)
+{
+  fprintf(stderr, "%s: %s\n", name, failures == 0 ? "ok" : "FAILED");
+  return failures == 0 ? EXIT_SUCCESS : EXIT_FAILURE;
+}
+
+#endif // CHECK_H
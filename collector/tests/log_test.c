@@ -0,0 +1,115 @@
+#include "../src/log.c"
+#include "check.h"
+
+#include <unistd.h>
+
+static char captured[LOG_MESSAGE_SIZE];
+static FILE *capture_file;
+static int saved_stderr;
+
+// Sends stderr to a temporary file until end_capture, which reads it into
+// `captured`.
+static void start_capture(void)
+{
+  fflush(stderr);
+  saved_stderr = dup(STDERR_FILENO);
+  capture_file = tmpfile();
+  dup2(fileno(capture_file), STDERR_FILENO);
+}
+
+static void end_capture(void)
+{
+  fflush(stderr);
+  dup2(saved_stderr, STDERR_FILENO);
+  close(saved_stderr);
+  rewind(capture_file);
+  size_t len = fread(captured, 1, sizeof(captured) - 1, capture_file);
+  captured[len] = '\0';
+  fclose(capture_file);
+}
+
+static void test_take_level(void)
+{
+  const char *message = "ERROR: query failed";
+  CHECK(strcmp(take_level(&message), "error") == 0 && strcmp(message, "query failed") == 0);
+
+  message = "PANIC: out of memory";
+  CHECK(strcmp(take_level(&message), "error") == 0 && strcmp(message, "out of memory") == 0);
+
+  message = "FATAL: missing columns";
+  CHECK(strcmp(take_level(&message), "error") == 0);
+
+  message = "WARN: forcing reconnect...";
+  CHECK(strcmp(take_level(&message), "warn") == 0 && strcmp(message, "forcing reconnect...") == 0);
+
+  message = "connected";
+  CHECK(strcmp(take_level(&message), "info") == 0 && strcmp(message, "connected") == 0);
+
+  // Only a prefix sets the level.
+  message = "no ERROR: here";
+  CHECK(strcmp(take_level(&message), "info") == 0);
+}
+
+static void test_text(void)
+{
+  log_set_json(false);
+
+  start_capture();
+  log_printf("WARN: expected %d items to be processed, got %d", 5, 3);
+  end_capture();
+
+  // 2026/10/17 22:43:43 [PG] WARN: ...
+  CHECK(strlen(captured) > 20 && captured[4] == '/' && captured[10] == ' ');
+  CHECK(strcmp(captured + 19, " [PG] WARN: expected 5 items to be processed, got 3\n") == 0);
+}
+
+static void test_json(void)
+{
+  log_set_json(true);
+
+  start_capture();
+  log_printf("ERROR: query execution failed: %s", "relation \"jobs\" does not exist\n");
+  end_capture();
+
+  const char *timestamp = "{\"timestamp\":\"";
+  CHECK(strncmp(captured, timestamp, strlen(timestamp)) == 0);
+  // 2026-10-17T22:43:43Z
+  const char *rest = captured + strlen(timestamp);
+  CHECK(rest[4] == '-' && rest[10] == 'T' && rest[19] == 'Z');
+  CHECK(strcmp(rest + 20, "\",\"level\":\"error\",\"component\":\"collector\","
+                          "\"message\":\"query execution failed: relation \\\"jobs\\\" does not exist\"}\n") == 0);
+
+  start_capture();
+  log_printf("rows\t%s\\%c", "a\nb", 1);
+  end_capture();
+
+  CHECK(strstr(captured, "\"level\":\"info\"") != NULL);
+  CHECK(strstr(captured, "\"message\":\"rows\\ta\\nb\\\\\\u0001\"}\n") != NULL);
+
+  log_set_json(false);
+}
+
+static void test_errors(void)
+{
+  unsigned long before = log_errors();
+
+  start_capture();
+  log_printf("ERROR: one");
+  log_printf("PANIC: two");
+  log_printf("FATAL: three");
+  log_printf("WARN: not an error");
+  log_printf("ERRORS are not a prefix");
+  end_capture();
+
+  CHECK(log_errors() - before == 3);
+}
+
+int main(void)
+{
+  test_take_level();
+  test_text();
+  test_json();
+  test_errors();
+
+  return check_result("log");
+}
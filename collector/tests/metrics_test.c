@@ -0,0 +1,156 @@
+#include "../src/metrics.c"
+#include "check.h"
+
+#include <signal.h>
+#include <time.h>
+#include <sys/wait.h>
+
+static void test_format(void)
+{
+  char body[METRICS_BODY_SIZE];
+
+  metrics_consumed(3);
+  metrics_emitted(1);
+  metrics_emitted(1);
+  metrics_emitted(2);
+  // Ids past the last counted action are left out.
+  metrics_emitted(METRICS_ACTIONS);
+  metrics_skipped();
+
+  CHECK(format_metrics(body, sizeof(body), NULL, "mailroom") > 0);
+  CHECK(strstr(body, "# TYPE mailroom_collector_rows_consumed_total counter\n") != NULL);
+  CHECK(strstr(body, "\nmailroom_collector_rows_consumed_total 3\n") != NULL);
+  CHECK(strstr(body, "\nmailroom_collector_rows_emitted_total{action=\"1\"} 2\n") != NULL);
+  CHECK(strstr(body, "\nmailroom_collector_rows_emitted_total{action=\"2\"} 1\n") != NULL);
+  CHECK(strstr(body, "action=\"0\"") == NULL);
+  CHECK(strstr(body, "action=\"10\"") == NULL);
+  CHECK(strstr(body, "\nmailroom_collector_rows_skipped_total 1\n") != NULL);
+  CHECK(strstr(body, "\nmailroom_collector_errors_total 0\n") != NULL);
+  // Without a connection, the lag is unknown.
+  CHECK(strstr(body, "queue_depth") == NULL);
+
+  char small[64];
+  CHECK(format_metrics(small, sizeof(small), NULL, "mailroom") == -1);
+}
+
+static void test_listen(void)
+{
+  const char *invalid[] = {"9100", ":9100", "127.0.0.1", "127.0.0.1:", "127.0.0.1:0",
+                           "127.0.0.1:65536", "127.0.0.1:91x", "localhost:9100"};
+  for (size_t i = 0; i < sizeof(invalid) / sizeof(invalid[0]); i++)
+  {
+    CHECK(metrics_listen(invalid[i]) == -1);
+  }
+}
+
+// Listens on the first free port from 39100.
+static int listen_any(int *port)
+{
+  char addr[32];
+  for (*port = 39100; *port < 39200; (*port)++)
+  {
+    snprintf(addr, sizeof(addr), "127.0.0.1:%d", *port);
+    int fd = metrics_listen(addr);
+    if (fd >= 0)
+    {
+      return fd;
+    }
+  }
+  return -1;
+}
+
+static int connect_to(int port)
+{
+  struct sockaddr_in sin;
+  memset(&sin, 0, sizeof(sin));
+  sin.sin_family = AF_INET;
+  sin.sin_port = htons((unsigned short)port);
+  inet_pton(AF_INET, "127.0.0.1", &sin.sin_addr);
+
+  int fd = socket(AF_INET, SOCK_STREAM, 0);
+  if (fd >= 0 && connect(fd, (struct sockaddr *)&sin, sizeof(sin)) != 0)
+  {
+    close(fd);
+    return -1;
+  }
+  return fd;
+}
+
+// Connects, has a child process send `request` after `delay_ms`, serves it
+// and reads the response into `response`.
+static void scrape(int fd, int port, const char *request, long delay_ms, char *response, size_t size)
+{
+  response[0] = '\0';
+
+  int client = connect_to(port);
+  CHECK(client >= 0);
+  if (client < 0)
+  {
+    return;
+  }
+
+  pid_t pid = fork();
+  if (pid == 0)
+  {
+    struct timespec delay = {0, delay_ms * 1000000};
+    nanosleep(&delay, NULL);
+    _exit(write(client, request, strlen(request)) == (ssize_t)strlen(request) ? 0 : 1);
+  }
+
+  metrics_serve(fd, NULL, "mailroom");
+
+  size_t len = 0;
+  ssize_t n;
+  while (len < size - 1 && (n = read(client, response + len, size - 1 - len)) > 0)
+  {
+    len += n;
+  }
+  response[len] = '\0';
+  close(client);
+
+  int status;
+  waitpid(pid, &status, 0);
+  CHECK(WIFEXITED(status) && WEXITSTATUS(status) == 0);
+}
+
+static void test_serve(void)
+{
+  int port;
+  int fd = listen_any(&port);
+  CHECK(fd >= 0);
+  if (fd < 0)
+  {
+    return;
+  }
+
+  char response[METRICS_BODY_SIZE];
+
+  scrape(fd, port, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n", 0, response, sizeof(response));
+  CHECK(strncmp(response, "HTTP/1.1 200 OK\r\n", 17) == 0);
+  CHECK(strstr(response, "\r\n\r\n# HELP mailroom_collector_rows_consumed_total") != NULL);
+
+  // The request may arrive after the connection is accepted.
+  scrape(fd, port, "GET /metrics?name[]=x HTTP/1.1\r\n\r\n", 200, response, sizeof(response));
+  CHECK(strncmp(response, "HTTP/1.1 200 OK\r\n", 17) == 0);
+
+  scrape(fd, port, "GET / HTTP/1.1\r\n\r\n", 0, response, sizeof(response));
+  CHECK(strncmp(response, "HTTP/1.1 404 Not Found\r\n", 24) == 0);
+  CHECK(strstr(response, "Content-Length: 0\r\n") != NULL);
+
+  // Without a pending connection, serving returns at once.
+  metrics_serve(fd, NULL, "mailroom");
+  metrics_serve(-1, NULL, "mailroom");
+
+  close(fd);
+}
+
+int main(void)
+{
+  signal(SIGPIPE, SIG_IGN);
+
+  test_format();
+  test_listen();
+  test_serve();
+
+  return check_result("metrics");
+}
@@ -0,0 +1,159 @@
+#include "../src/migrate.c"
+#include "check.h"
+
+#include <unistd.h>
+
+#define MIGRATE_TEST_FILES 16
+
+static char dir[] = "/tmp/migrate_test.XXXXXX";
+static char *files[MIGRATE_TEST_FILES];
+static int file_count = 0;
+
+static void add_file(const char *name, const char *content)
+{
+  char path[MIGRATION_PATH_SIZE];
+  snprintf(path, sizeof(path), "%s/%s", dir, name);
+
+  FILE *file = fopen(path, "w");
+  CHECK(file != NULL);
+  if (file)
+  {
+    fputs(content, file);
+    fclose(file);
+  }
+
+  if (file_count < MIGRATE_TEST_FILES)
+  {
+    files[file_count] = malloc(strlen(path) + 1);
+    memcpy(files[file_count], path, strlen(path) + 1);
+    file_count++;
+  }
+}
+
+static void remove_files(void)
+{
+  for (int i = 0; i < file_count; i++)
+  {
+    unlink(files[i]);
+    free(files[i]);
+  }
+  file_count = 0;
+}
+
+static void test_list_migrations(void)
+{
+  struct migration *migrations;
+
+  add_file("1_outbox.up.sql", "");
+  add_file("10_results.up.sql", "");
+  add_file("0_init.up.sql", "");
+  // Only numbered up migrations are listed.
+  add_file("0_init.down.sql", "");
+  add_file("README.md", "");
+  add_file("init.up.sql", "");
+  add_file("2.up.sql", "");
+
+  int count = list_migrations(dir, &migrations);
+  CHECK(count == 3);
+  if (count == 3)
+  {
+    CHECK(migrations[0].version == 0 && strcmp(migrations[0].name, "0_init.up.sql") == 0);
+    CHECK(migrations[1].version == 1 && strcmp(migrations[1].name, "1_outbox.up.sql") == 0);
+    CHECK(migrations[2].version == 10 && strcmp(migrations[2].name, "10_results.up.sql") == 0);
+    free_migrations(migrations, count);
+  }
+
+  add_file("1_duplicate.up.sql", "");
+  CHECK(list_migrations(dir, &migrations) == -1);
+
+  remove_files();
+
+  CHECK(list_migrations(dir, &migrations) == 0);
+  CHECK(list_migrations("/nonexistent/migrations", &migrations) == -1);
+}
+
+static char *query_value(PGconn *conn, const char *sql)
+{
+  static char value[256];
+  PGresult *res = PQexec(conn, sql);
+  if (PQresultStatus(res) != PGRES_TUPLES_OK || PQntuples(res) == 0)
+  {
+    PQclear(res);
+    return "";
+  }
+  snprintf(value, sizeof(value), "%s|%s", PQgetvalue(res, 0, 0), PQnfields(res) > 1 ? PQgetvalue(res, 0, 1) : "");
+  PQclear(res);
+  return value;
+}
+
+// Migrates a schema of its own in the database at DATABASE_URL, if set.
+static void test_migrate(void)
+{
+  const char *url = getenv("DATABASE_URL");
+  if (!url)
+  {
+    fprintf(stderr, "migrate: DATABASE_URL not set; skipping the database tests\n");
+    return;
+  }
+
+  PGconn *conn = PQconnectdb(url);
+  CHECK(PQstatus(conn) == CONNECTION_OK);
+  if (PQstatus(conn) != CONNECTION_OK)
+  {
+    PQfinish(conn);
+    return;
+  }
+
+  char schema[64];
+  snprintf(schema, sizeof(schema), "migrate_test_%d", (int)getpid());
+  char sql[256];
+  snprintf(sql, sizeof(sql), "CREATE SCHEMA %s; SET search_path TO %s", schema, schema);
+  CHECK(exec(conn, sql));
+
+  add_file("0_init.up.sql", "CREATE TABLE accounts (id bigint PRIMARY KEY);");
+  add_file("1_outbox.up.sql", "CREATE TABLE outbox (id bigint REFERENCES accounts (id));");
+
+  CHECK(migrate(conn, dir));
+  CHECK(strcmp(query_value(conn, "SELECT version, dirty FROM schema_migrations"), "1|f") == 0);
+  CHECK(strcmp(query_value(conn, "SELECT COUNT(*) FROM outbox"), "0|") == 0);
+
+  // Applied migrations are not run again.
+  CHECK(migrate(conn, dir));
+  CHECK(strcmp(query_value(conn, "SELECT COUNT(*) FROM schema_migrations"), "1|") == 0);
+
+  // A failed migration is rolled back and leaves its version dirty, which
+  // stops later runs.
+  add_file("2_results.up.sql", "CREATE TABLE results (id bigint); SELECT missing FROM outbox;");
+  CHECK(!migrate(conn, dir));
+  CHECK(strcmp(query_value(conn, "SELECT version, dirty FROM schema_migrations"), "2|t") == 0);
+  CHECK(strcmp(query_value(conn, "SELECT to_regclass('results') IS NULL"), "t|") == 0);
+  CHECK(!migrate(conn, dir));
+
+  // Once the migration is fixed and the version marked clean, it is skipped.
+  CHECK(exec(conn, "UPDATE schema_migrations SET version = 1, dirty = false"));
+  add_file("2_results.up.sql", "CREATE TABLE results (id bigint);");
+  CHECK(migrate(conn, dir));
+  CHECK(strcmp(query_value(conn, "SELECT version, dirty FROM schema_migrations"), "2|f") == 0);
+
+  snprintf(sql, sizeof(sql), "DROP SCHEMA %s CASCADE", schema);
+  CHECK(exec(conn, sql));
+  PQfinish(conn);
+
+  remove_files();
+}
+
+int main(void)
+{
+  if (!mkdtemp(dir))
+  {
+    perror("mkdtemp");
+    return EXIT_FAILURE;
+  }
+
+  test_list_migrations();
+  test_migrate();
+
+  rmdir(dir);
+
+  return check_result("migrate");
+}
@@ -0,0 +1,170 @@
+#include "../src/output.c"
+#include "check.h"
+
+#include <unistd.h>
+
+static void test_parse_rule(void)
+{
+  struct rule rule;
+
+  char drop[] = "domain is example.test drop\n";
+  CHECK(parse_rule(drop, &rule) == 0);
+  CHECK(rule.field == FIELD_DOMAIN && rule.op == OP_IS && rule.output == DROP);
+  CHECK(strcmp(rule.value, "example.test") == 0);
+  free(rule.value);
+
+  char spaced[] = "  field2\tstarts-with   abc   stdout  \n";
+  CHECK(parse_rule(spaced, &rule) == 0);
+  CHECK(rule.field == FIELD_2 && rule.op == OP_STARTS_WITH && rule.output == 0);
+  free(rule.value);
+
+  char *invalid[] = {
+      (char[]){"domain is example.test\n"},
+      (char[]){"domain is example.test drop extra\n"},
+      (char[]){"tenant is example.test drop\n"},
+      (char[]){"domain matches example.test drop\n"},
+      (char[]){"\n"},
+  };
+  for (size_t i = 0; i < sizeof(invalid) / sizeof(invalid[0]); i++)
+  {
+    CHECK(parse_rule(invalid[i], &rule) == -1);
+  }
+
+  char unopened[] = "domain is example.test /nonexistent/tenant.fifo\n";
+  CHECK(parse_rule(unopened, &rule) == -2);
+}
+
+static bool matches_field(const char *line, int action, const char *email, const char *field1)
+{
+  struct rule rule;
+  char copy[RULES_LINE_SIZE];
+  snprintf(copy, sizeof(copy), "%s", line);
+  if (parse_rule(copy, &rule) != 0)
+  {
+    return false;
+  }
+
+  const char *values[OUTPUT_VALUES] = {email, field1, "", ""};
+  bool matched = matches(&rule, action, values);
+  free(rule.value);
+  return matched;
+}
+
+static void test_matches(void)
+{
+  CHECK(matches_field("action is 2 drop", 2, "a@x.test", ""));
+  CHECK(!matches_field("action is 2 drop", 12, "a@x.test", ""));
+  CHECK(matches_field("email is a@x.test drop", 1, "a@x.test", ""));
+  CHECK(!matches_field("email is a@x.test drop", 1, "ba@x.test", ""));
+  CHECK(matches_field("domain is x.test drop", 1, "a@x.test", ""));
+  CHECK(!matches_field("domain is x.test drop", 1, "a@sub.x.test", ""));
+  // Addresses without an @ have no domain.
+  CHECK(!matches_field("domain is x.test drop", 1, "x.test", ""));
+  CHECK(matches_field("domain ends-with .x.test drop", 1, "a@sub.x.test", ""));
+  CHECK(matches_field("email starts-with qa+ drop", 1, "qa+1@x.test", ""));
+  CHECK(!matches_field("email starts-with qa+ drop", 1, "q@x.test", ""));
+  CHECK(matches_field("field1 contains tenant-a drop", 1, "a@x.test", "login-tenant-a-7"));
+  CHECK(!matches_field("field1 ends-with long-suffix drop", 1, "a@x.test", "fix"));
+}
+
+static bool write_file(char *path, const char *content)
+{
+  int fd = mkstemp(path);
+  if (fd < 0)
+  {
+    return false;
+  }
+  size_t len = strlen(content);
+  bool ok = write(fd, content, len) == (ssize_t)len;
+  close(fd);
+  return ok;
+}
+
+static void read_file(const char *path, char *content, size_t size)
+{
+  content[0] = '\0';
+  FILE *file = fopen(path, "r");
+  if (file)
+  {
+    size_t len = fread(content, 1, size - 1, file);
+    content[len] = '\0';
+    fclose(file);
+  }
+}
+
+static void test_routing(void)
+{
+  char tenant[] = "/tmp/output_test.XXXXXX";
+  char other[] = "/tmp/output_test.XXXXXX";
+  CHECK(write_file(tenant, ""));
+  CHECK(write_file(other, ""));
+
+  char rules_path[] = "/tmp/output_test.XXXXXX";
+  char rules_content[1024];
+  snprintf(rules_content, sizeof(rules_content),
+           "# field operator value target\n"
+           "\n"
+           "domain is example.test drop\n"
+           "domain is tenant-a.example %s\n"
+           "action is 2 %s\n"
+           "email contains @ %s\n",
+           tenant, tenant, other);
+  CHECK(write_file(rules_path, rules_content));
+  CHECK(output_load_rules(rules_path));
+  CHECK(rule_count == 4);
+  // Both rules writing to the tenant share its output.
+  CHECK(output_count == 3);
+
+  const char *dropped_row[OUTPUT_VALUES] = {"a@example.test", "al", "", ""};
+  const char *tenant_row[OUTPUT_VALUES] = {"b@tenant-a.example", "bo", "tok", "12345"};
+  const char *recovery_row[OUTPUT_VALUES] = {"c@other.example", "cy", "tok2", ""};
+  const char *other_row[OUTPUT_VALUES] = {"d@other.example", "", "", ""};
+  const char *escaped_row[OUTPUT_VALUES] = {"e@other.example", "a,b", "c\nd", "e\\f"};
+
+  output_row(1, NULL, dropped_row);
+  output_row(1, "1714565100", tenant_row);
+  output_row(2, NULL, recovery_row);
+  output_row(1, NULL, other_row);
+  output_flush();
+  // Outputs without rows get no line.
+  output_flush();
+  // Separators in values are escaped.
+  output_row(1, NULL, escaped_row);
+  output_flush();
+
+  char content[1024];
+  read_file(tenant, content, sizeof(content));
+  CHECK(strcmp(content, "1@1714565100,b@tenant-a.example,bo,tok,12345,2,c@other.example,cy,tok2,\n") == 0);
+  read_file(other, content, sizeof(content));
+  CHECK(strcmp(content, "1,d@other.example,,,\n1,e@other.example,a\\,b,c\\nd,e\\\\f\n") == 0);
+
+  output_free();
+  CHECK(rule_count == 0 && output_count == 0 && rules == NULL && outputs == NULL);
+
+  // A malformed rule or an output that can't be opened fails the load.
+  char malformed[] = "/tmp/output_test.XXXXXX";
+  CHECK(write_file(malformed, "domain is example.test\n"));
+  CHECK(!output_load_rules(malformed));
+  output_free();
+  char unopened[] = "/tmp/output_test.XXXXXX";
+  CHECK(write_file(unopened, "domain is example.test /nonexistent/tenant.fifo\n"));
+  CHECK(!output_load_rules(unopened));
+  output_free();
+  CHECK(!output_load_rules("/nonexistent/rules"));
+  output_free();
+
+  unlink(rules_path);
+  unlink(malformed);
+  unlink(unopened);
+  unlink(tenant);
+  unlink(other);
+}
+
+int main(void)
+{
+  test_parse_rule();
+  test_matches();
+  test_routing();
+
+  return check_result("output");
+}
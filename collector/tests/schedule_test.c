@@ -0,0 +1,155 @@
+#include "../src/schedule.c"
+#include "check.h"
+
+#include <unistd.h>
+
+static uint64_t bits_of(const int *values, int count)
+{
+  uint64_t bits = 0;
+  for (int i = 0; i < count; i++)
+  {
+    bits |= (uint64_t)1 << values[i];
+  }
+  return bits;
+}
+
+static void test_parse_field(void)
+{
+  uint64_t bits;
+
+  CHECK(parse_field("*", 0, 59, &bits) && bits == ((uint64_t)1 << 60) - 1);
+  CHECK(parse_field("*/15", 0, 59, &bits) && bits == bits_of((int[]){0, 15, 30, 45}, 4));
+  CHECK(parse_field("1,15", 1, 31, &bits) && bits == bits_of((int[]){1, 15}, 2));
+  CHECK(parse_field("9-17/2", 0, 23, &bits) && bits == bits_of((int[]){9, 11, 13, 15, 17}, 5));
+  // A step from a single value runs to the end of the range.
+  CHECK(parse_field("50/5", 0, 59, &bits) && bits == bits_of((int[]){50, 55}, 2));
+  CHECK(parse_field("1-5,0", 0, 7, &bits) && bits == bits_of((int[]){0, 1, 2, 3, 4, 5}, 6));
+
+  const char *invalid[] = {"", "60", "5-1", "*/0", "1,", ",1", "a", "1-", "*1", "1/"};
+  for (size_t i = 0; i < sizeof(invalid) / sizeof(invalid[0]); i++)
+  {
+    CHECK(!parse_field(invalid[i], 0, 59, &bits));
+  }
+}
+
+static void test_parse_job(void)
+{
+  struct job job;
+
+  char weekdays[] = "30 9 * * 1-5 3   SELECT email, login FROM accounts  \n";
+  CHECK(parse_job(weekdays, &job));
+  CHECK(job.minutes == (uint64_t)1 << 30);
+  CHECK(job.hours == 1u << 9);
+  CHECK(job.weekdays == 0x3e);
+  CHECK(job.any_day && !job.any_weekday);
+  CHECK(job.action == 3);
+  CHECK(strcmp(job.query, "SELECT email, login FROM accounts") == 0);
+  free(job.query);
+
+  // Both 0 and 7 stand for Sunday.
+  char sunday[] = "0 0 * * 7 1 SELECT 1\n";
+  CHECK(parse_job(sunday, &job) && job.weekdays == 1);
+  free(job.query);
+
+  char *invalid[] = {
+      (char[]){"0 9 * * * 3\n"},
+      (char[]){"0 9 * * * 3   \n"},
+      (char[]){"0 9 * * * 0 SELECT 1\n"},
+      (char[]){"0 9 * * * 10 SELECT 1\n"},
+      (char[]){"0 9 * * SELECT 1\n"},
+      (char[]){"0 24 * * * 1 SELECT 1\n"},
+      (char[]){"0 9 0 * * 1 SELECT 1\n"},
+  };
+  for (size_t i = 0; i < sizeof(invalid) / sizeof(invalid[0]); i++)
+  {
+    CHECK(!parse_job(invalid[i], &job));
+  }
+}
+
+static struct tm utc(int mday, int wday, int hour, int min)
+{
+  struct tm tm = {0};
+  tm.tm_mday = mday;
+  tm.tm_wday = wday;
+  tm.tm_hour = hour;
+  tm.tm_min = min;
+  tm.tm_mon = 0;
+  return tm;
+}
+
+static void test_is_due(void)
+{
+  struct job job;
+
+  char monthly[] = "0 9 13 * * 1 SELECT 1\n";
+  CHECK(parse_job(monthly, &job));
+  struct tm at = utc(13, 1, 9, 0);
+  CHECK(is_due(&job, &at));
+  at = utc(13, 1, 9, 1);
+  CHECK(!is_due(&job, &at));
+  at = utc(14, 2, 9, 0);
+  CHECK(!is_due(&job, &at));
+  free(job.query);
+
+  // Restricted to both, a job runs on either the day of the month or the
+  // weekday.
+  char either[] = "0 9 13 * 5 1 SELECT 1\n";
+  CHECK(parse_job(either, &job));
+  at = utc(13, 1, 9, 0);
+  CHECK(is_due(&job, &at));
+  at = utc(14, 5, 9, 0);
+  CHECK(is_due(&job, &at));
+  at = utc(14, 6, 9, 0);
+  CHECK(!is_due(&job, &at));
+  free(job.query);
+}
+
+static bool write_file(char *path, const char *content)
+{
+  int fd = mkstemp(path);
+  if (fd < 0)
+  {
+    return false;
+  }
+  size_t len = strlen(content);
+  bool ok = write(fd, content, len) == (ssize_t)len;
+  close(fd);
+  return ok;
+}
+
+static void test_schedule_load(void)
+{
+  struct schedule schedule;
+
+  char path[] = "/tmp/schedule_test.XXXXXX";
+  CHECK(write_file(path, "# minute hour day month weekday action query\n"
+                         "\n"
+                         "0 9 * * * 3 SELECT email FROM trials\n"
+                         "  */15 * * * * 1 SELECT email FROM accounts\n"));
+  CHECK(schedule_load(&schedule, path));
+  CHECK(schedule.count == 2);
+  CHECK(schedule.count == 2 && schedule.jobs[1].minutes == bits_of((int[]){0, 15, 30, 45}, 4));
+  CHECK(schedule_wait_ms(&schedule) > 0 && schedule_wait_ms(&schedule) <= 60000);
+  schedule_free(&schedule);
+  CHECK(schedule.count == 0 && schedule.jobs == NULL);
+  CHECK(schedule_wait_ms(&schedule) == -1);
+  unlink(path);
+
+  char malformed[] = "/tmp/schedule_test.XXXXXX";
+  CHECK(write_file(malformed, "0 9 * * * 3 SELECT 1\n0 9 * *\n"));
+  CHECK(!schedule_load(&schedule, malformed));
+  CHECK(schedule.count == 0 && schedule.jobs == NULL);
+  unlink(malformed);
+
+  CHECK(!schedule_load(&schedule, "/nonexistent/schedule"));
+}
+
+int main(void)
+{
+  test_parse_field();
+  test_parse_job();
+  test_is_due();
+  test_schedule_load();
+
+  return check_result("schedule");
+}
@@ -0,0 +1,61 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+// Where whatever schedules, expires, rate-limits or retains things reads the
+// time from, so that a test can move time by hand instead of sleeping.
+pub trait Clock: Send + Sync {
+    // Monotonic time, for intervals.
+    fn now(&self) -> Instant;
+    // Wall-clock time, for what is persisted or compared across restarts.
+    fn system(&self) -> SystemTime;
+}
+
+// The operating system's clocks.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+// A clock that stands still until `advance` moves it. Both of its times
+// move together.
+pub struct ManualClock {
+    started: Instant,
+    epoch: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    // Starts at wall-clock time `at`.
+    pub fn new(at: SystemTime) -> Self {
+        ManualClock {
+            started: Instant::now(),
+            epoch: at,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.started + *self.elapsed.lock().unwrap()
+    }
+
+    fn system(&self) -> SystemTime {
+        self.epoch + *self.elapsed.lock().unwrap()
+    }
+}
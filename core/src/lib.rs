@@ -0,0 +1,817 @@
+// The collector's output format, shared by the sender and by tools that
+// read the same stream.
+//
+// Every line holds rows of `action,email,field,field,field`, comma
+// separated, and ends with a newline. The action is the single-digit ID of
+// an action in the registry, optionally followed by `@` and the Unix time
+// after which the row is no longer worth sending. Within the other fields,
+// `\n`, `\,` and `\\` stand for a newline, a comma and a backslash.
+
+pub mod clock;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Index;
+use std::sync::OnceLock;
+
+// Action IDs are a single digit, from 1.
+pub const MAX_ACTIONS: usize = 9;
+// The email address and up to three template fields.
+pub const MAX_FIELDS: usize = 4;
+pub const MAX_FIELD_LEN: usize = 254;
+// The action followed by the email address and the template fields.
+const ROW_FIELDS: usize = MAX_FIELDS + 1;
+
+// An email the collector can ask for: the ID rows carry, the SES template
+// it is sent with and the names of the template fields following the email
+// address, in order.
+#[derive(Clone, Debug)]
+pub struct Action {
+    pub id: u8,
+    pub name: String,
+    pub template: String,
+    pub fields: Vec<String>,
+}
+
+// The actions rows can refer to, in the order they were defined.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    actions: Vec<Action>,
+    // Index of the action of each ID.
+    ids: [Option<usize>; MAX_ACTIONS + 1],
+}
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+// The actions in use: the ones passed to `install`, or the built-in ones.
+pub fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(Registry::builtin)
+}
+
+// Replaces the built-in actions. Fails once the registry has been used,
+// since rows may have been parsed with it.
+pub fn install(registry: Registry) -> Result<(), String> {
+    REGISTRY
+        .set(registry)
+        .map_err(|_| "the action registry is already in use".to_string())
+}
+
+impl Registry {
+    pub fn builtin() -> Self {
+        let action = |id, name: &str, template: &str, fields: &[&str]| Action {
+            id,
+            name: name.to_string(),
+            template: template.to_string(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+        };
+        Registry::new(vec![
+            action(1, "activation", "activationv1", &["login", "secret"]),
+            action(
+                2,
+                "password_recovery",
+                "passwordrecoveryv1",
+                &["login", "secret", "code"],
+            ),
+        ])
+        .expect("built-in actions are valid")
+    }
+
+    // Checks that IDs are single digits and that IDs, names and templates
+    // are unique.
+    pub fn new(actions: Vec<Action>) -> Result<Self, String> {
+        if actions.is_empty() {
+            return Err("no actions defined".to_string());
+        }
+        let mut ids = [None; MAX_ACTIONS + 1];
+        for (i, action) in actions.iter().enumerate() {
+            if action.id == 0 || action.id as usize > MAX_ACTIONS {
+                return Err(format!(
+                    "action {:?}: id must be from 1 to {}, got {}",
+                    action.name, MAX_ACTIONS, action.id
+                ));
+            }
+            if action.name.is_empty() || action.template.is_empty() {
+                return Err(format!(
+                    "action {}: name and template must not be empty",
+                    action.id
+                ));
+            }
+            if action.fields.len() >= MAX_FIELDS {
+                return Err(format!(
+                    "action {:?}: at most {} fields are supported, got {}",
+                    action.name,
+                    MAX_FIELDS - 1,
+                    action.fields.len()
+                ));
+            }
+            if ids[action.id as usize].replace(i).is_some() {
+                return Err(format!("action id {} is defined twice", action.id));
+            }
+            if let Some(other) = actions[..i]
+                .iter()
+                .find(|a| a.name == action.name || a.template == action.template)
+            {
+                return Err(format!(
+                    "actions {} and {} share a name or template",
+                    other.id, action.id
+                ));
+            }
+        }
+        Ok(Registry { actions, ids })
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Action> {
+        self.actions.iter()
+    }
+
+    // Index of the action with ID `id`.
+    pub fn index(&self, id: u8) -> Option<usize> {
+        self.ids.get(id as usize).copied().flatten()
+    }
+
+    // Index of the action named `name`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.actions.iter().position(|a| a.name == name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.actions.iter().map(|a| a.name.as_str()).collect()
+    }
+
+    pub fn templates(&self) -> Vec<&str> {
+        self.actions.iter().map(|a| a.template.as_str()).collect()
+    }
+}
+
+impl Index<usize> for Registry {
+    type Output = Action;
+
+    fn index(&self, i: usize) -> &Action {
+        &self.actions[i]
+    }
+}
+
+impl<'a> IntoIterator for &'a Registry {
+    type Item = &'a Action;
+    type IntoIter = std::slice::Iter<'a, Action>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.actions.iter()
+    }
+}
+
+// Escapes the newlines, commas and backslashes of a field value, so that it
+// can be written into a line.
+pub fn escape(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\n', ',', '\\']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            ',' => escaped.push_str("\\,"),
+            '\\' => escaped.push_str("\\\\"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+// What is wrong with a row.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    // The action isn't the ID of an action in the registry, or is followed
+    // by something other than a deadline.
+    UnknownAction(u8),
+    MissingDeadline,
+    DeadlineOutOfRange,
+    UnknownEscape(u8),
+    // A field is longer than MAX_FIELD_LEN bytes. Fields count from 1, the
+    // action.
+    FieldTooLong(usize),
+    // The line ended after this many fields of a row.
+    MissingFields(usize),
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnknownAction(c) => write!(f, "unknown identifier '{}'", *c as char),
+            ParseErrorKind::MissingDeadline => f.write_str("missing deadline after '@'"),
+            ParseErrorKind::DeadlineOutOfRange => f.write_str("deadline out of range"),
+            ParseErrorKind::UnknownEscape(b'\n') => f.write_str("backslash at the end of a line"),
+            ParseErrorKind::UnknownEscape(c) => {
+                write!(f, "unknown escape sequence '\\{}'", c.escape_ascii())
+            }
+            ParseErrorKind::FieldTooLong(field) => {
+                write!(f, "field {} is longer than {} bytes", field, MAX_FIELD_LEN)
+            }
+            ParseErrorKind::MissingFields(n) => write!(
+                f,
+                "line ended after {} of the {} fields of a row",
+                n, ROW_FIELDS
+            ),
+        }
+    }
+}
+
+// A malformed row, with where it went wrong: the line of the input and the
+// byte within it, both counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub line: usize,
+    pub column: usize,
+    // Whether the byte in error ended its line, which `consume` would
+    // otherwise have reported by returning true.
+    pub ends_line: bool,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// One row as it is read.
+#[derive(Clone, Copy)]
+struct Row {
+    b: [[u8; MAX_FIELD_LEN]; MAX_FIELDS],
+    nb: [usize; MAX_FIELDS],
+    // Position of the row in the input, and the send round it belongs to.
+    // Rows go out round by round, so that a recipient never receives an
+    // email ahead of one that preceded it in the input.
+    seq: usize,
+    round: usize,
+    deadline: Option<u64>,
+}
+
+impl Row {
+    const EMPTY: Row = Row {
+        b: [[0; MAX_FIELD_LEN]; MAX_FIELDS],
+        nb: [0; MAX_FIELDS],
+        seq: 0,
+        round: 0,
+        deadline: None,
+    };
+
+    fn to(&self) -> &[u8] {
+        &self.b[0][..self.nb[0]]
+    }
+}
+
+// Reads lines a byte at a time into fixed-size field buffers. Rows are kept
+// in vectors that are cleared rather than freed by `reset`, so that parsing
+// only allocates while lines grow larger than any before them. Completed
+// lines accumulate until `reset`, so that the rows of several lines can be
+// sent together.
+pub struct Parser {
+    // The actions rows refer to.
+    registry: Registry,
+    rows: [Vec<Row>; MAX_ACTIONS],
+    // The row being read.
+    row: Row,
+    rounds: usize,
+    // Rows of each action before the line being read, and before the last
+    // completed line.
+    line_start: [usize; MAX_ACTIONS],
+    last_line_start: [usize; MAX_ACTIONS],
+    // Set after a backslash, until the character it escapes.
+    escaped: bool,
+    // Set after an error, until the end of the row, which is dropped.
+    skipping: bool,
+    // Position of the last byte read, counting from 1.
+    line: usize,
+    column: usize,
+    i: usize,
+    fidx: usize,
+    fsz: usize,
+}
+
+impl Parser {
+    pub fn new(registry: Registry) -> Self {
+        Parser {
+            registry,
+            rows: Default::default(),
+            row: Row::EMPTY,
+            rounds: 0,
+            line_start: [0; MAX_ACTIONS],
+            last_line_start: [0; MAX_ACTIONS],
+            escaped: false,
+            skipping: false,
+            line: 1,
+            column: 0,
+            i: 0,
+            fidx: 0,
+            fsz: 0,
+        }
+    }
+
+    // Feeds one byte of input. Returns true once it completed a line, whose
+    // rows are then available from `batch` until `reset`. After an error the
+    // rest of the malformed row is skipped, its fields still being counted,
+    // so that a caller that carries on loses only that row.
+    pub fn consume(&mut self, c: u8) -> Result<bool, ParseError> {
+        self.column += 1;
+        let (line, column) = (self.line, self.column);
+        // Only the first error of a row is reported.
+        let skipping = self.skipping;
+        let result = self.read(c);
+        let ends_line = self.line != line;
+        match result {
+            Ok(completed) => Ok(completed),
+            Err(_) if skipping => Ok(ends_line),
+            Err(kind) => {
+                self.skipping = !ends_line;
+                Err(ParseError {
+                    kind,
+                    line,
+                    column,
+                    ends_line,
+                })
+            }
+        }
+    }
+
+    fn read(&mut self, c: u8) -> Result<bool, ParseErrorKind> {
+        let escaped = std::mem::take(&mut self.escaped);
+        if !escaped && c == b'\\' && self.fidx > 0 {
+            self.escaped = true;
+            return Ok(false);
+        }
+        if c == b'\n' || (c == b',' && !escaped) {
+            // A backslash before a newline is an error, but the newline
+            // still ends the line.
+            if escaped {
+                self.skipping = true;
+                self.separate(true)?;
+                return Err(ParseErrorKind::UnknownEscape(c));
+            }
+            return self.separate(c == b'\n');
+        }
+        if self.skipping {
+            return Ok(false);
+        }
+
+        let c = match (escaped, c) {
+            (false, _) => c,
+            (true, b'n') => b'\n',
+            (true, b',' | b'\\') => c,
+            (true, _) => return Err(ParseErrorKind::UnknownEscape(c)),
+        };
+        if self.fidx == 0 {
+            let deadline = &mut self.row.deadline;
+            match (self.fsz, c) {
+                (0, _) => match self.registry.index(c.wrapping_sub(b'0')) {
+                    Some(i) => {
+                        self.i = i;
+                        *deadline = None;
+                    }
+                    None => return Err(ParseErrorKind::UnknownAction(c)),
+                },
+                (1, b'@') => *deadline = Some(0),
+                (_, b'0'..=b'9') if deadline.is_some() => {
+                    *deadline = deadline
+                        .and_then(|d| d.checked_mul(10))
+                        .and_then(|d| d.checked_add((c - b'0') as u64));
+                    if deadline.is_none() {
+                        return Err(ParseErrorKind::DeadlineOutOfRange);
+                    }
+                }
+                _ => return Err(ParseErrorKind::UnknownAction(c)),
+            }
+        } else if self.fsz == MAX_FIELD_LEN {
+            return Err(ParseErrorKind::FieldTooLong(self.fidx + 1));
+        } else {
+            self.row.b[self.fidx - 1][self.fsz] = c;
+        }
+        self.fsz += 1;
+        Ok(false)
+    }
+
+    // Ends the field being read at a comma or newline, and the row once it
+    // has all of its fields; a row being skipped is dropped. At a newline,
+    // also ends the line, dropping a row it cuts short. Returns whether it
+    // ended the line.
+    fn separate(&mut self, newline: bool) -> Result<bool, ParseErrorKind> {
+        // Nothing of a row was read, as on an empty line or after a
+        // trailing comma.
+        let empty = self.fidx == 0 && self.fsz == 0;
+        let error = match self.fidx {
+            0 if self.fsz == 2 => Some(ParseErrorKind::MissingDeadline),
+            0 => None,
+            _ => {
+                self.row.nb[self.fidx - 1] = self.fsz;
+                None
+            }
+        };
+
+        self.fidx += 1;
+        self.fsz = 0;
+        if self.fidx == ROW_FIELDS {
+            if !std::mem::take(&mut self.skipping) {
+                self.order_row();
+                self.rows[self.i].push(self.row);
+            }
+            self.fidx = 0;
+        }
+        if !newline {
+            return error.map_or(Ok(false), Err);
+        }
+
+        let fields = if empty { 0 } else { self.fidx };
+        self.fidx = 0;
+        self.last_line_start = self.line_start;
+        self.line_start = self.rows.each_ref().map(Vec::len);
+        self.line += 1;
+        self.column = 0;
+        let skipped = std::mem::take(&mut self.skipping);
+        match error {
+            Some(error) => Err(error),
+            None if fields > 0 && !skipped => Err(ParseErrorKind::MissingFields(fields)),
+            None => Ok(true),
+        }
+    }
+
+    // Assigns the row being completed to a send round: the same round as the
+    // recipient's previous row if that was for the same action, otherwise the
+    // round after it.
+    fn order_row(&mut self) {
+        let to = self.row.to();
+        let prev = self
+            .rows
+            .iter()
+            .enumerate()
+            .flat_map(|(a, rows)| rows.iter().map(move |row| (a, row)))
+            .filter(|(_, row)| row.to() == to)
+            .max_by_key(|(_, row)| row.seq);
+
+        let round = match prev {
+            Some((a, row)) if a == self.i => row.round,
+            Some((_, row)) => row.round + 1,
+            None => 0,
+        };
+        self.row.seq = self.rows.iter().map(Vec::len).sum();
+        self.row.round = round;
+        self.rounds = self.rounds.max(round + 1);
+    }
+
+    // The rows of the completed lines.
+    pub fn batch(&self) -> Batch<'_> {
+        Batch { parser: self }
+    }
+
+    // Discards the rows of the last completed line, such as a duplicate of
+    // one already processed.
+    pub fn discard_line(&mut self) {
+        for (rows, &start) in self.rows.iter_mut().zip(&self.last_line_start) {
+            rows.truncate(start);
+        }
+        self.line_start = self.last_line_start;
+        self.rounds = self
+            .rows
+            .iter()
+            .flatten()
+            .map(|row| row.round + 1)
+            .max()
+            .unwrap_or(0);
+    }
+
+    // Discards the rows of the completed lines.
+    pub fn reset(&mut self) {
+        for rows in &mut self.rows {
+            rows.clear();
+        }
+        self.line_start = [0; MAX_ACTIONS];
+        self.last_line_start = [0; MAX_ACTIONS];
+        self.rounds = 0;
+    }
+}
+
+// A view of the rows of the completed lines, grouped by action.
+#[derive(Clone, Copy)]
+pub struct Batch<'a> {
+    parser: &'a Parser,
+}
+
+impl Batch<'_> {
+    // Rows across all actions.
+    pub fn len(&self) -> usize {
+        self.parser.rows.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Rows of `action`.
+    pub fn rows(&self, action: usize) -> usize {
+        self.parser.rows[action].len()
+    }
+
+    // Number of send rounds; see `round`.
+    pub fn rounds(&self) -> usize {
+        self.parser.rounds
+    }
+
+    // The send round of a row. Rows of earlier rounds must be sent first.
+    pub fn round(&self, action: usize, row: usize) -> usize {
+        self.parser.rows[action][row].round
+    }
+
+    // The Unix time after which a row is no longer worth sending, if the
+    // producer set one.
+    pub fn deadline(&self, action: usize, row: usize) -> Option<u64> {
+        self.parser.rows[action][row].deadline
+    }
+
+    // Field `field` of a row, counting from the email address.
+    pub fn field(&self, action: usize, row: usize, field: usize) -> &[u8] {
+        let row = &self.parser.rows[action][row];
+        &row.b[field][..row.nb[field]]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions() -> Registry {
+        let action = |id, name: &str, fields: &[&str]| Action {
+            id,
+            name: name.to_string(),
+            template: format!("{}v1", name),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+        };
+        Registry::new(vec![
+            action(1, "welcome", &["login"]),
+            action(3, "receipt", &["login", "order", "total"]),
+        ])
+        .unwrap()
+    }
+
+    // Feeds `input`, returning the number of lines completed.
+    fn feed(parser: &mut Parser, input: &[u8]) -> usize {
+        let mut completed = 0;
+        for &c in input {
+            if parser.consume(c).unwrap() {
+                completed += 1;
+            }
+        }
+        completed
+    }
+
+    // Feeds `input` the way a caller that skips malformed rows does,
+    // returning the errors.
+    fn feed_errors(parser: &mut Parser, input: &[u8]) -> Vec<ParseError> {
+        input
+            .iter()
+            .filter_map(|&c| parser.consume(c).err())
+            .collect()
+    }
+
+    fn fields(batch: Batch<'_>, action: usize, row: usize) -> Vec<String> {
+        (0..MAX_FIELDS)
+            .map(|k| String::from_utf8(batch.field(action, row, k).to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_rows_of_a_line_by_action() {
+        let mut parser = Parser::new(actions());
+        let completed = feed(
+            &mut parser,
+            b"1,a@x.test,alice,,,3,b@x.test,bob,42,9.99,1,c@x.test,carol,,\n",
+        );
+        assert_eq!(completed, 1);
+
+        let batch = parser.batch();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.rows(0), 2);
+        assert_eq!(batch.rows(1), 1);
+        assert_eq!(fields(batch, 0, 0), ["a@x.test", "alice", "", ""]);
+        assert_eq!(fields(batch, 0, 1), ["c@x.test", "carol", "", ""]);
+        assert_eq!(fields(batch, 1, 0), ["b@x.test", "bob", "42", "9.99"]);
+        assert_eq!(batch.deadline(0, 0), None);
+    }
+
+    #[test]
+    fn uses_the_registry_it_was_given() {
+        let mut parser = Parser::new(actions());
+        // 2 is a built-in action, but not one of this registry.
+        let errors: Vec<ParseError> = b"2,a@x.test,a,s,\n"
+            .iter()
+            .filter_map(|&c| parser.consume(c).err())
+            .collect();
+        assert!(matches!(
+            errors[0].kind,
+            ParseErrorKind::UnknownAction(b'2')
+        ));
+        assert!(parser.batch().is_empty());
+
+        feed(&mut parser, b"3,a@x.test,a,1,2\n");
+        assert_eq!(parser.batch().rows(1), 1);
+    }
+
+    #[test]
+    fn reads_deadlines() {
+        let mut parser = Parser::new(actions());
+        feed(&mut parser, b"1@1700000000,a@x.test,alice,,\n");
+        assert_eq!(parser.batch().deadline(0, 0), Some(1700000000));
+    }
+
+    #[test]
+    fn puts_a_recipient_s_rows_of_other_actions_in_later_rounds() {
+        let mut parser = Parser::new(actions());
+        feed(
+            &mut parser,
+            b"1,a@x.test,a,,,1,b@x.test,b,,,3,a@x.test,a,1,2,1,a@x.test,a,,\n",
+        );
+        let batch = parser.batch();
+        assert_eq!(batch.rounds(), 3);
+        assert_eq!(batch.round(0, 0), 0);
+        assert_eq!(batch.round(0, 1), 0);
+        assert_eq!(batch.round(1, 0), 1);
+        assert_eq!(batch.round(0, 2), 2);
+    }
+
+    #[test]
+    fn accumulates_lines_until_reset() {
+        let mut parser = Parser::new(actions());
+        assert_eq!(
+            feed(&mut parser, b"1,a@x.test,a,,\n1,b@x.test,b,,\n1,c@x.test,c"),
+            2
+        );
+        assert_eq!(parser.batch().len(), 2);
+
+        parser.reset();
+        assert!(parser.batch().is_empty());
+        // The line being read survives the reset.
+        assert_eq!(feed(&mut parser, b",,\n"), 1);
+        assert_eq!(fields(parser.batch(), 0, 0), ["c@x.test", "c", "", ""]);
+    }
+
+    #[test]
+    fn discards_the_last_line() {
+        let mut parser = Parser::new(actions());
+        feed(
+            &mut parser,
+            b"1,a@x.test,a,,\n3,b@x.test,b,1,2,1,b@x.test,b,,\n",
+        );
+        assert_eq!(parser.batch().rounds(), 2);
+
+        parser.discard_line();
+        let batch = parser.batch();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch.rows(1), 0);
+        assert_eq!(batch.rounds(), 1);
+        assert_eq!(fields(batch, 0, 0), ["a@x.test", "a", "", ""]);
+    }
+
+    #[test]
+    fn completes_empty_lines_without_rows() {
+        let mut parser = Parser::new(actions());
+        assert_eq!(feed(&mut parser, b"\n\n"), 2);
+        assert!(parser.batch().is_empty());
+    }
+
+    #[test]
+    fn reports_a_field_too_long_and_skips_its_row() {
+        let mut parser = Parser::new(actions());
+        let mut input = b"1,a@x.test,".to_vec();
+        input.extend([b'x'; MAX_FIELD_LEN + 1]);
+        input.extend(b",,,1,b@x.test,b,,\n");
+        let errors = feed_errors(&mut parser, &input);
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::FieldTooLong(3));
+        assert_eq!((errors[0].line, errors[0].column), (1, 12 + MAX_FIELD_LEN));
+        assert!(!errors[0].ends_line);
+        let batch = parser.batch();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(fields(batch, 0, 0), ["b@x.test", "b", "", ""]);
+    }
+
+    #[test]
+    fn accepts_a_field_of_the_maximum_length() {
+        let mut parser = Parser::new(actions());
+        let mut input = b"1,a@x.test,".to_vec();
+        input.extend([b'x'; MAX_FIELD_LEN]);
+        input.extend(b",,\n");
+        assert!(feed_errors(&mut parser, &input).is_empty());
+        assert_eq!(parser.batch().field(0, 0, 1).len(), MAX_FIELD_LEN);
+    }
+
+    #[test]
+    fn reports_too_many_fields_as_a_row_without_an_action() {
+        let mut parser = Parser::new(actions());
+        let errors = feed_errors(&mut parser, b"1,a@x.test,a,,,extra\n");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::UnknownAction(b'e'));
+        assert_eq!((errors[0].line, errors[0].column), (1, 16));
+        // The row before the extra field is complete.
+        assert_eq!(parser.batch().len(), 1);
+    }
+
+    #[test]
+    fn reports_missing_fields_at_the_end_of_the_line() {
+        let mut parser = Parser::new(actions());
+        let errors = feed_errors(&mut parser, b"1,a@x.test,a,,,1,b@x.test\n");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::MissingFields(2));
+        assert_eq!((errors[0].line, errors[0].column), (1, 26));
+        assert!(errors[0].ends_line);
+        assert_eq!(
+            errors[0].to_string(),
+            "line 1, column 26: line ended after 2 of the 5 fields of a row"
+        );
+        assert_eq!(parser.batch().len(), 1);
+    }
+
+    #[test]
+    fn recovers_after_a_skipped_row() {
+        let mut parser = Parser::new(actions());
+        let errors = feed_errors(
+            &mut parser,
+            b"1,a@x.test,a,,,9,b@x.test,b,,,1,c@x.test,c,,\n1,d@x.test,d,,\n",
+        );
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::UnknownAction(b'9'));
+        let batch = parser.batch();
+        let recipients: Vec<String> = (0..batch.rows(0))
+            .map(|j| fields(batch, 0, j).remove(0))
+            .collect();
+        assert_eq!(recipients, ["a@x.test", "c@x.test", "d@x.test"]);
+    }
+
+    #[test]
+    fn reports_one_error_per_row() {
+        let mut parser = Parser::new(actions());
+        let errors = feed_errors(&mut parser, b"1,a@x.test,\\q\\q,,\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::UnknownEscape(b'q'));
+    }
+
+    #[test]
+    fn counts_lines_and_columns_from_1() {
+        let mut parser = Parser::new(actions());
+        let errors = feed_errors(
+            &mut parser,
+            b"1,a@x.test,a,,\n\n3,b@x.test,b,1,2\n1@,c@x.test,c,,\n1,d@x.test,d\\\n",
+        );
+
+        let positions: Vec<(ParseErrorKind, usize, usize)> =
+            errors.iter().map(|e| (e.kind, e.line, e.column)).collect();
+        assert_eq!(
+            positions,
+            [
+                (ParseErrorKind::MissingDeadline, 4, 3),
+                (ParseErrorKind::UnknownEscape(b'\n'), 5, 14),
+            ]
+        );
+        assert_eq!(
+            errors[1].to_string(),
+            "line 5, column 14: backslash at the end of a line"
+        );
+        assert!(errors[1].ends_line);
+    }
+
+    #[test]
+    fn reports_deadlines_out_of_range() {
+        let mut parser = Parser::new(actions());
+        let errors = feed_errors(&mut parser, b"1@99999999999999999999,a@x.test,a,,\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ParseErrorKind::DeadlineOutOfRange);
+        assert!(parser.batch().is_empty());
+    }
+
+    #[test]
+    fn escapes_only_newlines_commas_and_backslashes() {
+        assert!(matches!(escape("say \"hi\" to Zoë 🚀\t"), Cow::Borrowed(_)));
+        assert_eq!(escape("a,b\\c\nd"), "a\\,b\\\\c\\nd");
+
+        let mut parser = Parser::new(actions());
+        let value = "\"q\", C:\\dir\\, 日本\r\n\u{1}";
+        let line = format!("1,a@x.test,{},,\n", escape(value));
+        assert!(feed_errors(&mut parser, line.as_bytes()).is_empty());
+        assert_eq!(parser.batch().field(0, 0, 1), value.as_bytes());
+    }
+}
@@ -0,0 +1,13 @@
+BEGIN;
+
+DROP INDEX IF EXISTS outbox_unsent_idx;
+
+DROP TRIGGER IF EXISTS after_outbox_inserted ON outbox;
+
+DROP FUNCTION IF EXISTS trg_after_outbox_inserted();
+
+DROP TABLE IF EXISTS outbox;
+
+DROP TYPE IF EXISTS outbox_status;
+
+END;
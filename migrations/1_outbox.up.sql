@@ -0,0 +1,40 @@
+BEGIN;
+
+SET client_min_messages = warning;
+
+CREATE TYPE outbox_status AS ENUM (
+    'pending',
+    'sending',
+    'sent',
+    'failed'
+);
+
+-- Lines for the sender's outbox source, each in the collector's format
+CREATE TABLE outbox (
+    id          BIGSERIAL PRIMARY KEY,
+    line        TEXT NOT NULL,
+    status      outbox_status DEFAULT 'pending' NOT NULL,
+    attempts    INTEGER DEFAULT 0 NOT NULL,
+    created_at  INTEGER DEFAULT EXTRACT(EPOCH FROM NOW()) NOT NULL,
+    claimed_at  INTEGER,
+    done_at     INTEGER
+);
+
+CREATE INDEX outbox_unsent_idx ON outbox (id) WHERE status IN ('pending', 'sending');
+
+CREATE OR REPLACE FUNCTION trg_after_outbox_inserted()
+    RETURNS TRIGGER
+    LANGUAGE plpgsql
+AS $$
+BEGIN
+    NOTIFY outbox_insert;
+    RETURN NULL;
+END;
+$$;
+
+CREATE TRIGGER after_outbox_inserted
+    AFTER INSERT ON outbox
+    FOR EACH STATEMENT
+    EXECUTE FUNCTION trg_after_outbox_inserted ();
+
+END;
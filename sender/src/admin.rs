@@ -0,0 +1,232 @@
+use crate::config;
+use crate::gauges::Gauges;
+use crate::metrics::Metrics;
+use crate::stats::Stats;
+use chrono::{DateTime, Utc};
+use mailroom_core::clock::Clock;
+use mailroom_core::{registry, MAX_ACTIONS, MAX_FIELDS};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+const MAX_BODY_LEN: usize = 4096;
+// Routes that change state, which require the token.
+const PROTECTED: [&str; 5] = ["/inject", "/pause", "/resume", "/redirect", "/drain"];
+
+// State the admin endpoint shares with the running sender.
+#[derive(Clone)]
+pub struct Shared {
+    pub stats: Arc<Mutex<Stats>>,
+    pub gauges: Arc<Mutex<Gauges>>,
+    pub metrics: Arc<Mutex<Metrics>>,
+    // Actions whose rows are diverted to the dead-letter directory.
+    pub paused: Arc<Mutex<[bool; MAX_ACTIONS]>>,
+    // Address every email is sent to instead of its recipient.
+    pub redirect: Arc<Mutex<Option<String>>>,
+    // Bearer token required by the routes that change state. Those routes
+    // are disabled when it is not set.
+    pub token: Option<String>,
+    // Input lines to process as test rows.
+    pub inject: mpsc::Sender<Vec<u8>>,
+    // Requests to drain input, as on SIGTERM.
+    pub drain: mpsc::Sender<()>,
+    pub clock: Arc<dyn Clock>,
+}
+
+// Returns the index of the action named in a body such as
+// {"action": "activation"}.
+fn action(body: &Value) -> Result<usize, String> {
+    let action = body["action"].as_str().unwrap_or_default();
+    registry()
+        .position(action)
+        .ok_or_else(|| format!("action must be one of {}", registry().names().join(", ")))
+}
+
+// Builds a test row for `action` addressed to `to` from a body such as
+// {"action": "activation", "to": "ops@example.com"}.
+fn test_row(body: &[u8], clock: &dyn Clock) -> Result<Vec<u8>, String> {
+    let body: Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let i = action(&body)?;
+    let to = body["to"].as_str().unwrap_or_default();
+    if !config::is_valid_address(to) || to.contains(',') {
+        return Err("to must be an email address".to_string());
+    }
+    let secret = format!(
+        "test-{}",
+        DateTime::<Utc>::from(clock.system()).format("%Y%m%d%H%M%S%.3f")
+    );
+    let action = &registry()[i];
+    let fields: Vec<&str> = (0..MAX_FIELDS - 1)
+        .map(|k| match action.fields.get(k).map(String::as_str) {
+            Some("secret") => secret.as_str(),
+            Some("code") => "000000",
+            Some(_) => "test",
+            None => "",
+        })
+        .collect();
+    Ok(format!("{},{},{}\n", action.id, to, fields.join(",")).into_bytes())
+}
+
+// Pauses or resumes the action named in `body`, and returns the actions
+// now paused.
+fn set_paused(shared: &Shared, body: &[u8], paused: bool) -> Result<Value, String> {
+    let body: Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let i = action(&body)?;
+    let mut actions = shared.paused.lock().unwrap();
+    if actions[i] != paused {
+        actions[i] = paused;
+        if paused {
+            log!(
+                "WARN: {} paused; its rows go to the dead-letter directory",
+                registry()[i].name
+            );
+        } else {
+            log!("{} resumed", registry()[i].name);
+        }
+    }
+    let names: Vec<&str> = registry()
+        .names()
+        .into_iter()
+        .zip(actions.iter())
+        .filter(|(_, &p)| p)
+        .map(|(a, _)| a)
+        .collect();
+    Ok(json!({ "paused": names }))
+}
+
+// Redirects every email to the address in a body such as
+// {"to": "catch-all@example.com"}, or stops redirecting on {"to": null}.
+fn set_redirect(shared: &Shared, body: &[u8]) -> Result<Value, String> {
+    let body: Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let to = match &body["to"] {
+        Value::Null => None,
+        Value::String(to) if config::is_valid_address(to) && !to.contains(',') => Some(to.clone()),
+        _ => return Err("to must be an email address or null".to_string()),
+    };
+    let mut redirect = shared.redirect.lock().unwrap();
+    if *redirect != to {
+        match &to {
+            Some(to) => log!("WARN: redirecting every email to {}", to),
+            None => log!("no longer redirecting emails"),
+        }
+        *redirect = to;
+    }
+    Ok(json!({ "redirect_to": *redirect }))
+}
+
+// Serves the admin HTTP endpoint on `listener`:
+//
+//   GET /stats     rolling success and failure rates per template
+//   GET /gauges    input backlog and throttling, for autoscalers
+//   GET /metrics   counters and send latencies, for Prometheus
+//   POST /inject   sends a test row through the pipeline
+//   POST /pause    diverts the rows of an action to the dead-letter directory
+//   POST /resume   sends the rows of a paused action again
+//   POST /redirect sends every email to one address, or stops doing so
+pub async fn serve(listener: TcpListener, shared: Shared) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log!("ERROR: admin endpoint failed to accept: {}", e);
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &shared).await {
+                log!("WARN: admin request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(stream: TcpStream, shared: &Shared) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    let mut authorization = None;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim().to_string();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "authorization" => authorization = Some(value),
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length.min(MAX_BODY_LEN)];
+    reader.read_exact(&mut body).await?;
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let authorized = match (&shared.token, &authorization) {
+        (Some(token), Some(auth)) => auth.strip_prefix("Bearer ") == Some(token.as_str()),
+        _ => false,
+    };
+
+    let protected = PROTECTED.contains(&path);
+
+    let (status, body) = match (method, path) {
+        ("GET", "/stats") => ("200 OK", shared.stats.lock().unwrap().to_json().to_string()),
+        ("GET", "/gauges") => (
+            "200 OK",
+            shared.gauges.lock().unwrap().to_json().to_string(),
+        ),
+        ("GET", "/metrics") => ("200 OK", shared.metrics.lock().unwrap().to_text()),
+        ("POST", _) if protected && shared.token.is_none() => ("404 Not Found", String::new()),
+        ("POST", _) if protected && !authorized => ("401 Unauthorized", String::new()),
+        ("POST", "/inject") => match test_row(&body, &*shared.clock) {
+            Ok(row) => match shared.inject.send(row).await {
+                Ok(()) => ("202 Accepted", String::new()),
+                Err(_) => ("503 Service Unavailable", String::new()),
+            },
+            Err(e) => ("400 Bad Request", json!({ "error": e }).to_string()),
+        },
+        ("POST", "/pause" | "/resume") => match set_paused(shared, &body, path == "/pause") {
+            Ok(paused) => ("200 OK", paused.to_string()),
+            Err(e) => ("400 Bad Request", json!({ "error": e }).to_string()),
+        },
+        ("POST", "/redirect") => match set_redirect(shared, &body) {
+            Ok(redirect) => ("200 OK", redirect.to_string()),
+            Err(e) => ("400 Bad Request", json!({ "error": e }).to_string()),
+        },
+        // A drain already requested or under way makes no difference.
+        ("POST", "/drain") => match shared.drain.try_send(()) {
+            Ok(()) | Err(mpsc::error::TrySendError::Full(())) => ("202 Accepted", String::new()),
+            Err(mpsc::error::TrySendError::Closed(())) => {
+                ("503 Service Unavailable", String::new())
+            }
+        },
+        (_, "/stats" | "/gauges" | "/metrics") => ("405 Method Not Allowed", String::new()),
+        _ if protected => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let content_type = match path {
+        "/metrics" => "text/plain; version=0.0.4",
+        _ => "application/json",
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    let mut stream = reader.into_inner();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,43 @@
+use regex::Regex;
+
+// Recipients a sender may email, so that a pre-production deployment with
+// production-like data never emails real customers.
+pub struct Allowlist {
+    addresses: Vec<String>,
+    domains: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Allowlist {
+    // Parses comma-separated entries, each an address such as
+    // "qa@example.com", a domain such as "example.com", or a regular
+    // expression between slashes such as "/^qa\+.*@example\.com$/".
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut allowlist = Allowlist {
+            addresses: Vec::new(),
+            domains: Vec::new(),
+            patterns: Vec::new(),
+        };
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            if let Some(pattern) = entry.strip_prefix('/').and_then(|e| e.strip_suffix('/')) {
+                let pattern = Regex::new(pattern).map_err(|e| format!("{}: {}", entry, e))?;
+                allowlist.patterns.push(pattern);
+            } else if entry.contains('@') {
+                allowlist.addresses.push(entry.to_lowercase());
+            } else {
+                allowlist.domains.push(entry.to_lowercase());
+            }
+        }
+        Ok(allowlist)
+    }
+
+    // Whether `to` may be emailed. Addresses and domains are compared
+    // case-insensitively.
+    pub fn allows(&self, to: &str) -> bool {
+        let to_lower = to.to_lowercase();
+        let domain = to_lower.rsplit_once('@').map_or("", |(_, d)| d);
+        self.addresses.contains(&to_lower)
+            || self.domains.iter().any(|d| d == domain)
+            || self.patterns.iter().any(|p| p.is_match(to))
+    }
+}
@@ -0,0 +1,66 @@
+use mailroom_core::clock::Clock;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Pauses sending after SES reports throttling. The pause is taken from the
+// Retry-After header when SES provides one, and otherwise doubles with every
+// consecutive throttling error, starting at `min` and capped at `max`.
+pub struct Backoff {
+    until: Option<Instant>,
+    delay: Duration,
+    min: Duration,
+    max: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration, clock: Arc<dyn Clock>) -> Self {
+        Backoff {
+            until: None,
+            delay: min,
+            min,
+            max,
+            clock,
+        }
+    }
+
+    pub async fn wait(&self) {
+        if let Some(until) = self.until {
+            tokio::time::sleep(until.saturating_duration_since(self.clock.now())).await;
+        }
+    }
+
+    // Records a throttling error and returns how long sending is paused for.
+    pub fn throttled(&mut self, retry_after: Option<Duration>) -> Duration {
+        let pause = retry_after.unwrap_or(self.delay).min(self.max);
+        self.delay = (self.delay * 2).min(self.max);
+        self.until = Some(self.clock.now() + pause);
+        pause
+    }
+
+    pub fn succeeded(&mut self) {
+        self.delay = self.min;
+        self.until = None;
+    }
+}
+
+// The pause before the `attempt`th retry of destinations that failed
+// transiently: `base`, doubled with every attempt up to a minute, of which
+// half is random so that the retries of many senders don't line up.
+pub fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let delay = base
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(Duration::from_secs(60));
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    delay / 2 + (delay / 2).mul_f64(random)
+}
+
+// Parses a Retry-After header value given in seconds.
+pub fn retry_after(value: Option<&str>) -> Option<Duration> {
+    value
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+}
@@ -0,0 +1,141 @@
+use mailroom_core::clock::Clock;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+const HOUR: u64 = 3600;
+const WINDOW_HOURS: u64 = 24;
+
+fn current_hour(clock: &dyn Clock) -> u64 {
+    clock
+        .system()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / HOUR)
+}
+
+// Caps the emails sent over the last 24 hours, across actions and restarts,
+// so that a runaway producer can't use up the SES budget overnight. Sends
+// are counted per hour in a file of "<hour> <count>" lines.
+pub struct Budget {
+    path: PathBuf,
+    limit: u64,
+    // Hours since the epoch to emails sent during that hour.
+    hours: BTreeMap<u64, u64>,
+    // Set once the budget ran out, until it has room again.
+    exhausted: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Budget {
+    pub fn open(path: PathBuf, limit: u64, clock: Arc<dyn Clock>) -> io::Result<Self> {
+        let mut hours = BTreeMap::new();
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    if let Some((hour, count)) = line.split_once(' ') {
+                        if let (Ok(hour), Ok(count)) = (hour.parse(), count.parse()) {
+                            hours.insert(hour, count);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Budget {
+            path,
+            limit,
+            hours,
+            exhausted: false,
+            clock,
+        })
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    // Emails that can still be sent within the window.
+    pub fn remaining(&mut self) -> u64 {
+        let start = current_hour(&*self.clock).saturating_sub(WINDOW_HOURS - 1);
+        let used: u64 = self.hours.range(start..).map(|(_, n)| n).sum();
+        let remaining = self.limit.saturating_sub(used);
+        if remaining > 0 {
+            self.exhausted = false;
+        }
+        remaining
+    }
+
+    // Marks the budget as used up. Returns true only the first time since
+    // it last had room, so that running out is reported once.
+    pub fn exhaust(&mut self) -> bool {
+        !std::mem::replace(&mut self.exhausted, true)
+    }
+
+    // Counts `sent` emails against the budget and returns once that is on
+    // disk.
+    pub fn spend(&mut self, sent: u64) -> io::Result<()> {
+        if sent == 0 {
+            return Ok(());
+        }
+        let hour = current_hour(&*self.clock);
+        *self.hours.entry(hour).or_default() += sent;
+        self.hours = self.hours.split_off(&hour.saturating_sub(WINDOW_HOURS - 1));
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for (hour, count) in &self.hours {
+            writeln!(file, "{} {}", hour, count)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailroom_core::clock::ManualClock;
+    use std::time::Duration;
+
+    #[test]
+    fn rolls_the_window_over_hour_by_hour() {
+        let path =
+            std::env::temp_dir().join(format!("mailroom-budget-{}.journal", std::process::id()));
+        let _ = fs::remove_file(&path);
+        // On the hour, so that the hours below are whole.
+        let clock = Arc::new(ManualClock::new(
+            UNIX_EPOCH + Duration::from_secs(480_000 * HOUR),
+        ));
+        let mut budget = Budget::open(path.clone(), 100, clock.clone()).unwrap();
+
+        budget.spend(60).unwrap();
+        clock.advance(Duration::from_secs(12 * HOUR));
+        budget.spend(30).unwrap();
+        assert_eq!(budget.remaining(), 10);
+        budget.spend(10).unwrap();
+        assert_eq!(budget.remaining(), 0);
+        assert!(budget.exhaust());
+        assert!(!budget.exhaust());
+
+        // The first hour is still in the window in its 24th hour...
+        clock.advance(Duration::from_secs(12 * HOUR - 1));
+        assert_eq!(budget.remaining(), 0);
+        // ...and leaves it at the next.
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(budget.remaining(), 60);
+        assert!(budget.exhaust());
+
+        // Spending drops the hours that left the window from the file, and
+        // the rest is counted after a restart.
+        budget.spend(5).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        let mut budget = Budget::open(path.clone(), 100, clock.clone()).unwrap();
+        assert_eq!(budget.remaining(), 55);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use crate::config::Config;
+use crate::mailer::{Destination, Mailer, Request};
+use chrono::{DateTime, Utc};
+use mailroom_core::clock::Clock;
+use mailroom_core::registry;
+use serde_json::Value;
+
+// SES mailbox simulator addresses. SES accepts mail to each of them; the
+// bounce and complaint are reported later through the event destination.
+pub const SIMULATOR: [&str; 3] = [
+    "success@simulator.amazonses.com",
+    "bounce@simulator.amazonses.com",
+    "complaint@simulator.amazonses.com",
+];
+
+// Template data for a canary: every field of the action filled with a
+// recognizable placeholder, on top of the globals and default data.
+fn synthetic_data(config: &Config, clock: &dyn Clock, action: usize) -> String {
+    let mut data = config.globals.clone();
+    data.extend(config.default_data[action].clone());
+    for name in &registry()[action].fields {
+        let value = match name.as_str() {
+            "login" => "canary".to_string(),
+            "code" => "000000".to_string(),
+            _ => format!(
+                "canary-{}",
+                DateTime::<Utc>::from(clock.system()).format("%Y%m%d%H%M%S")
+            ),
+        };
+        data.insert(name.to_string(), Value::String(value));
+    }
+    Value::Object(data).to_string()
+}
+
+// Sends one real email for `action` to `to` and checks that SES accepted
+// the destination. Returns the message id.
+pub async fn send(
+    mailer: &dyn Mailer,
+    config: &Config,
+    clock: &dyn Clock,
+    action: usize,
+    to: &str,
+) -> Result<String, String> {
+    let data = synthetic_data(config, clock, action);
+    let destinations = [Destination {
+        to: to.to_string(),
+        bcc: None,
+        data: data.clone(),
+    }];
+
+    let response = mailer
+        .send(Request {
+            template: &registry()[action].template,
+            subject: None,
+            config_set: config.config_set(action),
+            source: &config.from_email,
+            default_data: &data,
+            destinations: &destinations,
+            tags: &config.tags,
+            test: false,
+        })
+        .await;
+
+    let delivery = response
+        .deliveries
+        .first()
+        .ok_or("SES returned no destination status")?;
+    match (&response.failure, delivery.accepted()) {
+        (None, true) => Ok(delivery.message_id.clone().unwrap_or_default()),
+        (None, false) => Err(format!(
+            "destination status {}: {}",
+            delivery.code,
+            delivery.error.as_deref().unwrap_or_default()
+        )),
+        (Some(_), _) => Err(delivery.error.clone().unwrap_or_default()),
+    }
+}
+
+// Sends every action to every simulator address, printing one line per
+// send. Returns whether all of them were accepted.
+pub async fn selftest(mailer: &dyn Mailer, config: &Config, clock: &dyn Clock) -> bool {
+    let mut passed = 0;
+    let mut total = 0;
+    for (action, template) in registry().templates().into_iter().enumerate() {
+        for to in SIMULATOR {
+            total += 1;
+            match send(mailer, config, clock, action, to).await {
+                Ok(message_id) => {
+                    passed += 1;
+                    println!("PASS  {}  {}  {}", template, to, message_id);
+                }
+                Err(e) => println!("FAIL  {}  {}  {}", template, to, e),
+            }
+        }
+    }
+    println!("{}/{} passed", passed, total);
+    passed == total
+}
@@ -0,0 +1,100 @@
+use crate::secrets::Secret;
+use aws_credential_types::provider::error::CredentialsError;
+use aws_credential_types::provider::{future, ProvideCredentials};
+use aws_credential_types::Credentials;
+use serde_json::Value;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// AWS credentials read from a secret on every request, so that keys rotated
+// in Secrets Manager or in a mounted file are picked up by the clients
+// already built, without restarting and without losing queued work.
+#[derive(Clone)]
+pub struct Rotating {
+    secret: Secret,
+    // The value last read and the last credentials read, which are kept
+    // while the secret holds something else, such as a file caught halfway
+    // through being rewritten.
+    last: Arc<Mutex<(String, Option<Credentials>)>>,
+}
+
+impl Rotating {
+    pub fn new(secret: Secret) -> Self {
+        Rotating {
+            secret,
+            last: Arc::new(Mutex::new((String::new(), None))),
+        }
+    }
+
+    fn get(&self) -> Result<Credentials, String> {
+        let value = self.secret.get();
+        let mut last = self.last.lock().unwrap();
+        if last.0 != value {
+            match parse(&value) {
+                Ok(credentials) => last.1 = Some(credentials),
+                Err(e) if last.1.is_some() => {
+                    log!("WARN: ignoring new AWS credentials: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+            last.0 = value;
+        }
+        last.1
+            .clone()
+            .ok_or_else(|| "no AWS credentials".to_string())
+    }
+}
+
+// Only the secret's value is sensitive.
+impl fmt::Debug for Rotating {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Rotating")
+    }
+}
+
+impl ProvideCredentials for Rotating {
+    fn provide_credentials<'a>(&'a self) -> future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        future::ProvideCredentials::ready(
+            self.get().map_err(CredentialsError::invalid_configuration),
+        )
+    }
+}
+
+// Reads credentials from a JSON object with the AccessKeyId, SecretAccessKey
+// and optional SessionToken keys, as in the output of `aws sts` and
+// credential processes, or from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and
+// optional AWS_SESSION_TOKEN lines of an environment file.
+fn parse(value: &str) -> Result<Credentials, String> {
+    let mut keys = [None, None, None];
+    if let Ok(Value::Object(json)) = serde_json::from_str(value) {
+        for (key, name) in keys
+            .iter_mut()
+            .zip(["AccessKeyId", "SecretAccessKey", "SessionToken"])
+        {
+            *key = json.get(name).and_then(Value::as_str).map(str::to_string);
+        }
+    } else {
+        for line in value.lines().map(str::trim) {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            let i = match name.trim() {
+                "AWS_ACCESS_KEY_ID" => 0,
+                "AWS_SECRET_ACCESS_KEY" => 1,
+                "AWS_SESSION_TOKEN" => 2,
+                _ => continue,
+            };
+            keys[i] = Some(value.to_string());
+        }
+    }
+
+    let [Some(id), Some(secret), token] = keys else {
+        return Err("expected an access key id and a secret access key".to_string());
+    };
+    Ok(Credentials::new(id, secret, token, None, "mailroom"))
+}
@@ -0,0 +1,110 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+const PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+
+// Decrypts field values that producers encrypted so that they don't travel
+// through queues and pipes in plaintext. An encrypted value is "enc:"
+// followed by the unpadded URL-safe base64 of a 12-byte nonce and the
+// AES-256-GCM ciphertext.
+pub struct FieldKey {
+    cipher: Aes256Gcm,
+}
+
+impl FieldKey {
+    // Parses a key given as 64 hexadecimal characters.
+    pub fn from_hex(key: &str) -> Result<Self, String> {
+        let bytes = hex::decode(key).map_err(|e| e.to_string())?;
+        let cipher = Aes256Gcm::new_from_slice(&bytes)
+            .map_err(|_| format!("expected a 32-byte key, got {} bytes", bytes.len()))?;
+        Ok(FieldKey { cipher })
+    }
+}
+
+// Returns `value` decrypted if it is encrypted, or unchanged if it isn't.
+pub fn decrypt(key: Option<&FieldKey>, value: &str) -> Result<String, String> {
+    let Some(encoded) = value.strip_prefix(PREFIX) else {
+        return Ok(value.to_string());
+    };
+    let key = key.ok_or("encrypted field but no MAILROOM_FIELD_KEY is configured")?;
+
+    let data = URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|e| format!("invalid encrypted field: {}", e))?;
+    if data.len() < NONCE_LEN {
+        return Err("invalid encrypted field: too short".to_string());
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let nonce = Nonce::try_from(nonce).map_err(|_| "invalid encrypted field: bad nonce")?;
+
+    let plaintext = key
+        .cipher
+        .decrypt(&nonce, ciphertext)
+        .map_err(|_| "failed to decrypt field; wrong key or corrupted value")?;
+    String::from_utf8(plaintext).map_err(|_| "decrypted field is not valid UTF-8".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const OTHER_KEY: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    // Encrypts `plaintext` the way producers do.
+    fn encrypt(key: &str, plaintext: &str) -> String {
+        let key = FieldKey::from_hex(key).unwrap();
+        let nonce = [7; NONCE_LEN];
+        let mut data = nonce.to_vec();
+        data.extend(
+            key.cipher
+                .encrypt(&Nonce::from(nonce), plaintext.as_bytes())
+                .unwrap(),
+        );
+        format!("{}{}", PREFIX, URL_SAFE_NO_PAD.encode(data))
+    }
+
+    #[test]
+    fn decrypts_encrypted_fields_and_passes_plaintext_through() {
+        let key = FieldKey::from_hex(KEY).unwrap();
+        let value = encrypt(KEY, "jane.smith");
+        assert_eq!(decrypt(Some(&key), &value).unwrap(), "jane.smith");
+        assert_eq!(decrypt(Some(&key), "jane.smith").unwrap(), "jane.smith");
+        assert_eq!(decrypt(None, "jane.smith").unwrap(), "jane.smith");
+    }
+
+    #[test]
+    fn rejects_fields_it_cannot_decrypt() {
+        let key = FieldKey::from_hex(KEY).unwrap();
+        assert_eq!(
+            decrypt(Some(&key), &encrypt(OTHER_KEY, "jane.smith")),
+            Err("failed to decrypt field; wrong key or corrupted value".to_string())
+        );
+        assert_eq!(
+            decrypt(None, &encrypt(KEY, "jane.smith")),
+            Err("encrypted field but no MAILROOM_FIELD_KEY is configured".to_string())
+        );
+        let short = format!("{}{}", PREFIX, URL_SAFE_NO_PAD.encode([0; NONCE_LEN - 1]));
+        assert_eq!(
+            decrypt(Some(&key), &short),
+            Err("invalid encrypted field: too short".to_string())
+        );
+        // A nonce without a ciphertext lacks the authentication tag.
+        let empty = format!("{}{}", PREFIX, URL_SAFE_NO_PAD.encode([0; NONCE_LEN]));
+        assert!(decrypt(Some(&key), &empty).is_err());
+        assert!(decrypt(Some(&key), "enc:not base64!").is_err());
+    }
+
+    #[test]
+    fn accepts_only_32_byte_hexadecimal_keys() {
+        assert!(FieldKey::from_hex(&KEY[..62]).is_err());
+        assert_eq!(
+            FieldKey::from_hex(&KEY[..32]).err(),
+            Some("expected a 32-byte key, got 16 bytes".to_string())
+        );
+        assert!(FieldKey::from_hex(&KEY.replace('0', "g")).is_err());
+    }
+}
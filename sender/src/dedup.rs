@@ -0,0 +1,206 @@
+use mailroom_core::clock::Clock;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn now_ms(clock: &dyn Clock) -> u64 {
+    clock
+        .system()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn write_entry(file: &mut File, ts: u64, hash: &str, detail: &str) -> io::Result<()> {
+    if detail.is_empty() {
+        writeln!(file, "{} {}", ts, hash)
+    } else {
+        writeln!(file, "{} {} {}", ts, hash, detail)
+    }
+}
+
+// Remembers the hashes of recently processed input, persisted to a journal
+// of "<unix-ms> <hash> [<detail>]" lines, so that a producer replaying its
+// last lines after a reconnect or a restart doesn't cause duplicate sends.
+pub struct Seen {
+    path: PathBuf,
+    window_ms: u64,
+    // Hash to timestamp and detail.
+    entries: HashMap<String, (u64, String)>,
+    file: File,
+    // Lines in the journal, including expired ones.
+    written: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl Seen {
+    // Loads the journal at `path`, dropping entries older than `window`.
+    pub fn open(path: PathBuf, window: Duration, clock: Arc<dyn Clock>) -> io::Result<Self> {
+        let window_ms = window.as_millis() as u64;
+        let cutoff = now_ms(&*clock).saturating_sub(window_ms);
+
+        let mut entries = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let mut parts = line.splitn(3, ' ');
+                    if let (Some(ts), Some(hash)) = (parts.next(), parts.next()) {
+                        if let Ok(ts) = ts.parse::<u64>() {
+                            if ts >= cutoff {
+                                let detail = parts.next().unwrap_or_default().to_string();
+                                entries.insert(hash.to_string(), (ts, detail));
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let file = Self::rewrite(&path, &entries)?;
+        Ok(Seen {
+            path,
+            window_ms,
+            written: entries.len(),
+            entries,
+            file,
+            clock,
+        })
+    }
+
+    // Replaces the journal with the given entries and reopens it for
+    // appending.
+    fn rewrite(path: &PathBuf, entries: &HashMap<String, (u64, String)>) -> io::Result<File> {
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for (hash, (ts, detail)) in entries {
+            write_entry(&mut file, *ts, hash, detail)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        OpenOptions::new().append(true).open(path)
+    }
+
+    pub fn hash(line: &[u8]) -> String {
+        hex::encode(Sha256::digest(line))
+    }
+
+    // Whether a line with this hash was processed within the window.
+    pub fn contains(&self, hash: &str) -> bool {
+        self.entries
+            .get(hash)
+            .is_some_and(|&(ts, _)| ts >= now_ms(&*self.clock).saturating_sub(self.window_ms))
+    }
+
+    pub fn insert(&mut self, hash: String) -> io::Result<()> {
+        self.record(vec![(hash, String::new())])
+    }
+
+    // Records processed hashes, each with a detail kept in the journal for
+    // operators, and returns once they are on disk. The journal is
+    // compacted once expired entries make up most of it.
+    pub fn record(&mut self, records: Vec<(String, String)>) -> io::Result<()> {
+        let ts = now_ms(&*self.clock);
+        for (hash, detail) in &records {
+            write_entry(&mut self.file, ts, hash, detail)?;
+        }
+        self.file.sync_data()?;
+        self.written += records.len();
+
+        let cutoff = ts.saturating_sub(self.window_ms);
+        self.entries.retain(|_, (t, _)| *t >= cutoff);
+        for (hash, detail) in records {
+            self.entries.insert(hash, (ts, detail));
+        }
+
+        if self.written > 2 * self.entries.len().max(64) {
+            self.file = Self::rewrite(&self.path, &self.entries)?;
+            self.written = self.entries.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailroom_core::clock::ManualClock;
+    use std::time::SystemTime;
+
+    const WINDOW: Duration = Duration::from_secs(600);
+
+    // A journal of its own in the temporary directory.
+    fn journal(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mailroom-dedup-{}-{}.journal",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn lines(path: &PathBuf) -> usize {
+        fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[test]
+    fn remembers_hashes_for_the_window_and_across_restarts() {
+        let path = journal("reload");
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let mut seen = Seen::open(path.clone(), WINDOW, clock.clone()).unwrap();
+
+        seen.insert("old".to_string()).unwrap();
+        clock.advance(Duration::from_secs(400));
+        seen.record(vec![("new".to_string(), "line 2".to_string())])
+            .unwrap();
+        assert!(seen.contains("old") && seen.contains("new"));
+        assert!(!seen.contains("other"));
+
+        clock.advance(Duration::from_secs(300));
+        assert!(!seen.contains("old"));
+        assert!(seen.contains("new"));
+
+        // Reopening drops what expired and keeps the detail of the rest.
+        drop(seen);
+        let seen = Seen::open(path.clone(), WINDOW, clock.clone()).unwrap();
+        assert!(!seen.contains("old"));
+        assert!(seen.contains("new"));
+        assert_eq!(seen.entries["new"].1, "line 2");
+        assert_eq!(lines(&path), 1);
+
+        clock.advance(WINDOW);
+        assert!(!seen.contains("new"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compacts_the_journal_once_expired_entries_make_up_most_of_it() {
+        let path = journal("compact");
+        let clock = Arc::new(ManualClock::new(SystemTime::now()));
+        let mut seen = Seen::open(path.clone(), WINDOW, clock.clone()).unwrap();
+
+        let hashes = |from: usize, to: usize| -> Vec<(String, String)> {
+            (from..to).map(|i| (i.to_string(), String::new())).collect()
+        };
+        seen.record(hashes(0, 100)).unwrap();
+        assert_eq!(lines(&path), 100);
+
+        clock.advance(WINDOW + Duration::from_secs(1));
+        seen.record(hashes(100, 128)).unwrap();
+        // A journal of up to twice 64 lines is left alone, however few of
+        // them are live.
+        assert_eq!(lines(&path), 128);
+        seen.record(hashes(128, 130)).unwrap();
+        assert_eq!(lines(&path), 30);
+        assert_eq!(seen.entries.len(), 30);
+        assert!(!seen.contains("0"));
+        assert!(seen.contains("129"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
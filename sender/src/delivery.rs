@@ -0,0 +1,64 @@
+use crate::errors::ErrorClass;
+
+// What became of a destination, in terms that don't depend on the provider.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // The provider took the email for delivery.
+    Accepted,
+    // Rejected, and sending it again will be rejected again.
+    RejectedPermanent,
+    // Rejected for now; sending it again can succeed, possibly once an
+    // operator fixed the configuration.
+    RejectedTransient,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Accepted => "accepted",
+            Outcome::RejectedPermanent => "rejected_permanent",
+            Outcome::RejectedTransient => "rejected_transient",
+        }
+    }
+}
+
+// The result of sending to one destination.
+pub struct Delivery {
+    pub outcome: Outcome,
+    pub class: Option<ErrorClass>,
+    // The provider's own status, such as "MessageRejected", kept as is.
+    pub code: String,
+    pub message_id: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Delivery {
+    pub fn new(class: Option<ErrorClass>, code: &str) -> Self {
+        let outcome = match class {
+            None => Outcome::Accepted,
+            Some(ErrorClass::Permanent) => Outcome::RejectedPermanent,
+            Some(_) => Outcome::RejectedTransient,
+        };
+        Delivery {
+            outcome,
+            class,
+            code: code.to_string(),
+            message_id: None,
+            error: None,
+        }
+    }
+
+    pub fn accepted(&self) -> bool {
+        self.outcome == Outcome::Accepted
+    }
+}
+
+// Deliveries for `count` destinations of a request that failed as a whole.
+pub fn failed(count: usize, class: ErrorClass, error: &str) -> Vec<Delivery> {
+    (0..count)
+        .map(|_| Delivery {
+            error: Some(error.to_string()),
+            ..Delivery::new(Some(class), "Failed")
+        })
+        .collect()
+}
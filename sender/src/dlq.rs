@@ -0,0 +1,215 @@
+use crate::delivery::Delivery;
+use chrono::{DateTime, Utc};
+use mailroom_core::clock::Clock;
+use mailroom_core::{escape, registry};
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Distinguishes entries written within the same timestamp.
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+// Rows that failed to send, one JSON file each. Every entry keeps the
+// original input row, so that it can be fed back to the sender as is.
+// The rows can also be appended to a file or FIFO, one line each, for a
+// consumer that replays them.
+pub struct DeadLetters {
+    dir: PathBuf,
+    stream: Option<PathBuf>,
+    clock: Arc<dyn Clock>,
+}
+
+// Formats the fields of a row back into an input row.
+pub fn row_line(action: usize, deadline: Option<u64>, fields: &[String]) -> String {
+    let id = registry()[action].id;
+    let fields: Vec<_> = fields.iter().map(|f| escape(f)).collect();
+    match deadline {
+        Some(deadline) => format!("{}@{},{}", id, deadline, fields.join(",")),
+        None => format!("{},{}", id, fields.join(",")),
+    }
+}
+
+impl DeadLetters {
+    pub fn new(dir: &str, stream: Option<&str>, clock: Arc<dyn Clock>) -> Self {
+        DeadLetters {
+            dir: PathBuf::from(dir),
+            stream: stream.map(PathBuf::from),
+            clock,
+        }
+    }
+
+    // Appends `row` as a line of its own. Opened for every row, so that the
+    // file can be rotated and a FIFO's reader can come and go; without a
+    // reader the open fails rather than blocking the sender. The line is
+    // written with a single write, which a FIFO makes atomic since rows are
+    // far shorter than PIPE_BUF: when the pipe is full the write fails with
+    // EAGAIN and nothing is written, instead of leaving a partial line for
+    // the reader.
+    fn emit(&self, path: &Path, row: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+        let line = format!("{}\n", row);
+        match file.write(line.as_bytes()) {
+            Ok(n) if n == line.len() => Ok(()),
+            Ok(n) => Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("wrote {} of {} bytes", n, line.len()),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(io::Error::new(
+                e.kind(),
+                "the reader is not keeping up; row not written",
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn add(
+        &self,
+        template: &str,
+        to: &str,
+        row: &str,
+        class: &str,
+        error: &str,
+    ) -> io::Result<PathBuf> {
+        let now = DateTime::<Utc>::from(self.clock.system());
+        let id = format!(
+            "{}_{}",
+            now.format("%Y%m%d%H%M%S%.6f"),
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        );
+        let entry = json!({
+            "id": id,
+            "timestamp": now.to_rfc3339(),
+            "template": template,
+            "to": to,
+            "row": row,
+            "class": class,
+            "error": error,
+        });
+        let path = self.dir.join(format!("{}.json", id));
+        fs::write(&path, format!("{:#}\n", entry))?;
+        Ok(path)
+    }
+
+    // Adds an entry for a row that was rejected before it was sent.
+    pub fn reject(&self, template: &str, to: &str, row: &str, class: &str, error: &str) {
+        if let Err(e) = self.add(template, to, row, class, error) {
+            log!(
+                "ERROR: failed to write dead letter for {} to {}: {}",
+                to,
+                self.dir.display(),
+                e
+            );
+        }
+        if let Some(path) = &self.stream {
+            if let Err(e) = self.emit(path, row) {
+                log!(
+                    "ERROR: failed to write dead letter for {} to {}: {}",
+                    to,
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    // Adds an entry for every row of a bulk send that failed, either because
+    // the whole request failed or because its destination was rejected.
+    pub fn record(
+        &self,
+        template: &str,
+        recipients: &[String],
+        rows: &[String],
+        deliveries: &[Delivery],
+    ) {
+        for ((to, row), delivery) in recipients.iter().zip(rows).zip(deliveries) {
+            if let Some(class) = delivery.class {
+                let error = delivery.error.as_deref().unwrap_or(&delivery.code);
+                self.reject(template, to, row, class.as_str(), error);
+            }
+        }
+    }
+
+    // Returns the entries with the given ids, or every entry when `ids` is
+    // empty, oldest first.
+    fn entries(&self, ids: &[String]) -> io::Result<Vec<(PathBuf, Value)>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+            .filter(|p| ids.is_empty() || ids.iter().any(|id| Some(id.as_str()) == stem(p)))
+            .collect();
+        paths.sort();
+
+        let mut entries = Vec::new();
+        for path in paths {
+            let contents = fs::read_to_string(&path)?;
+            let entry = serde_json::from_str(&contents).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?;
+            entries.push((path, entry));
+        }
+
+        for id in ids {
+            if !entries.iter().any(|(p, _)| stem(p) == Some(id.as_str())) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no dead letter with id {}", id),
+                ));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // Prints one line per entry: id, template, recipient, class and error.
+    pub fn list(&self) -> io::Result<()> {
+        for (_, entry) in self.entries(&[])? {
+            println!(
+                "{}  {}  {}  {}  {}",
+                field(&entry, "id"),
+                field(&entry, "template"),
+                field(&entry, "to"),
+                field(&entry, "class"),
+                field(&entry, "error")
+            );
+        }
+        Ok(())
+    }
+
+    // Prints the input row of each entry to stdout, one per line, and
+    // removes the entries. The output can be piped into a sender.
+    pub fn retry(&self, ids: &[String]) -> io::Result<usize> {
+        let entries = self.entries(ids)?;
+        for (path, entry) in &entries {
+            println!("{}", field(entry, "row"));
+            fs::remove_file(path)?;
+        }
+        Ok(entries.len())
+    }
+
+    pub fn purge(&self, ids: &[String]) -> io::Result<usize> {
+        let entries = self.entries(ids)?;
+        for (path, _) in &entries {
+            fs::remove_file(path)?;
+        }
+        Ok(entries.len())
+    }
+}
+
+fn stem(path: &Path) -> Option<&str> {
+    path.file_stem().and_then(|s| s.to_str())
+}
+
+fn field<'a>(entry: &'a Value, name: &str) -> &'a str {
+    entry[name].as_str().unwrap_or("-")
+}
@@ -0,0 +1,117 @@
+use crate::lookups::Lookups;
+use aws_sdk_ses::types::VerificationStatus;
+use aws_sdk_ses::Client;
+use hickory_resolver::proto::rr::RData;
+use hickory_resolver::TokioResolver;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+async fn txt_records(resolver: &TokioResolver, name: &str) -> Result<Vec<String>, String> {
+    match resolver.txt_lookup(name).await {
+        Ok(lookup) => Ok(lookup
+            .answers()
+            .iter()
+            .filter_map(|r| match &r.data {
+                RData::TXT(txt) => Some(txt.to_string()),
+                _ => None,
+            })
+            .collect()),
+        Err(e) if e.is_no_records_found() => Ok(Vec::new()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Returns whether `domain` is an SES identity with DKIM enabled and
+// verified, as {"identity": bool, "dkim": bool}, from `lookups` when a copy
+// younger than `ttl` is there.
+async fn dkim_status(
+    client: &Client,
+    domain: &str,
+    lookups: Option<&mut Lookups>,
+    ttl: Duration,
+) -> Result<Value, String> {
+    let key = format!("dkim:{}", domain);
+    if let Some((status, _)) = lookups.as_ref().and_then(|l| l.get(&key, ttl)) {
+        return Ok(status.clone());
+    }
+
+    let output = client
+        .get_identity_dkim_attributes()
+        .identities(domain)
+        .send()
+        .await
+        .map_err(|e| aws_sdk_ses::Error::from(e).to_string())?;
+    let attrs = output.dkim_attributes().get(domain);
+    let status = json!({
+        "identity": attrs.is_some(),
+        "dkim": attrs.is_some_and(|a| a.dkim_enabled()
+            && a.dkim_verification_status() == &VerificationStatus::Success),
+    });
+    if let Some(lookups) = lookups {
+        lookups.put(&key, status.clone());
+    }
+    Ok(status)
+}
+
+// Looks up the SPF and DMARC records of the domain of `source` and its Easy
+// DKIM status in SES. Returns a description of every problem that would keep
+// mail sent through SES from aligning under DMARC.
+pub async fn check_alignment(
+    client: &Client,
+    source: &str,
+    lookups: Option<&mut Lookups>,
+    ttl: Duration,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let domain = match source.rsplit_once('@') {
+        Some((_, domain)) if !domain.is_empty() => domain.trim_end_matches('>').to_lowercase(),
+        _ => {
+            problems.push(format!("cannot determine domain of {}", source));
+            return problems;
+        }
+    };
+
+    let resolver = match TokioResolver::builder_tokio().and_then(|b| b.build()) {
+        Ok(resolver) => resolver,
+        Err(e) => {
+            problems.push(format!("failed to initialize DNS resolver: {}", e));
+            return problems;
+        }
+    };
+
+    match txt_records(&resolver, &format!("_dmarc.{}.", domain)).await {
+        Ok(records) => match records.iter().find(|r| r.starts_with("v=DMARC1")) {
+            Some(record) if record.contains("p=none") => {
+                problems.push(format!("DMARC policy for {} is p=none", domain))
+            }
+            Some(_) => {}
+            None => problems.push(format!("no DMARC record found for {}", domain)),
+        },
+        Err(e) => problems.push(format!("DMARC lookup for {} failed: {}", domain, e)),
+    }
+
+    match txt_records(&resolver, &format!("{}.", domain)).await {
+        Ok(records) if !records.iter().any(|r| r.starts_with("v=spf1")) => {
+            problems.push(format!("no SPF record found for {}", domain))
+        }
+        Ok(_) => {}
+        Err(e) => problems.push(format!("SPF lookup for {} failed: {}", domain, e)),
+    }
+
+    // Without a custom MAIL FROM domain, SPF aligns with amazonses.com rather
+    // than the from domain, so DKIM is what DMARC alignment relies on.
+    match dkim_status(client, &domain, lookups, ttl).await {
+        Ok(status) if status["dkim"] == true => {}
+        Ok(status) if status["identity"] == true => {
+            problems.push(format!("DKIM is not enabled and verified for {}", domain))
+        }
+        Ok(_) => problems.push(format!("{} is not a verified SES identity", domain)),
+        Err(e) => problems.push(format!(
+            "failed to fetch DKIM attributes for {}: {}",
+            domain, e
+        )),
+    }
+
+    problems
+}
@@ -0,0 +1,106 @@
+use aws_sdk_ses::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_ses::types::BulkEmailStatus;
+use aws_sdk_sesv2::types as v2;
+use std::fmt;
+
+// What a failure means for the rows it affected: whether sending them again
+// can succeed, and who has to act if it can't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    // Temporary condition; the same request may succeed later.
+    Retryable,
+    // The recipient or message was rejected and will be rejected again.
+    Permanent,
+    // Account or deployment misconfiguration that needs an operator.
+    Config,
+    // The account's sending quota or rate has been exhausted.
+    Quota,
+}
+
+impl ErrorClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::Retryable => "retryable",
+            ErrorClass::Permanent => "permanent",
+            ErrorClass::Config => "config",
+            ErrorClass::Quota => "quota",
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn classify_code(code: &str, message: &str) -> ErrorClass {
+    match code {
+        "Throttling" if message.contains("quota") => ErrorClass::Quota,
+        "LimitExceededException" => ErrorClass::Quota,
+        "Throttling"
+        | "TooManyRequestsException"
+        | "InternalFailure"
+        | "ServiceUnavailable"
+        | "RequestExpired" => ErrorClass::Retryable,
+        "MessageRejected" | "InvalidParameterValue" | "BadRequestException" => {
+            ErrorClass::Permanent
+        }
+        _ => ErrorClass::Config,
+    }
+}
+
+// Whether an error code means the account is sending too fast. Gmail's are
+// the reasons of its 403 responses.
+pub fn is_throttling(code: &str) -> bool {
+    matches!(
+        code,
+        "Throttling" | "TooManyRequestsException" | "rateLimitExceeded" | "userRateLimitExceeded"
+    )
+}
+
+// Classifies an error returned for a whole SES request.
+pub fn classify_error<E: ProvideErrorMetadata, R>(err: &SdkError<E, R>) -> ErrorClass {
+    match err {
+        SdkError::ServiceError(e) => classify_code(
+            e.err().code().unwrap_or_default(),
+            e.err().message().unwrap_or_default(),
+        ),
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            ErrorClass::Retryable
+        }
+        _ => ErrorClass::Config,
+    }
+}
+
+// Classifies the status SES reported for a single destination of a bulk
+// request. Returns None for destinations that were accepted.
+pub fn classify_status(status: &BulkEmailStatus) -> Option<ErrorClass> {
+    match status {
+        BulkEmailStatus::Success => None,
+        BulkEmailStatus::AccountDailyQuotaExceeded => Some(ErrorClass::Quota),
+        BulkEmailStatus::AccountThrottled | BulkEmailStatus::TransientFailure => {
+            Some(ErrorClass::Retryable)
+        }
+        BulkEmailStatus::MessageRejected
+        | BulkEmailStatus::InvalidParameterValue
+        | BulkEmailStatus::Failed => Some(ErrorClass::Permanent),
+        _ => Some(ErrorClass::Config),
+    }
+}
+
+// Classifies the status SESv2 reported for a single entry of a bulk request.
+// Returns None for entries that were accepted.
+pub fn classify_v2_status(status: &v2::BulkEmailStatus) -> Option<ErrorClass> {
+    match status {
+        v2::BulkEmailStatus::Success => None,
+        v2::BulkEmailStatus::AccountDailyQuotaExceeded => Some(ErrorClass::Quota),
+        v2::BulkEmailStatus::AccountThrottled | v2::BulkEmailStatus::TransientFailure => {
+            Some(ErrorClass::Retryable)
+        }
+        v2::BulkEmailStatus::MessageRejected
+        | v2::BulkEmailStatus::InvalidParameter
+        | v2::BulkEmailStatus::Failed => Some(ErrorClass::Permanent),
+        _ => Some(ErrorClass::Config),
+    }
+}
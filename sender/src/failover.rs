@@ -0,0 +1,286 @@
+use crate::mailer::{Mailer, Probing, Request, Sending};
+use crate::metrics::Metrics;
+use crate::webhook::Webhook;
+use chrono::{DateTime, Utc};
+use mailroom_core::clock::Clock;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Sends through the primary provider, or the failover one while probes find
+// the primary unhealthy. Traffic only shifts after `fail_after` failed probes
+// in a row, and back after `failback_after` healthy ones, so that a flapping
+// provider doesn't move it on every probe.
+pub struct Failover {
+    primary: (String, Box<dyn Mailer>),
+    secondary: (String, Box<dyn Mailer>),
+    fail_after: u32,
+    failback_after: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    failed_over: bool,
+    // Probes in a row that disagree with where traffic goes.
+    streak: u32,
+}
+
+// A shift of traffic from one provider to the other.
+pub struct Switch {
+    pub from: String,
+    pub to: String,
+    pub failed_over: bool,
+    // Why the primary provider was found unhealthy, when it was.
+    pub error: Option<String>,
+}
+
+impl Failover {
+    pub fn new(
+        primary: (String, Box<dyn Mailer>),
+        secondary: (String, Box<dyn Mailer>),
+        fail_after: u32,
+        failback_after: u32,
+    ) -> Self {
+        Failover {
+            primary,
+            secondary,
+            fail_after,
+            failback_after,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn active(&self) -> &dyn Mailer {
+        if self.state.lock().unwrap().failed_over {
+            &*self.secondary.1
+        } else {
+            &*self.primary.1
+        }
+    }
+
+    // Probes the primary provider and shifts traffic once enough probes in a
+    // row disagree with where it goes. Traffic isn't shifted to a failover
+    // provider that fails its own probe.
+    pub async fn check(&self) -> Option<Switch> {
+        let result = self.primary.1.probe().await;
+        if let Err(e) = &result {
+            log!("WARN: probing {} failed: {}", self.primary.0, e);
+        }
+
+        let (failed_over, due) = {
+            let mut state = self.state.lock().unwrap();
+            if result.is_ok() == state.failed_over {
+                state.streak += 1;
+            } else {
+                state.streak = 0;
+            }
+            let needed = if state.failed_over {
+                self.failback_after
+            } else {
+                self.fail_after
+            };
+            (state.failed_over, state.streak >= needed)
+        };
+        if !due {
+            return None;
+        }
+
+        if !failed_over {
+            if let Err(e) = self.secondary.1.probe().await {
+                log!(
+                    "WARN: not failing over to {}, probing it failed: {}",
+                    self.secondary.0,
+                    e
+                );
+                return None;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.failed_over = !failed_over;
+        state.streak = 0;
+        let (from, to) = if state.failed_over {
+            (&self.primary.0, &self.secondary.0)
+        } else {
+            (&self.secondary.0, &self.primary.0)
+        };
+        Some(Switch {
+            from: from.clone(),
+            to: to.clone(),
+            failed_over: state.failed_over,
+            error: result.err(),
+        })
+    }
+}
+
+impl Mailer for Failover {
+    fn name(&self) -> &'static str {
+        self.active().name()
+    }
+
+    fn send<'a>(&'a self, request: Request<'a>) -> Sending<'a> {
+        self.active().send(request)
+    }
+
+    fn probe(&self) -> Probing<'_> {
+        self.active().probe()
+    }
+}
+
+// Probes the primary provider every `interval` for as long as the sender
+// runs, logging, counting and alerting on every shift of traffic.
+pub async fn watch(
+    failover: Arc<Failover>,
+    interval: Duration,
+    metrics: Arc<Mutex<Metrics>>,
+    alerts: Option<Webhook>,
+    clock: Arc<dyn Clock>,
+) {
+    metrics.lock().unwrap().failover(None, false);
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let Some(switch) = failover.check().await else {
+            continue;
+        };
+        match &switch.error {
+            Some(e) => log!(
+                "WARN: {} is unhealthy ({}); failing over to {}",
+                switch.from,
+                e,
+                switch.to
+            ),
+            None => log!(
+                "WARN: {} is healthy again; failing back from {}",
+                switch.to,
+                switch.from
+            ),
+        }
+        metrics
+            .lock()
+            .unwrap()
+            .failover(Some(&switch.to), switch.failed_over);
+        if let Some(alerts) = &alerts {
+            let alert = serde_json::json!({
+                "type": "failover",
+                "from": switch.from,
+                "to": switch.to,
+                "error": switch.error,
+                "timestamp": DateTime::<Utc>::from(clock.system()).to_rfc3339(),
+            });
+            if let Err(e) = alerts.post(&alert).await {
+                log!("ERROR: failed to post alert: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::Response;
+
+    // A provider whose probes succeed or fail as told.
+    struct Fake {
+        name: &'static str,
+        healthy: Arc<Mutex<bool>>,
+    }
+
+    impl Mailer for Fake {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn send<'a>(&'a self, _: Request<'a>) -> Sending<'a> {
+            Box::pin(async {
+                Response {
+                    deliveries: Vec::new(),
+                    failure: None,
+                    debug: String::new(),
+                }
+            })
+        }
+
+        fn probe(&self) -> Probing<'_> {
+            let healthy = *self.healthy.lock().unwrap();
+            Box::pin(async move {
+                if healthy {
+                    Ok(())
+                } else {
+                    Err("unreachable".to_string())
+                }
+            })
+        }
+    }
+
+    fn fake(name: &'static str) -> (Box<dyn Mailer>, Arc<Mutex<bool>>) {
+        let healthy = Arc::new(Mutex::new(true));
+        let fake = Fake {
+            name,
+            healthy: healthy.clone(),
+        };
+        (Box::new(fake), healthy)
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_back_after_enough_probes_in_a_row() {
+        let (primary, primary_healthy) = fake("ses");
+        let (secondary, secondary_healthy) = fake("postmark");
+        let failover = Failover::new(
+            ("ses".to_string(), primary),
+            ("postmark".to_string(), secondary),
+            2,
+            3,
+        );
+        let set = |healthy: &Arc<Mutex<bool>>, value| *healthy.lock().unwrap() = value;
+
+        // A failed probe between healthy ones doesn't shift traffic.
+        set(&primary_healthy, false);
+        assert!(failover.check().await.is_none());
+        set(&primary_healthy, true);
+        assert!(failover.check().await.is_none());
+        set(&primary_healthy, false);
+        assert!(failover.check().await.is_none());
+        assert_eq!(failover.name(), "ses");
+
+        // Nor does failing over to a provider that fails its own probe.
+        set(&secondary_healthy, false);
+        assert!(failover.check().await.is_none());
+        assert_eq!(failover.name(), "ses");
+
+        set(&secondary_healthy, true);
+        let switch = failover.check().await.unwrap();
+        assert_eq!(
+            (switch.from.as_str(), switch.to.as_str()),
+            ("ses", "postmark")
+        );
+        assert_eq!(switch.error.as_deref(), Some("unreachable"));
+        assert!(switch.failed_over);
+        assert_eq!(failover.name(), "postmark");
+
+        set(&primary_healthy, true);
+        assert!(failover.check().await.is_none());
+        assert!(failover.check().await.is_none());
+        let switch = failover.check().await.unwrap();
+        assert_eq!(
+            (switch.from.as_str(), switch.to.as_str()),
+            ("postmark", "ses")
+        );
+        assert!(switch.error.is_none());
+        assert!(!switch.failed_over);
+        assert_eq!(failover.name(), "ses");
+    }
+
+    #[test]
+    fn counts_failovers_in_the_metrics() {
+        let mut metrics = Metrics::default();
+        assert!(!metrics.to_text().contains("mailroom_failed_over"));
+
+        metrics.failover(None, false);
+        metrics.failover(Some("postmark"), true);
+        let text = metrics.to_text();
+        assert!(text.contains("mailroom_failovers_total{to=\"postmark\"} 1\n"));
+        assert!(text.contains("mailroom_failed_over 1\n"));
+    }
+}
@@ -0,0 +1,68 @@
+use mailroom_core::clock::Clock;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The sender's backlog as it stands, for autoscalers: what has been read
+// but not processed yet, and whether SES is holding sends back.
+pub struct Gauges {
+    // When each complete line waiting to be processed was read, oldest
+    // first.
+    lines: VecDeque<Instant>,
+    // Bytes of the line being read, which isn't complete yet.
+    partial: usize,
+    throttled_until: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Gauges {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Gauges {
+            lines: VecDeque::new(),
+            partial: 0,
+            throttled_until: None,
+            clock,
+        }
+    }
+
+    // Records a chunk of input that was just read.
+    pub fn read(&mut self, chunk: &[u8]) {
+        let now = self.clock.now();
+        for &byte in chunk {
+            if byte == b'\n' {
+                self.lines.push_back(now);
+                self.partial = 0;
+            } else {
+                self.partial += 1;
+            }
+        }
+    }
+
+    // Records that the oldest waiting line was processed.
+    pub fn processed(&mut self) {
+        self.lines.pop_front();
+    }
+
+    // Records that sends are paused for `pause` after throttling.
+    pub fn throttled(&mut self, pause: Duration) {
+        self.throttled_until = Some(self.clock.now() + pause);
+    }
+
+    pub fn to_json(&self) -> Value {
+        let now = self.clock.now();
+        let throttled_for = self
+            .throttled_until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(now));
+        json!({
+            "queue_depth": self.lines.len(),
+            "oldest_line_age_ms": self
+                .lines
+                .front()
+                .map_or(0, |read| now.saturating_duration_since(*read).as_millis() as u64),
+            "partial_line_bytes": self.partial,
+            "throttled": !throttled_for.is_zero(),
+            "throttled_for_ms": throttled_for.as_millis() as u64,
+        })
+    }
+}
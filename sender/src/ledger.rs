@@ -0,0 +1,255 @@
+use crate::delivery::Delivery;
+use chrono::{DateTime, SecondsFormat, Utc};
+use mailroom_core::clock::Clock;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// The final result of every destination sent, one JSON line each, in a
+// file per UTC day, so that old days can be deleted and an export only
+// reads the days it covers. Kept for analytics; nothing reads it back
+// while sending.
+pub struct Ledger {
+    dir: PathBuf,
+    provider: String,
+    clock: Arc<dyn Clock>,
+}
+
+// One destination of a batch, as recorded.
+pub struct Entry<'a> {
+    pub action: &'a str,
+    pub template: &'a str,
+    pub recipient: &'a str,
+    pub redirected_to: Option<&'a str>,
+    // Variant of the template the recipient was assigned, if the action
+    // has variants.
+    pub variant: Option<&'a str>,
+    // Provider the destination was sent through, if not that of the ledger
+    // because its action splits recipients between transports.
+    pub provider: Option<&'a str>,
+    pub delivery: &'a Delivery,
+    pub first_attempt_at: SystemTime,
+    pub attempts: u32,
+}
+
+// Columns of an export, in order.
+const COLUMNS: [&str; 13] = [
+    "sent_at",
+    "first_attempt_at",
+    "action",
+    "template",
+    "variant",
+    "provider",
+    "recipient",
+    "redirected_to",
+    "outcome",
+    "status",
+    "class",
+    "message_id",
+    "attempts",
+];
+
+// The same columns as a Parquet schema. The columns without a value for
+// every entry are optional.
+const SCHEMA: &str = "
+    message ledger {
+        required int64 sent_at (TIMESTAMP(MILLIS, true));
+        required int64 first_attempt_at (TIMESTAMP(MILLIS, true));
+        required binary action (UTF8);
+        required binary template (UTF8);
+        optional binary variant (UTF8);
+        required binary provider (UTF8);
+        required binary recipient (UTF8);
+        optional binary redirected_to (UTF8);
+        required binary outcome (UTF8);
+        required binary status (UTF8);
+        optional binary class (UTF8);
+        optional binary message_id (UTF8);
+        required int32 attempts;
+    }
+";
+
+fn timestamp(at: SystemTime) -> String {
+    DateTime::<Utc>::from(at).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+impl Ledger {
+    pub fn new(dir: &str, provider: &str, clock: Arc<dyn Clock>) -> Self {
+        Ledger {
+            dir: PathBuf::from(dir),
+            provider: provider.to_string(),
+            clock,
+        }
+    }
+
+    // Appends the results of a batch. Opened for every batch, so that the
+    // files can be moved away at any time.
+    pub fn record(&self, entries: &[Entry]) -> io::Result<()> {
+        let now = self.clock.system();
+        let mut lines = String::new();
+        for entry in entries {
+            let delivery = entry.delivery;
+            let line = json!({
+                "sent_at": timestamp(now),
+                "first_attempt_at": timestamp(entry.first_attempt_at),
+                "action": entry.action,
+                "template": entry.template,
+                "variant": entry.variant,
+                "provider": entry.provider.unwrap_or(&self.provider),
+                "recipient": entry.recipient,
+                "redirected_to": entry.redirected_to,
+                "outcome": delivery.outcome.as_str(),
+                "status": delivery.code,
+                "class": delivery.class.map(|c| c.as_str()),
+                "message_id": delivery.message_id,
+                "attempts": entry.attempts,
+            });
+            lines.push_str(&line.to_string());
+            lines.push('\n');
+        }
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!(
+            "{}.jsonl",
+            DateTime::<Utc>::from(now).format("%Y-%m-%d")
+        ));
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?
+            .write_all(lines.as_bytes())
+    }
+
+    // Reads the entries sent at or after `since`, oldest day first.
+    fn read(&self, since: DateTime<Utc>) -> io::Result<Vec<Value>> {
+        let first_day = since.format("%Y-%m-%d.jsonl").to_string();
+        let mut files: Vec<String> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.ends_with(".jsonl") && *name >= first_day)
+            .collect();
+        files.sort();
+
+        let mut entries = Vec::new();
+        for name in files {
+            for line in fs::read_to_string(self.dir.join(&name))?.lines() {
+                let Ok(entry) = serde_json::from_str::<Value>(line) else {
+                    log!("WARN: skipping malformed ledger entry in {}", name);
+                    continue;
+                };
+                let sent_at = entry["sent_at"]
+                    .as_str()
+                    .and_then(|s| DateTime::parse_from_rfc3339(s).ok());
+                if sent_at.is_some_and(|at| at >= since) {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    // Writes the entries sent at or after `since` to `out`, as "csv" with a
+    // header line or as "parquet". Returns the number of entries.
+    pub fn export(
+        &self,
+        since: DateTime<Utc>,
+        format: &str,
+        out: impl Write + Send,
+    ) -> Result<usize, String> {
+        let entries = self
+            .read(since)
+            .map_err(|e| format!("{}: {}", self.dir.display(), e))?;
+        match format {
+            "csv" => write_csv(&entries, out).map_err(|e| e.to_string())?,
+            "parquet" => write_parquet(&entries, out).map_err(|e| e.to_string())?,
+            _ => return Err(format!("unknown format {:?}", format)),
+        }
+        Ok(entries.len())
+    }
+}
+
+// A field of a CSV record, quoted when it holds a separator, quote or line
+// break.
+fn csv_field(value: &Value) -> String {
+    let s = match value {
+        Value::Null => return String::new(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+fn write_csv(entries: &[Value], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "{}", COLUMNS.join(","))?;
+    for entry in entries {
+        let fields: Vec<String> = COLUMNS.iter().map(|c| csv_field(&entry[*c])).collect();
+        writeln!(out, "{}", fields.join(","))?;
+    }
+    out.flush()
+}
+
+fn write_parquet(entries: &[Value], out: impl Write + Send) -> parquet::errors::Result<()> {
+    let schema = Arc::new(parse_message_type(SCHEMA)?);
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(out, schema, properties)?;
+
+    let mut row_group = writer.next_row_group()?;
+    for name in COLUMNS {
+        let Some(mut column) = row_group.next_column()? else {
+            break;
+        };
+        let values: Vec<&Value> = entries.iter().map(|e| &e[name]).collect();
+        // Null values of optional columns are left out, with a definition
+        // level of 0.
+        let levels: Vec<i16> = values.iter().map(|v| !v.is_null() as i16).collect();
+        let levels = matches!(name, "variant" | "redirected_to" | "class" | "message_id")
+            .then_some(&levels[..]);
+        match name {
+            "sent_at" | "first_attempt_at" => {
+                let millis: Vec<i64> = values
+                    .iter()
+                    .map(|v| {
+                        v.as_str()
+                            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+                            .map_or(0, |at| at.timestamp_millis())
+                    })
+                    .collect();
+                column
+                    .typed::<parquet::data_type::Int64Type>()
+                    .write_batch(&millis, None, None)?;
+            }
+            "attempts" => {
+                let attempts: Vec<i32> = values
+                    .iter()
+                    .map(|v| v.as_i64().unwrap_or_default() as i32)
+                    .collect();
+                column
+                    .typed::<parquet::data_type::Int32Type>()
+                    .write_batch(&attempts, None, None)?;
+            }
+            _ => {
+                let strings: Vec<ByteArray> = values
+                    .iter()
+                    .filter(|v| !v.is_null())
+                    .map(|v| ByteArray::from(v.as_str().unwrap_or_default()))
+                    .collect();
+                column
+                    .typed::<parquet::data_type::ByteArrayType>()
+                    .write_batch(&strings, levels, None)?;
+            }
+        }
+        column.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
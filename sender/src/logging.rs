@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use mailroom_core::clock::Clock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::SystemTime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Level> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    // Log lines carry their level as a prefix of the message; lines without
+    // one are informational.
+    fn of(message: &str) -> Level {
+        if message.starts_with("ERROR:") {
+            Level::Error
+        } else if message.starts_with("WARN:") {
+            Level::Warn
+        } else if message.starts_with("DEBUG:") {
+            Level::Debug
+        } else {
+            Level::Info
+        }
+    }
+}
+
+// A RUST_LOG-style filter: a default level followed by per-target
+// overrides, e.g. "warn,templates=debug". Targets are module names, with
+// "sender" for the main module.
+pub struct Filter {
+    default: Level,
+    targets: Vec<(String, Level)>,
+}
+
+impl Filter {
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter {
+            default: Level::Info,
+            targets: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let level = match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = Level::parse(level)
+                        .ok_or_else(|| format!("unknown log level {:?}", level))?;
+                    filter.targets.push((target.to_string(), level));
+                    continue;
+                }
+                None => directive,
+            };
+            filter.default =
+                Level::parse(level).ok_or_else(|| format!("unknown log level {:?}", level))?;
+        }
+        Ok(filter)
+    }
+
+    fn level(&self, target: &str) -> Level {
+        self.targets
+            .iter()
+            .rev()
+            .find(|(t, _)| t == target)
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    default: Level::Info,
+    targets: Vec::new(),
+});
+
+// Set while debug logging has been switched on at runtime.
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+// The clock log lines are stamped from, once set.
+static CLOCK: OnceLock<Arc<dyn Clock>> = OnceLock::new();
+
+pub fn init(filter: Filter) {
+    *FILTER.write().unwrap() = filter;
+}
+
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    let _ = CLOCK.set(clock);
+}
+
+// The time to stamp a log line with, from the clock given to `set_clock` or
+// the system's before it was.
+pub fn timestamp() -> String {
+    let now = CLOCK
+        .get()
+        .map_or_else(SystemTime::now, |clock| clock.system());
+    DateTime::<Utc>::from(now)
+        .format("%Y/%m/%d %H:%M:%S")
+        .to_string()
+}
+
+// Switches debug logging for every target on or off and returns whether it
+// is now on.
+pub fn toggle_debug() -> bool {
+    !DEBUG.fetch_xor(true, Ordering::Relaxed)
+}
+
+pub fn enabled(module: &str, message: &str) -> bool {
+    if DEBUG.load(Ordering::Relaxed) {
+        return true;
+    }
+    let target = module.rsplit("::").next().unwrap_or(module);
+    Level::of(message) <= FILTER.read().unwrap().level(target)
+}
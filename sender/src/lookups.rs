@@ -0,0 +1,71 @@
+use mailroom_core::clock::Clock;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+// SES responses that rarely change, kept on disk so that a sender restarted
+// over and over, by a crash loop or a deploy, doesn't fetch them every time
+// and trip the SES API throttles. The file is a JSON object of keys such as
+// "template:<name>" to the response and the Unix time it was fetched at.
+pub struct Lookups {
+    path: PathBuf,
+    entries: Map<String, Value>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Lookups {
+    // Starts empty when the file is missing or unreadable, since everything
+    // in it can be fetched again.
+    pub fn open(path: PathBuf, clock: Arc<dyn Clock>) -> Self {
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                log!("WARN: ignoring {}: {}", path.display(), e);
+                Map::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Map::new(),
+            Err(e) => {
+                log!("WARN: ignoring {}: {}", path.display(), e);
+                Map::new()
+            }
+        };
+        Lookups {
+            path,
+            entries,
+            clock,
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock
+            .system()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    }
+
+    // The response stored under `key` and how old it is, if it is younger
+    // than `ttl`.
+    pub fn get(&self, key: &str, ttl: Duration) -> Option<(&Value, Duration)> {
+        let entry = self.entries.get(key)?;
+        let age = Duration::from_secs(
+            self.now()
+                .saturating_sub(entry["fetched_at"].as_u64().unwrap_or(0)),
+        );
+        (age < ttl).then_some((&entry["response"], age))
+    }
+
+    // Stores a response that was just fetched.
+    pub fn put(&mut self, key: &str, response: Value) {
+        let entry = json!({ "fetched_at": self.now(), "response": response });
+        self.entries.insert(key.to_string(), entry);
+
+        let tmp = self.path.with_extension("tmp");
+        if let Err(e) = fs::write(&tmp, Value::Object(self.entries.clone()).to_string())
+            .and_then(|()| fs::rename(&tmp, &self.path))
+        {
+            log!("WARN: failed to write {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -1,308 +1,2613 @@
 use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_ses::types::{BulkEmailDestination, Destination};
+use aws_sdk_ses::config::{IdentityCache, ProvideCredentials, SharedCredentialsProvider};
 use aws_sdk_ses::{Client, Error};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use mailroom_core::clock::{self, Clock};
+use mailroom_core::{registry, Parser, MAX_ACTIONS, MAX_FIELDS};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
-use std::io::{self, Read};
+use std::io::{self, Write};
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
-use std::process;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{signal, SignalKind};
 
-const MAX_ACTIONS: usize = 2;
-const MAX_FIELDS: usize = 4;
-const MAX_ROWS: usize = 10;
-const MAX_FIELD_LEN: usize = 254;
+// SES limits on the template data of one destination, and on the number of
+// destinations and their combined template data in one bulk request.
+const MAX_TEMPLATE_DATA_LEN: usize = 262144;
+const MAX_DESTINATIONS: usize = 50;
+const MAX_REQUEST_DATA_LEN: usize = 10 * 1024 * 1024;
+
+const HANDOFF_FILE: &str = "handoff.journal";
+const SEEN_FILE: &str = "seen.journal";
+const RECEIPTS_FILE: &str = "receipts.journal";
+const BUDGET_FILE: &str = "budget.journal";
+const LOOKUPS_FILE: &str = "lookups.cache";
 
 macro_rules! log {
     ($($arg:tt)*) => {{
-        let timestamp = Utc::now().format("%Y/%m/%d %H:%M:%S");
-        eprintln!("{} [SES] {}", timestamp, format_args!($($arg)*));
+        let message = format!($($arg)*);
+        if $crate::logging::enabled(module_path!(), &message) {
+            eprintln!("{} [SES] {}", $crate::logging::timestamp(), message);
+        }
     }};
 }
 
-struct Parser {
-    cnt: [usize; MAX_ACTIONS],
-    nb: [[[usize; MAX_FIELDS]; MAX_ROWS]; MAX_ACTIONS],
-    b: [[[[u8; MAX_FIELD_LEN]; MAX_FIELDS]; MAX_ROWS]; MAX_ACTIONS],
-    i: usize,
-    fidx: usize,
-    fsz: usize,
+mod admin;
+mod allowlist;
+mod backoff;
+mod budget;
+mod canary;
+mod config;
+mod credentials;
+mod crypto;
+mod dedup;
+mod delivery;
+mod dlq;
+mod domain;
+mod errors;
+mod failover;
+mod gauges;
+mod ledger;
+mod logging;
+mod lookups;
+mod mailer;
+mod metrics;
+mod outbox;
+mod pools;
+mod rate;
+mod samples;
+mod scheduler;
+mod secrets;
+mod source;
+mod stats;
+mod status;
+mod suppression;
+mod templates;
+mod volume;
+mod webhook;
+
+use backoff::Backoff;
+use budget::Budget;
+use config::{Config, Layers};
+use dedup::Seen;
+use delivery::Delivery;
+use dlq::DeadLetters;
+use errors::ErrorClass;
+use failover::Failover;
+use gauges::Gauges;
+use ledger::Ledger;
+use lookups::Lookups;
+use mailer::Mailer;
+use metrics::Metrics;
+use rate::RateLimiter;
+use samples::Samples;
+use scheduler::Scheduler;
+use secrets::{Secret, Secrets};
+use source::Source;
+use stats::Stats;
+use status::Exit;
+use suppression::Suppressions;
+use templates::TemplateCache;
+use volume::Volume;
+use webhook::Webhook;
+
+// Everything a batch needs to be sent, built once at startup.
+struct Context {
+    clock: Arc<dyn Clock>,
+    client: Client,
+    mailer: Box<dyn Mailer>,
+    // Mailers of the other transports the actions split their recipients
+    // between.
+    mailers: HashMap<String, Box<dyn Mailer>>,
+    config: Config,
+    templates: TemplateCache,
+    webhook: Option<Webhook>,
+    backoff: Backoff,
+    // Spaces out sends with MAILROOM_SES_MAX_SEND_RATE.
+    limiter: Option<RateLimiter>,
+    dead_letters: DeadLetters,
+    stats: Arc<Mutex<Stats>>,
+    alerts: Option<Webhook>,
+    field_key: Option<Secret>,
+    seen: Option<Seen>,
+    // Rows SES accepted, with their message ids.
+    receipts: Option<Seen>,
+    volume: Volume,
+    // Actions whose rows are diverted to the dead-letter directory, shared
+    // with the admin endpoint.
+    paused: Arc<Mutex<[bool; MAX_ACTIONS]>>,
+    // Address every email is sent to instead of its recipient, shared with
+    // the admin endpoint.
+    redirect: Arc<Mutex<Option<String>>>,
+    samples: Option<Samples>,
+    budget: Option<Budget>,
+    ledger: Option<Ledger>,
+    // Recipients rows are not sent to.
+    suppressions: Suppressions,
+    // Input backlog and throttling, shared with the admin endpoint.
+    gauges: Arc<Mutex<Gauges>>,
+    // Counters served by the admin endpoint for Prometheus.
+    metrics: Arc<Mutex<Metrics>>,
+    // Destinations waiting to be sent again after failing transiently.
+    retries: Scheduler<Retry>,
 }
 
-impl Parser {
-    fn new() -> Self {
-        Parser {
-            cnt: [0; MAX_ACTIONS],
-            nb: [[[0; MAX_FIELDS]; MAX_ROWS]; MAX_ACTIONS],
-            b: [[[[0; MAX_FIELD_LEN]; MAX_FIELDS]; MAX_ROWS]; MAX_ACTIONS],
-            i: 0,
-            fidx: 0,
-            fsz: 0,
+impl Context {
+    // The mailer `batch` is sent through, that of MAILROOM_TRANSPORT unless
+    // its recipients were assigned another transport.
+    fn mailer_of(&self, batch: &Batch) -> &dyn Mailer {
+        match batch.transport.as_ref().and_then(|t| self.mailers.get(t)) {
+            Some(mailer) => &**mailer,
+            None => &*self.mailer,
         }
     }
+}
+
+// What happened to the rows of one input line, reported once the line has
+// been processed.
+#[derive(Default)]
+struct Summary {
+    rows: usize,
+    sent: [usize; MAX_ACTIONS],
+    failed: [usize; MAX_ACTIONS],
+    // Rows of paused actions, written to the dead-letter directory.
+    diverted: [usize; MAX_ACTIONS],
+    // Rows for recipients outside the allowlist, which are not sent.
+    filtered: [usize; MAX_ACTIONS],
+    // Rows whose deadline passed before they could be sent.
+    expired: [usize; MAX_ACTIONS],
+    // Rows for invalid or suppressed recipients, which are not sent.
+    suppressed: [usize; MAX_ACTIONS],
+    // Destinations sent again after a transient failure, once per attempt.
+    retried: usize,
+    throttled: usize,
+}
+
+impl Summary {
+    // Whether every row counted went out.
+    fn complete(&self) -> bool {
+        self.failed.iter().sum::<usize>() + self.diverted.iter().sum::<usize>() == 0
+    }
 
-    fn consume(&mut self, c: u8) -> Result<bool, String> {
-        if c == b',' || c == b'\n' {
-            if self.fidx > 0 {
-                self.nb[self.i][self.cnt[self.i]][self.fidx - 1] = self.fsz;
+    // Writes the summary as a JSON record to stdout and as a log line.
+    fn emit(&self, clock: &dyn Clock, duration: Duration) {
+        let per_template = |counts: &[usize; MAX_ACTIONS]| {
+            registry()
+                .iter()
+                .zip(counts)
+                .map(|(a, n)| (a.template.clone(), Value::from(*n)))
+                .collect::<serde_json::Map<_, _>>()
+        };
+        let record = serde_json::json!({
+            "type": "batch_summary",
+            "timestamp": DateTime::<Utc>::from(clock.system()).to_rfc3339(),
+            "rows": self.rows,
+            "sent": per_template(&self.sent),
+            "failed": per_template(&self.failed),
+            "diverted": per_template(&self.diverted),
+            "filtered": per_template(&self.filtered),
+            "expired": per_template(&self.expired),
+            "suppressed": per_template(&self.suppressed),
+            "retried": self.retried,
+            "throttled": self.throttled,
+            "duration_ms": duration.as_millis() as u64,
+        });
+        println!("{}", record);
+
+        log!(
+            "batch; rows={} sent={} failed={} diverted={} filtered={} expired={} suppressed={} retried={} throttled={} duration={:.2}s",
+            self.rows,
+            self.sent.iter().sum::<usize>(),
+            self.failed.iter().sum::<usize>(),
+            self.diverted.iter().sum::<usize>(),
+            self.filtered.iter().sum::<usize>(),
+            self.expired.iter().sum::<usize>(),
+            self.suppressed.iter().sum::<usize>(),
+            self.retried,
+            self.throttled,
+            duration.as_secs_f64()
+        );
+    }
+}
+
+fn post_results(ctx: &Context, payload: Value) {
+    if let Some(webhook) = &ctx.webhook {
+        let webhook = webhook.clone();
+        tokio::spawn(async move {
+            if let Err(e) = webhook.post(&payload).await {
+                log!("ERROR: failed to post results to webhook: {}", e);
             }
+        });
+    }
+}
 
-            self.fidx += 1;
-            self.fsz = 0;
+fn post_alert(ctx: &Context, alert: Value) {
+    if let Some(alerts) = &ctx.alerts {
+        let alerts = alerts.clone();
+        tokio::spawn(async move {
+            if let Err(e) = alerts.post(&alert).await {
+                log!("ERROR: failed to post alert: {}", e);
+            }
+        });
+    }
+}
 
-            if self.fidx == 5 {
-                self.cnt[self.i] += 1;
-                self.fidx = 0;
+// Resolves the value of a setting that may be a secret reference. Failing to
+// is fatal.
+async fn resolve(secrets: &mut Secrets, name: &str, value: &Option<String>) -> Option<Secret> {
+    match value {
+        Some(value) => match secrets.resolve(value).await {
+            Ok(secret) => Some(secret),
+            Err(e) => {
+                log!("ERROR: failed to resolve {}: {}", name, e);
+                status::exit(Exit::Credentials);
             }
+        },
+        None => None,
+    }
+}
 
-            if c == b'\n' {
-                return Ok(true);
+// The mailer of a transport, `config.transport` or one traffic is shifted
+// to.
+async fn build_mailer(
+    transport: &str,
+    config: &Config,
+    secrets: &mut Secrets,
+    sdk_config: &aws_config::SdkConfig,
+    ses_config: &aws_config::SdkConfig,
+) -> Box<dyn Mailer> {
+    match (transport, config.ses_api.as_str()) {
+        ("smtp", _) => {
+            let password = resolve(secrets, "MAILROOM_SMTP_SECRET", &config.smtp_secret).await;
+            let pool = mailer::SmtpPool {
+                size: config.smtp_pool_size,
+                max_messages: config.smtp_max_messages,
+                idle_timeout: Duration::from_millis(config.smtp_idle_timeout_ms),
+            };
+            match mailer::Smtp::new(
+                config.smtp_url.as_deref().unwrap_or_default(),
+                &config.smtp_tls(),
+                config.smtp_username.clone().zip(password),
+                config.template_dir.clone().unwrap_or_default().into(),
+                pool,
+            ) {
+                Ok(smtp) => Box::new(smtp),
+                Err(e) => {
+                    log!("ERROR: MAILROOM_SMTP_URL: {}", e);
+                    status::exit(Exit::Config);
+                }
             }
-        } else {
-            if self.fidx == 0 {
-                match c {
-                    b'1' => self.i = 0,
-                    b'2' => self.i = 1,
-                    _ => return Err(format!("unknown identifier '{}'", c as char)),
+        }
+        ("graph", _) => {
+            let secret = resolve(
+                secrets,
+                "MAILROOM_GRAPH_CLIENT_SECRET",
+                &config.graph_client_secret,
+            )
+            .await;
+            Box::new(mailer::Graph::new(
+                config.graph_tenant_id.clone().unwrap_or_default(),
+                config.graph_client_id.clone().unwrap_or_default(),
+                secret.unwrap_or_else(|| Secret::new(String::new())),
+                config.template_dir.clone().unwrap_or_default().into(),
+            ))
+        }
+        ("gmail", _) => {
+            let key = resolve(
+                secrets,
+                "MAILROOM_GMAIL_SERVICE_ACCOUNT_KEY",
+                &config.gmail_service_account_key,
+            )
+            .await;
+            match mailer::Gmail::new(
+                key.unwrap_or_else(|| Secret::new(String::new())),
+                config.template_dir.clone().unwrap_or_default().into(),
+            ) {
+                Ok(gmail) => Box::new(gmail),
+                Err(e) => {
+                    log!("ERROR: MAILROOM_GMAIL_SERVICE_ACCOUNT_KEY: {}", e);
+                    status::exit(Exit::Config);
                 }
-            } else {
-                self.b[self.i][self.cnt[self.i]][self.fidx - 1][self.fsz] = c;
-                self.fsz += 1;
             }
         }
-        Ok(false)
+        ("postmark", _) => {
+            let token = resolve(
+                secrets,
+                "MAILROOM_POSTMARK_SERVER_TOKEN",
+                &config.postmark_server_token,
+            )
+            .await;
+            Box::new(mailer::Postmark::new(
+                token.unwrap_or_else(|| Secret::new(String::new())),
+                config
+                    .postmark_message_stream
+                    .clone()
+                    .unwrap_or_else(|| "outbound".to_string()),
+            ))
+        }
+        ("sparkpost", _) => {
+            let key = resolve(
+                secrets,
+                "MAILROOM_SPARKPOST_API_KEY",
+                &config.sparkpost_api_key,
+            )
+            .await;
+            Box::new(mailer::SparkPost::new(
+                key.unwrap_or_else(|| Secret::new(String::new())),
+                config
+                    .sparkpost_api_url
+                    .clone()
+                    .unwrap_or_else(|| "https://api.sparkpost.com".to_string()),
+            ))
+        }
+        ("pinpoint", _) => {
+            let Some(credentials) = sdk_config.credentials_provider() else {
+                log!("ERROR: failed to load AWS credentials: no credentials provider");
+                status::exit(Exit::Credentials);
+            };
+            Box::new(mailer::Pinpoint::new(
+                credentials,
+                sdk_config
+                    .region()
+                    .map(ToString::to_string)
+                    .unwrap_or_default(),
+                config.pinpoint_app_id.clone().unwrap_or_default(),
+            ))
+        }
+        (_, "v1") => Box::new(mailer::SesV1::new(Client::new(ses_config))),
+        _ => Box::new(mailer::SesV2::new(aws_sdk_sesv2::Client::new(ses_config))),
     }
+}
 
-    async fn finalize(
-        &mut self,
-        client: &Client,
-        config_set_name: &str,
-        from_email: &str,
-        outdir: &str,
-        dev_mode: bool,
-    ) {
-        for i in 0..MAX_ACTIONS {
-            let mut destinations = Vec::new();
-
-            for j in 0..self.cnt[i] {
-                let b = &self.b[i][j];
-                let nb = &self.nb[i][j];
-
-                let to_address = String::from_utf8_lossy(&b[0][..nb[0]]).to_string();
-                let destination = Destination::builder().to_addresses(to_address).build();
-
-                let template_data = if i == 0 {
-                    format!(
-                        "{{\"login\":\"{}\",\"secret\":\"{}\"}}",
-                        String::from_utf8_lossy(&b[1][..nb[1]]),
-                        String::from_utf8_lossy(&b[2][..nb[2]])
-                    )
-                } else {
-                    format!(
-                        "{{\"login\":\"{}\",\"secret\":\"{}\",\"code\":\"{}\"}}",
-                        String::from_utf8_lossy(&b[1][..nb[1]]),
-                        String::from_utf8_lossy(&b[2][..nb[2]]),
-                        String::from_utf8_lossy(&b[3][..nb[3]])
-                    )
-                };
+enum Command {
+    Run,
+    // Prints the configuration, with the origin of each value if set.
+    Show(bool),
+    // A dead-letter action and the ids it applies to.
+    Dlq(String, Vec<String>),
+    // Sends one email for an action to an address.
+    Canary(String, String),
+    // Sends every action to the SES mailbox simulator.
+    Selftest,
+    // Writes the ledger entries sent since a time to stdout, in a format.
+    Export(DateTime<Utc>, String),
+}
+
+// Removes `--name value` or `--name=value` from `args` and returns the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args
+        .iter()
+        .position(|a| a == name || a.starts_with(&format!("{}=", name)))?;
+    let arg = args.remove(i);
+    match arg.split_once('=') {
+        Some((_, value)) => Some(value.to_string()),
+        None if i < args.len() => Some(args.remove(i)),
+        None => None,
+    }
+}
+
+// The parser of an input, and whether its rows are test rows injected
+// through the admin endpoint.
+struct Input {
+    parser: Parser,
+    test: bool,
+    // Completed lines whose rows are held in the parser until they are sent
+    // together, and when the first of them was completed.
+    held: Vec<Line>,
+    held_since: Option<Instant>,
+    // Flights with destinations waiting to be retried, by id.
+    flights: HashMap<u64, Flight>,
+    next_flight: u64,
+    // Number of the next line completed from the source's input.
+    next_line: u64,
+}
+
+// A completed line of input, with its hash and its number in the source's
+// input, unless it came from elsewhere, such as a handoff file.
+struct Line {
+    bytes: Vec<u8>,
+    hash: String,
+    number: Option<u64>,
+}
 
-                let bulk_dest = BulkEmailDestination::builder()
-                    .destination(destination)
-                    .replacement_template_data(template_data)
-                    .build();
+// Lines sent together whose rows aren't all settled yet, because some of
+// their destinations wait to be retried. The rounds after the one being
+// retried are held back until it settles, so that a retry doesn't reorder
+// a recipient's emails.
+struct Flight {
+    lines: Vec<Line>,
+    // Batches parked in `Context::retries`.
+    parked: usize,
+    rounds: VecDeque<Vec<Batch>>,
+    // Whether every row settled so far went out.
+    complete: bool,
+}
 
-                destinations.push(bulk_dest);
+impl Flight {
+    // Sends the next rounds until one of them parks destinations for a
+    // retry, or none are left.
+    async fn fly(&mut self, ctx: &mut Context, summary: &mut Summary, id: u64) {
+        while self.parked == 0 {
+            let Some(round) = self.rounds.pop_front() else {
+                break;
+            };
+            for batch in round {
+                if send(ctx, summary, batch, id).await {
+                    self.parked += 1;
+                }
             }
+        }
+    }
+}
+
+// Destinations of a batch that failed transiently and wait in
+// `Context::retries` to be sent again.
+struct Retry {
+    batch: Batch,
+    flight: u64,
+    deliveries: Vec<Delivery>,
+    // Indices of the destinations to send again.
+    pending: Vec<usize>,
+    attempt: u32,
+    // When the batch was first sent, and how often each destination was.
+    first_attempt_at: SystemTime,
+    attempts: Vec<u32>,
+}
 
-            self.cnt[i] = 0;
+impl Input {
+    fn new(test: bool) -> Self {
+        Input {
+            parser: Parser::new(registry().clone()),
+            test,
+            held: Vec::new(),
+            held_since: None,
+            flights: HashMap::new(),
+            next_flight: 0,
+            next_line: 0,
+        }
+    }
 
-            if destinations.is_empty() {
-                continue;
+    // When the held lines are due to be sent with MAILROOM_BATCH_TIMEOUT.
+    fn flush_at(&self, timeout: Duration) -> Option<Instant> {
+        self.held_since.map(|since| since + timeout)
+    }
+
+    // Sends the rows of the completed lines as flight `id`. Returns the
+    // flight, with the rounds left to send once its parked destinations
+    // settle.
+    async fn finalize(&mut self, ctx: &mut Context, id: u64) -> Flight {
+        let started = ctx.clock.now();
+        let line = self.parser.batch();
+
+        // Parsed per batch, since the key may be rotated.
+        let field_key = match ctx
+            .field_key
+            .as_ref()
+            .map(|k| crypto::FieldKey::from_hex(&k.get()))
+        {
+            Some(Ok(key)) => Some(key),
+            Some(Err(e)) => {
+                log!("ERROR: MAILROOM_FIELD_KEY: {}", e);
+                None
             }
+            None => None,
+        };
+        let mut summary = Summary {
+            rows: line.len(),
+            ..Default::default()
+        };
 
-            let template_name = match i {
-                0 => "activationv1",
-                1 => "passwordrecoveryv1",
-                _ => unreachable!(),
-            };
+        for (i, action) in registry().names().into_iter().enumerate() {
+            ctx.metrics.lock().unwrap().parsed(i, line.rows(i));
+            if let Some(alert) = ctx.volume.record(i, line.rows(i)) {
+                log!(
+                    "WARN: {} rows per minute for {}, against a baseline of {:.1}",
+                    alert["rows_per_minute"],
+                    action,
+                    alert["baseline"].as_f64().unwrap_or_default()
+                );
+                let mut paused = ctx.paused.lock().unwrap();
+                if ctx.config.volume_auto_pause && !paused[i] {
+                    paused[i] = true;
+                    log!(
+                        "WARN: pausing {}; its rows go to the dead-letter directory",
+                        action
+                    );
+                }
+                drop(paused);
+                post_alert(ctx, alert);
+            }
+        }
+        let paused = *ctx.paused.lock().unwrap();
+        let redirect = ctx.redirect.lock().unwrap().clone();
+        let mut allowance = ctx.budget.as_mut().map(Budget::remaining);
+        let now = ctx
+            .clock
+            .system()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut over_budget = false;
+        let mut rounds = VecDeque::new();
 
-            let default_template_data = match i {
-                0 => r#"{"login":"","secret":""}"#,
-                1 => r#"{"login":"","secret":"","code":""}"#,
-                _ => unreachable!(),
-            };
+        let recipients: Vec<String> = (0..registry().len())
+            .flat_map(|i| (0..line.rows(i)).map(move |j| (i, j)))
+            .map(|(i, j)| String::from_utf8_lossy(line.field(i, j, 0)).to_string())
+            .collect();
+        ctx.suppressions.refresh(&recipients).await;
+
+        for round in 0..line.rounds() {
+            let mut batches = Vec::new();
+            for (i, action) in registry().iter().enumerate() {
+                let template_name = action.template.as_str();
+                let config = &ctx.config;
+                // One batch per variant, the first for the action's own
+                // template, and per transport of the action's split.
+                let transports = config.splits[i].len().max(1);
+                let mut open: Vec<Batch> = [None]
+                    .into_iter()
+                    .chain((0..config.variants[i].len()).map(Some))
+                    .flat_map(|v| (0..transports).map(move |t| (v, t)))
+                    .map(|(v, t)| {
+                        let t = (!config.splits[i].is_empty()).then_some(t);
+                        Batch::new(config, i, v, t, self.test, redirect.clone())
+                    })
+                    .collect();
+                let mut filtered = Vec::new();
+                let mut expired = Vec::new();
+                let mut suppressed = Vec::new();
+
+                for j in 0..line.rows(i) {
+                    if line.round(i, j) != round {
+                        continue;
+                    }
+
+                    let fields: Vec<String> = (0..MAX_FIELDS)
+                        .map(|k| String::from_utf8_lossy(line.field(i, j, k)).to_string())
+                        .collect();
+                    let deadline = line.deadline(i, j);
+                    let row = dlq::row_line(i, deadline, &fields);
+
+                    // Checked first, since an expired row is worth nothing
+                    // even once an action is resumed.
+                    if deadline.is_some_and(|d| d < now) {
+                        log!(
+                            "WARN: dropping {} row for {}; its deadline passed",
+                            action.name,
+                            fields[0]
+                        );
+                        summary.expired[i] += 1;
+                        expired.push(fields[0].clone());
+                        continue;
+                    }
+
+                    if paused[i] {
+                        ctx.dead_letters.reject(
+                            template_name,
+                            &fields[0],
+                            &row,
+                            "paused",
+                            "action paused",
+                        );
+                        summary.diverted[i] += 1;
+                        continue;
+                    }
+
+                    if config
+                        .allowlist
+                        .as_ref()
+                        .is_some_and(|a| !a.allows(&fields[0]))
+                    {
+                        log!(
+                            "skipping {} row for {} outside the allowlist",
+                            action.name,
+                            fields[0]
+                        );
+                        summary.filtered[i] += 1;
+                        filtered.push(fields[0].clone());
+                        continue;
+                    }
+
+                    if let Some((class, reason)) = ctx.suppressions.check(&fields[0]) {
+                        log!(
+                            "WARN: skipping {} row for {}: {}",
+                            action.name,
+                            fields[0],
+                            reason
+                        );
+                        if config.invalid_recipients == "dead-letter" {
+                            ctx.dead_letters.reject(
+                                template_name,
+                                &fields[0],
+                                &row,
+                                class,
+                                &reason,
+                            );
+                        }
+                        summary.suppressed[i] += 1;
+                        suppressed.push(fields[0].clone());
+                        continue;
+                    }
+
+                    let row_hash = Seen::hash(row.as_bytes());
+                    if ctx.receipts.as_ref().is_some_and(|r| r.contains(&row_hash)) {
+                        log!(
+                            "WARN: skipping {} row for {} already sent",
+                            action.name,
+                            fields[0]
+                        );
+                        continue;
+                    }
+
+                    let mut data = config.globals.clone();
+                    data.extend(config.default_data[i].clone());
+                    let decrypted: Result<Vec<String>, String> = fields[1..=action.fields.len()]
+                        .iter()
+                        .map(|value| crypto::decrypt(field_key.as_ref(), value))
+                        .collect();
+                    let template_data = match decrypted {
+                        Ok(values) => template_data(data, &action.fields, values),
+                        Err(e) => {
+                            log!(
+                                "ERROR: skipping {} row for {}: {}",
+                                action.name,
+                                fields[0],
+                                e
+                            );
+                            ctx.dead_letters
+                                .reject(template_name, &fields[0], &row, "config", &e);
+                            summary.failed[i] += 1;
+                            continue;
+                        }
+                    };
+                    if template_data.len() > MAX_TEMPLATE_DATA_LEN {
+                        let e = format!(
+                            "template data is {} bytes, over the SES limit of {}",
+                            template_data.len(),
+                            MAX_TEMPLATE_DATA_LEN
+                        );
+                        log!(
+                            "ERROR: skipping {} row for {}: {}",
+                            action.name,
+                            fields[0],
+                            e
+                        );
+                        ctx.dead_letters
+                            .reject(template_name, &fields[0], &row, "permanent", &e);
+                        summary.failed[i] += 1;
+                        continue;
+                    }
 
-            if dev_mode {
-                println!("Sending bulk email 🚀");
-                println!("  Template Name         = {}", template_name);
-                println!("  Configuration Set     = {}", config_set_name);
-                println!("  From                  = {}", from_email);
-                println!("  Default Template Data = {}", default_template_data);
-                println!("  Destinations ({})", destinations.len());
-                for (idx, dest) in destinations.iter().enumerate() {
-                    println!("    {}. {:?}", idx + 1, dest);
+                    if allowance == Some(0) {
+                        ctx.dead_letters.reject(
+                            template_name,
+                            &fields[0],
+                            &row,
+                            "budget",
+                            "daily send budget exhausted",
+                        );
+                        summary.diverted[i] += 1;
+                        over_budget = true;
+                        continue;
+                    }
+                    if let Some(n) = allowance.as_mut() {
+                        *n -= 1;
+                    }
+
+                    let variant = assign_variant(config, i, &fields[0]);
+                    let transport = assign_transport(config, i, &fields[0]);
+                    let slot = variant.map_or(0, |v| v + 1) * transports + transport.unwrap_or(0);
+                    let batch = &mut open[slot];
+                    if !batch.fits(template_data.len(), config.batch_size) {
+                        batches.push(std::mem::replace(
+                            batch,
+                            Batch::new(config, i, variant, transport, self.test, redirect.clone()),
+                        ));
+                    }
+
+                    let to = redirect.as_ref().unwrap_or(&fields[0]);
+                    let bcc = config
+                        .archive_bcc
+                        .clone()
+                        .filter(|_| config.archive_actions[i]);
+                    batch.size += template_data.len();
+                    batch.destinations.push(mailer::Destination {
+                        to: to.clone(),
+                        bcc,
+                        data: template_data,
+                    });
+                    batch.recipients.push(fields[0].clone());
+                    batch.rows.push(row);
                 }
-                println!();
 
-                continue;
-            }
+                batches.extend(open.into_iter().filter(|b| !b.destinations.is_empty()));
 
-            let mut email_builder = client
-                .send_bulk_templated_email()
-                .template(template_name)
-                .configuration_set_name(config_set_name)
-                .source(from_email)
-                .default_template_data(default_template_data);
+                for (outcome, recipients) in [
+                    ("filtered", filtered),
+                    ("expired", expired),
+                    ("suppressed", suppressed),
+                ] {
+                    if recipients.is_empty() {
+                        continue;
+                    }
+                    let mut payload =
+                        webhook::skipped_results(&*ctx.clock, template_name, outcome, &recipients);
+                    if self.test {
+                        payload["test"] = Value::Bool(true);
+                    }
+                    post_results(ctx, payload);
+                }
+            }
+            rounds.push_back(batches);
+        }
 
-            for destination in &destinations {
-                email_builder = email_builder.destinations(destination.clone());
+        if let Some(budget) = ctx.budget.as_mut().filter(|_| over_budget) {
+            if budget.exhaust() {
+                let limit = budget.limit();
+                log!(
+                    "WARN: daily send budget of {} exhausted; rows go to the dead-letter directory",
+                    limit
+                );
+                post_alert(
+                    ctx,
+                    serde_json::json!({
+                        "type": "send_budget",
+                        "limit": limit,
+                        "timestamp": DateTime::<Utc>::from(ctx.clock.system()).to_rfc3339(),
+                    }),
+                );
             }
+        }
+
+        let mut flight = Flight {
+            lines: Vec::new(),
+            parked: 0,
+            rounds,
+            complete: true,
+        };
+        flight.fly(ctx, &mut summary, id).await;
+
+        summary.emit(&*ctx.clock, ctx.clock.now().duration_since(started));
+        if !self.test {
+            status::record(&summary);
+        }
 
-            let start_time = Instant::now();
+        self.parser.reset();
+        flight.complete = summary.complete();
+        flight
+    }
 
-            match email_builder.send().await {
-                Ok(output) => {
-                    println!("SendBulkTemplatedEmailResponse:\n{:#?}", output);
-                    for (idx, status) in output.status().iter().enumerate() {
-                        let code = status.status().map(|s| s.as_str()).unwrap_or("UNKNOWN");
-                        println!("  Destination #{} => Status: {}", idx, code);
+    // Sends the destinations of `retry` again, and the next rounds of its
+    // flight once nothing of it is parked anymore. Returns the lines of the
+    // flight if it settled.
+    async fn resume(&mut self, ctx: &mut Context, retry: Retry) -> Vec<(Option<u64>, bool)> {
+        let started = ctx.clock.now();
+        let mut summary = Summary::default();
+        let id = retry.flight;
+        // The flight stays in `flights` while it flies, so that its lines
+        // are still there if sending panics.
+        let result = AssertUnwindSafe(async {
+            let parked = resend(ctx, &mut summary, retry).await;
+            if let Some(flight) = self.flights.get_mut(&id) {
+                if !parked {
+                    flight.parked -= 1;
+                }
+                flight.fly(ctx, &mut summary, id).await;
+                flight.complete &= summary.complete();
+            }
+        })
+        .catch_unwind()
+        .await;
+        if let Err(panic) = result {
+            let error = panic_message(panic);
+            log!("ERROR: processing a line panicked: {}", error);
+            drop_retries(ctx, self.test, id);
+            if let Some(flight) = self.flights.get_mut(&id) {
+                // The rows of the flight are parsed again from its lines, as
+                // the parser moved on after it was sent.
+                let mut parser = Parser::new(registry().clone());
+                for line in &flight.lines {
+                    for &byte in &line.bytes {
+                        let _ = parser.consume(byte);
                     }
                 }
-                Err(aws_sdk_ses::error::SdkError::ServiceError(err)) => {
-                    // Extract and write the raw HTTP response to a file
-                    let file_name = format!(
-                        "ses_{}_{}.http",
-                        Utc::now().format("%Y%m%d%H%M%S%.3f").to_string(),
-                        i
+                abandon(ctx, self.test, &mut parser, &error);
+                flight.parked = 0;
+                flight.rounds.clear();
+                flight.complete = false;
+            }
+        }
+        // Flights are gone after a panic abandoned their rows.
+        let lines = match self.flights.remove(&id) {
+            Some(flight) => self.land(ctx, id, flight),
+            None => Vec::new(),
+        };
+        summary.emit(&*ctx.clock, ctx.clock.now().duration_since(started));
+        if !self.test {
+            status::record(&summary);
+        }
+        lines
+    }
+
+    // Records the lines of a flight as processed once nothing of it is
+    // parked, and returns their numbers in the source's input, each with
+    // whether all of its rows went out; a failed row counts against every
+    // line of the flight. Returns nothing while the flight waits for
+    // retries.
+    fn land(&mut self, ctx: &mut Context, id: u64, flight: Flight) -> Vec<(Option<u64>, bool)> {
+        if flight.parked > 0 {
+            self.flights.insert(id, flight);
+            return Vec::new();
+        }
+        let mut lines = Vec::new();
+        for line in flight.lines {
+            if let Some(seen) = ctx.seen.as_mut().filter(|_| flight.complete) {
+                if let Err(e) = seen.insert(line.hash) {
+                    log!("ERROR: failed to record processed line: {}", e);
+                }
+            }
+            lines.push((line.number, flight.complete));
+            if !self.test {
+                ctx.gauges.lock().unwrap().processed();
+                status::line(flight.complete);
+            }
+        }
+        lines
+    }
+
+    // The rows held until their lines are sent, and the batches of the
+    // flights' later rounds with their rows, which wait for a retry.
+    fn backlog(&self) -> (usize, usize, usize) {
+        let batches = self
+            .flights
+            .values()
+            .flat_map(|f| f.rounds.iter().flatten());
+        let (queued, rows) = batches.fold((0, 0), |(n, rows), batch| {
+            (n + 1, rows + batch.destinations.len())
+        });
+        (self.parser.batch().len(), queued, rows)
+    }
+
+    // The lines of the flights waiting for retries, oldest first.
+    fn in_flight(&self) -> Vec<&[u8]> {
+        let mut ids: Vec<&u64> = self.flights.keys().collect();
+        ids.sort();
+        ids.into_iter()
+            .flat_map(|id| self.flights[id].lines.iter())
+            .map(|line| line.bytes.as_slice())
+            .collect()
+    }
+}
+
+// Destinations of one bulk request, with the recipient and input row of
+// each.
+struct Batch {
+    action: usize,
+    // The template sent, the action's own or its variant's.
+    template: String,
+    // Name of the variant the recipients were assigned, "control" for the
+    // action's own template, if the action has variants.
+    variant: Option<String>,
+    // Subject line rendered instead of the template's.
+    subject: Option<String>,
+    // Transport the recipients were assigned, if the action splits them
+    // between transports.
+    transport: Option<String>,
+    destinations: Vec<mailer::Destination>,
+    recipients: Vec<String>,
+    rows: Vec<String>,
+    // Combined length of the template data of the destinations.
+    size: usize,
+    test: bool,
+    // Address the destinations were redirected to.
+    redirect: Option<String>,
+}
+
+impl Batch {
+    fn new(
+        config: &Config,
+        action: usize,
+        variant: Option<usize>,
+        transport: Option<usize>,
+        test: bool,
+        redirect: Option<String>,
+    ) -> Self {
+        let variants = &config.variants[action];
+        let assigned = variant.map(|v| &variants[v]);
+        Batch {
+            action,
+            template: assigned
+                .and_then(|v| v.template.clone())
+                .unwrap_or_else(|| registry()[action].template.clone()),
+            variant: match assigned {
+                Some(v) => Some(v.name.clone()),
+                None if !variants.is_empty() => Some("control".to_string()),
+                None => None,
+            },
+            subject: assigned.and_then(|v| v.subject.clone()),
+            transport: transport.map(|t| config.splits[action][t].0.clone()),
+            test,
+            redirect,
+            destinations: Vec::new(),
+            recipients: Vec::new(),
+            rows: Vec::new(),
+            size: 0,
+        }
+    }
+
+    // Whether a destination with `len` bytes of template data can be added
+    // without exceeding MAILROOM_BATCH_SIZE or the limits of a single SES
+    // request.
+    fn fits(&self, len: usize, max: usize) -> bool {
+        self.destinations.len() < max && self.size + len <= MAX_REQUEST_DATA_LEN
+    }
+}
+
+// The variant of `action` sent to `recipient`, as an index into its
+// variants, or None for its own template. Recipients are spread evenly
+// across the variants and the action's own template by a hash of their
+// address, so that each keeps getting the same one.
+fn assign_variant(config: &Config, action: usize, recipient: &str) -> Option<usize> {
+    let variants = config.variants[action].len() as u64;
+    if variants == 0 {
+        return None;
+    }
+    let digest = Sha256::digest(format!(
+        "{}:{}",
+        registry()[action].name,
+        recipient.to_lowercase()
+    ));
+    let n = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    (n % (variants + 1)).checked_sub(1).map(|v| v as usize)
+}
+
+// The transport of `action` `recipient` is sent through, as an index into
+// its split, or None if the action doesn't split its recipients. Like
+// variants, transports are assigned by a hash of the address, here weighted
+// by their shares.
+fn assign_transport(config: &Config, action: usize, recipient: &str) -> Option<usize> {
+    let split = &config.splits[action];
+    if split.is_empty() {
+        return None;
+    }
+    let digest = Sha256::digest(format!(
+        "transport:{}:{}",
+        registry()[action].name,
+        recipient.to_lowercase()
+    ));
+    let mut n = (u64::from_be_bytes(digest[..8].try_into().unwrap()) % 100) as u32;
+    split
+        .iter()
+        .position(|(_, share)| match n.checked_sub(*share) {
+            Some(rest) => {
+                n = rest;
+                false
+            }
+            None => true,
+        })
+}
+
+// The template data of a row: `data` with the values of the fields of its
+// action under their names, serialized as JSON, so that any value a field
+// holds is escaped.
+fn template_data(
+    mut data: serde_json::Map<String, Value>,
+    names: &[String],
+    values: Vec<String>,
+) -> String {
+    for (name, value) in names.iter().zip(values) {
+        data.insert(name.clone(), Value::String(value));
+    }
+    Value::Object(data).to_string()
+}
+
+// The template data every destination of `action` starts from: its
+// fields blank, then the globals and its default data.
+fn default_template_data(config: &Config, action: usize) -> String {
+    let mut data = config.globals.clone();
+    for name in &registry()[action].fields {
+        data.insert(name.to_string(), Value::String(String::new()));
+    }
+    data.extend(config.default_data[action].clone());
+    Value::Object(data).to_string()
+}
+
+// Sends the destinations of `batch`, a part of flight `flight`. Returns
+// whether destinations that failed transiently were parked for a retry, in
+// which case the batch settles once they were sent again.
+async fn send(ctx: &mut Context, summary: &mut Summary, batch: Batch, flight: u64) -> bool {
+    let config = &ctx.config;
+    let template = batch.template.as_str();
+    let default_template_data = default_template_data(config, batch.action);
+
+    // Printed to stderr, so that stdout only carries the JSON records.
+    if config.dev_mode {
+        eprintln!("Sending bulk email 🚀");
+        eprintln!("  Template Name         = {}", template);
+        if let Some(variant) = &batch.variant {
+            eprintln!("  Variant               = {}", variant);
+        }
+        if let Some(transport) = &batch.transport {
+            eprintln!("  Transport             = {}", transport);
+        }
+        if let Some(subject) = &batch.subject {
+            eprintln!("  Subject               = {}", subject);
+        }
+        eprintln!(
+            "  Configuration Set     = {}",
+            config.config_set(batch.action)
+        );
+        if !config.tags.is_empty() {
+            let tags: Vec<String> = config
+                .tags
+                .iter()
+                .map(|(n, v)| format!("{}={}", n, v))
+                .collect();
+            eprintln!("  Tags                  = {}", tags.join(", "));
+        }
+        eprintln!("  From                  = {}", config.from_email);
+        eprintln!("  Default Template Data = {}", default_template_data);
+        eprintln!("  Destinations ({})", batch.destinations.len());
+        for (idx, dest) in batch.destinations.iter().enumerate() {
+            eprintln!("    {}. {:?}", idx + 1, dest);
+        }
+        eprintln!();
+
+        return false;
+    }
+
+    let retry = Retry {
+        deliveries: delivery::failed(
+            batch.destinations.len(),
+            ErrorClass::Retryable,
+            "no result from the provider",
+        ),
+        pending: (0..batch.destinations.len()).collect(),
+        first_attempt_at: ctx.clock.system(),
+        attempts: vec![0; batch.destinations.len()],
+        batch,
+        flight,
+        attempt: 0,
+    };
+    resend(ctx, summary, retry).await
+}
+
+// Sends the pending destinations of `retry` once. Destinations that may be
+// accepted on another attempt are parked to be sent again, up to
+// MAILROOM_SEND_RETRIES times, and the batch settles once none are left.
+// Returns whether destinations were parked.
+async fn resend(ctx: &mut Context, summary: &mut Summary, mut retry: Retry) -> bool {
+    let default_template_data = default_template_data(&ctx.config, retry.batch.action);
+    let response = attempt_send(
+        ctx,
+        summary,
+        &retry.batch,
+        &retry.pending,
+        &default_template_data,
+    )
+    .await;
+
+    let last = retry.attempt == ctx.config.send_retries;
+    let mut again = Vec::new();
+    for (&i, delivery) in retry.pending.iter().zip(response.deliveries) {
+        if !last && delivery.class == Some(ErrorClass::Retryable) {
+            again.push(i);
+        }
+        retry.deliveries[i] = delivery;
+        retry.attempts[i] += 1;
+    }
+    record_receipts(ctx, &retry);
+    if again.is_empty() {
+        settle(ctx, summary, retry, &default_template_data);
+        return false;
+    }
+
+    retry.pending = again;
+    retry.attempt += 1;
+    let delay = backoff::retry_delay(
+        Duration::from_millis(ctx.config.send_retry_delay_ms),
+        retry.attempt,
+    );
+    log!(
+        "WARN: retrying {} of {} destination(s) of {} in {:.2} seconds (attempt {} of {})",
+        retry.pending.len(),
+        retry.batch.destinations.len(),
+        retry.batch.template,
+        delay.as_secs_f64(),
+        retry.attempt,
+        ctx.config.send_retries
+    );
+    summary.retried += retry.pending.len();
+    ctx.metrics
+        .lock()
+        .unwrap()
+        .retried(&retry.batch.template, retry.pending.len());
+    ctx.retries.defer(retry, delay);
+    true
+}
+
+// Records the destinations of `retry` accepted by its last attempt in the
+// receipts journal before it parks or settles, so that a row isn't sent
+// again once retried after a crash or a handoff.
+fn record_receipts(ctx: &mut Context, retry: &Retry) {
+    let Some(receipts) = ctx.receipts.as_mut() else {
+        return;
+    };
+    let accepted: Vec<(String, String)> = retry
+        .pending
+        .iter()
+        .map(|&i| (&retry.deliveries[i], &retry.batch.rows[i]))
+        .filter(|(delivery, _)| delivery.accepted())
+        .map(|(delivery, row)| {
+            (
+                Seen::hash(row.as_bytes()),
+                delivery.message_id.clone().unwrap_or_default(),
+            )
+        })
+        .collect();
+    if accepted.is_empty() {
+        return;
+    }
+    if let Err(e) = receipts.record(accepted) {
+        log!("ERROR: failed to record send receipts: {}", e);
+    }
+}
+
+// Records the results of a batch once every destination has its final one.
+fn settle(ctx: &mut Context, summary: &mut Summary, retry: Retry, default_template_data: &str) {
+    let Retry {
+        batch,
+        deliveries,
+        first_attempt_at,
+        attempts,
+        ..
+    } = retry;
+    let template = batch.template.as_str();
+
+    // Redirected and test emails aren't what customers receive.
+    if let (Some(samples), None, false) = (ctx.samples.as_mut(), &batch.redirect, batch.test) {
+        if let Some(idx) = deliveries.iter().position(Delivery::accepted) {
+            if let Some(path) = samples.take(batch.action) {
+                tokio::spawn(samples::capture(
+                    ctx.client.clone(),
+                    template.to_string(),
+                    default_template_data.to_string(),
+                    batch.destinations[idx].data.clone(),
+                    path,
+                ));
+            }
+        }
+    }
+
+    if ctx.webhook.is_some() {
+        let mut payload =
+            webhook::batch_results(&*ctx.clock, template, &batch.recipients, &deliveries);
+        if batch.test {
+            payload["test"] = Value::Bool(true);
+        }
+        if let Some(redirect) = &batch.redirect {
+            payload["redirected_to"] = Value::String(redirect.clone());
+        }
+        if let Some(variant) = &batch.variant {
+            payload["variant"] = Value::String(variant.clone());
+        }
+        if let Some(transport) = &batch.transport {
+            payload["transport"] = Value::String(transport.clone());
+        }
+        post_results(ctx, payload);
+    }
+
+    ctx.dead_letters
+        .record(template, &batch.recipients, &batch.rows, &deliveries);
+
+    if let (Some(ledger), false) = (&ctx.ledger, batch.test) {
+        let entries: Vec<ledger::Entry> = deliveries
+            .iter()
+            .zip(&batch.recipients)
+            .zip(&attempts)
+            .map(|((delivery, recipient), &attempts)| ledger::Entry {
+                action: &registry()[batch.action].name,
+                template,
+                recipient,
+                redirected_to: batch.redirect.as_deref(),
+                variant: batch.variant.as_deref(),
+                provider: batch.transport.as_deref(),
+                delivery,
+                first_attempt_at,
+                attempts,
+            })
+            .collect();
+        if let Err(e) = ledger.record(&entries) {
+            log!("ERROR: failed to record the send ledger: {}", e);
+        }
+    }
+
+    let sent = deliveries.iter().filter(|d| d.accepted()).count();
+    let failed = deliveries.len() - sent;
+    summary.sent[batch.action] += sent;
+    summary.failed[batch.action] += failed;
+
+    if let Some(budget) = ctx.budget.as_mut() {
+        if let Err(e) = budget.spend(sent as u64) {
+            log!("ERROR: failed to record send budget: {}", e);
+        }
+    }
+
+    let alert = ctx.stats.lock().unwrap().record(template, sent, failed);
+    if let Some(alert) = alert {
+        log!(
+            "WARN: failure rate of {} rose to {:.1}%",
+            template,
+            alert["failure_rate"].as_f64().unwrap_or_default() * 100.0
+        );
+        post_alert(ctx, alert);
+    }
+}
+
+// Sends the destinations of `batch` at indices `pending` once, after any
+// pause SES asked for, and logs how it went.
+async fn attempt_send(
+    ctx: &mut Context,
+    summary: &mut Summary,
+    batch: &Batch,
+    pending: &[usize],
+    default_template_data: &str,
+) -> mailer::Response {
+    let config = &ctx.config;
+    let template = batch.template.as_str();
+    let destinations: Vec<mailer::Destination> = pending
+        .iter()
+        .map(|&i| batch.destinations[i].clone())
+        .collect();
+
+    // The variant is tagged so that opens can be counted per variant.
+    let mut tags = config.tags.clone();
+    if let Some(variant) = &batch.variant {
+        tags.push(("mailroom_variant".to_string(), variant.clone()));
+    }
+    let request = mailer::Request {
+        template,
+        subject: batch.subject.as_deref(),
+        config_set: config.config_set(batch.action),
+        source: &config.from_email,
+        default_data: default_template_data,
+        destinations: &destinations,
+        tags: &tags,
+        test: batch.test,
+    };
+
+    ctx.backoff.wait().await;
+
+    if let Some(limiter) = ctx.limiter.as_mut() {
+        let emails = destinations
+            .iter()
+            .map(|d| 1 + d.bcc.is_some() as usize)
+            .sum();
+        let wait = limiter.take(emails);
+        if !wait.is_zero() {
+            log!(
+                "DEBUG: waiting {:.2} seconds to stay under the maximum send rate",
+                wait.as_secs_f64()
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    log!(
+        "DEBUG: sending {} to {} destination(s): {}{}; default data {}",
+        template,
+        pending.len(),
+        pending
+            .iter()
+            .map(|&i| batch.recipients[i].as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        batch
+            .redirect
+            .as_ref()
+            .map_or(String::new(), |to| format!(" (redirected to {})", to)),
+        default_template_data
+    );
+
+    let start_time = ctx.clock.now();
+
+    let response = ctx.mailer_of(batch).send(request).await;
+    ctx.metrics.lock().unwrap().sent(
+        template,
+        &response.deliveries,
+        ctx.clock.now().duration_since(start_time),
+    );
+
+    log!("DEBUG: {} response: {}", template, response.debug);
+
+    if let Some(failure) = &response.failure {
+        log!(
+            "ERROR: bulk send of {} failed ({})",
+            template,
+            failure.class()
+        );
+    }
+
+    match &response.failure {
+        None => {
+            ctx.backoff.succeeded();
+            for (&idx, delivery) in pending.iter().zip(&response.deliveries) {
+                log!(
+                    "DEBUG: {} status of destination #{} of {}: {}",
+                    ctx.mailer_of(batch).name(),
+                    idx,
+                    template,
+                    delivery.code
+                );
+                if let Some(class) = delivery.class {
+                    log!(
+                        "WARN: destination #{} of {} failed ({}): {}",
+                        idx,
+                        template,
+                        class,
+                        delivery.code
                     );
+                }
+            }
+        }
+        Some(failure @ mailer::Failure::Service { .. }) => {
+            if let Some(retry_after) = failure.throttled() {
+                let pause = ctx.backoff.throttled(retry_after);
+                summary.throttled += 1;
+                ctx.gauges.lock().unwrap().throttled(pause);
+                log!(
+                    "WARN: throttled by SES; pausing sends for {:.2} seconds",
+                    pause.as_secs_f64()
+                );
+            }
+            let mailer::Failure::Service { raw, .. } = failure else {
+                unreachable!()
+            };
+
+            // Extract and write the raw HTTP response to a file
+            let file_name = format!(
+                "ses_{}_{}.http",
+                DateTime::<Utc>::from(ctx.clock.system()).format("%Y%m%d%H%M%S%.3f"),
+                batch.action
+            );
+
+            let full_path = Path::new(&config.outdir).join(file_name);
 
-                    let full_path = Path::new(outdir).join(file_name);
-
-                    match File::create(&full_path) {
-                        Ok(mut file) => {
-                            let result = (|| -> Result<usize, std::io::Error> {
-                                let mut total_bytes_written = 0;
-
-                                let status_line = format!("HTTP/1.1 {}\n", err.raw().status());
-                                total_bytes_written += file.write(status_line.as_bytes())?;
-
-                                for (key, value) in err.raw().headers().iter() {
-                                    let header = format!("{}: {}\n", key, value);
-                                    total_bytes_written += file.write(header.as_bytes())?;
-                                }
-
-                                total_bytes_written += file.write(b"\n")?;
-
-                                if let Some(bytes) = err.raw().body().bytes() {
-                                    let raw_body = String::from_utf8_lossy(bytes);
-                                    total_bytes_written += file.write(raw_body.as_bytes())?;
-                                } else {
-                                    let no_body_message = "Empty body.\n";
-                                    total_bytes_written +=
-                                        file.write(no_body_message.as_bytes())?;
-                                }
-
-                                Ok(total_bytes_written)
-                            })();
-
-                            let duration = start_time.elapsed();
-
-                            match result {
-                                Ok(total_bytes_written) => {
-                                    log!(
-                                        "{} bytes written to {} ({:.2} seconds)",
-                                        total_bytes_written,
-                                        full_path.display(),
-                                        duration.as_secs_f64()
-                                    );
-                                }
-                                Err(e) => {
-                                    log!(
-                                        "ERROR: failed to write to file {}: {}",
-                                        full_path.display(),
-                                        e
-                                    );
-                                }
-                            }
+            match File::create(&full_path) {
+                Ok(mut file) => {
+                    let result = (|| -> Result<usize, std::io::Error> {
+                        let mut total_bytes_written = 0;
+
+                        let status_line = format!("HTTP/1.1 {}\n", raw.status);
+                        total_bytes_written += file.write(status_line.as_bytes())?;
+
+                        for (key, value) in &raw.headers {
+                            let header = format!("{}: {}\n", key, value);
+                            total_bytes_written += file.write(header.as_bytes())?;
+                        }
+
+                        total_bytes_written += file.write(b"\n")?;
+
+                        if let Some(bytes) = &raw.body {
+                            let raw_body = String::from_utf8_lossy(bytes);
+                            total_bytes_written += file.write(raw_body.as_bytes())?;
+                        } else {
+                            let no_body_message = "Empty body.\n";
+                            total_bytes_written += file.write(no_body_message.as_bytes())?;
+                        }
+
+                        Ok(total_bytes_written)
+                    })();
+
+                    let duration = ctx.clock.now().duration_since(start_time);
+
+                    match result {
+                        Ok(total_bytes_written) => {
+                            log!(
+                                "{} bytes written to {} ({:.2} seconds)",
+                                total_bytes_written,
+                                full_path.display(),
+                                duration.as_secs_f64()
+                            );
                         }
                         Err(e) => {
                             log!(
-                                "ERROR: failed to create file {}: {}",
+                                "ERROR: failed to write to file {}: {}",
                                 full_path.display(),
                                 e
                             );
                         }
                     }
                 }
-                Err(aws_sdk_ses::error::SdkError::TimeoutError { .. }) => {
-                    log!("ERROR: connection timeout out");
-                }
-                Err(aws_sdk_ses::error::SdkError::DispatchFailure(err)) => {
-                    log!("ERROR: dispatch failure; {:#?}", err);
+                Err(e) => {
+                    log!(
+                        "ERROR: failed to create file {}: {}",
+                        full_path.display(),
+                        e
+                    );
                 }
-                Err(err) => {
-                    log!("ERROR: unexpected error; {:#?}", err);
+            }
+        }
+        Some(mailer::Failure::Timeout) => {
+            log!("ERROR: connection timeout out");
+        }
+        Some(mailer::Failure::Dispatch(err)) => {
+            log!("ERROR: dispatch failure; {}", err);
+        }
+        Some(mailer::Failure::Other(_, err)) => {
+            log!("ERROR: unexpected error; {}", err);
+        }
+    }
+    response
+}
+
+// Feeds `bytes` to the parser, sending the rows of every completed line,
+// or holding them with MAILROOM_BATCH_TIMEOUT until a full batch is ready.
+// Bytes of the line still being read are kept in `pending`. Lines completed
+// by bytes `from_source` are numbered as the source's input. Returns the
+// numbers of the lines that were processed, each with whether all of its
+// rows went out.
+async fn process(
+    input: &mut Input,
+    ctx: &mut Context,
+    bytes: &[u8],
+    pending: &mut Vec<u8>,
+    from_source: bool,
+) -> Vec<(Option<u64>, bool)> {
+    let mut lines = Vec::new();
+    // Test rows are sent right away; they are not part of the backlog.
+    if !input.test {
+        ctx.gauges.lock().unwrap().read(bytes);
+    }
+    for &byte in bytes {
+        pending.push(byte);
+        let completed = match input.parser.consume(byte) {
+            Ok(completed) => completed,
+            // Test rows are built by the admin endpoint, so they never stop
+            // the sender.
+            Err(e) if input.test || ctx.config.parse_errors == "skip-row" => {
+                log!("ERROR: skipping malformed row; {}", e);
+                if !input.test {
+                    status::skipped_row();
                 }
+                e.ends_line
+            }
+            Err(e) => {
+                log!("ERROR: failed to parse input; {}", e);
+                status::exit(Exit::ParseAbort);
+            }
+        };
+        if !completed {
+            continue;
+        }
+        let bytes = std::mem::take(pending);
+        let hash = Seen::hash(&bytes);
+        let number = from_source.then(|| {
+            input.next_line += 1;
+            input.next_line - 1
+        });
+        if ctx.seen.as_ref().is_some_and(|seen| seen.contains(&hash))
+            || input
+                .held
+                .iter()
+                .chain(input.flights.values().flat_map(|f| &f.lines))
+                .any(|held| held.hash == hash)
+        {
+            log!("WARN: skipping duplicate line {}", &hash[..16]);
+            input.parser.discard_line();
+            lines.push((number, true));
+            if !input.test {
+                ctx.gauges.lock().unwrap().processed();
+                status::duplicate();
+            }
+            continue;
+        }
+        input.held.push(Line {
+            bytes,
+            hash,
+            number,
+        });
+        input.held_since.get_or_insert_with(|| ctx.clock.now());
+        if input.test
+            || ctx.config.batch_timeout_ms == 0
+            || input.parser.batch().len() >= ctx.config.batch_size
+        {
+            lines.extend(flush(input, ctx).await);
+        }
+    }
+    lines
+}
+
+// Dead-letters every row of the completed lines in `parser` after
+// processing them panicked, and reports them as failed. Rows accepted before
+// the panic are in the receipts journal, so retrying the entries doesn't send
+// them twice.
+fn abandon(ctx: &mut Context, test: bool, parser: &mut Parser, error: &str) {
+    let line = parser.batch();
+    let mut summary = Summary {
+        rows: line.len(),
+        ..Default::default()
+    };
+    for (i, action) in registry().iter().enumerate() {
+        let mut recipients = Vec::new();
+        for j in 0..line.rows(i) {
+            let fields: Vec<String> = (0..MAX_FIELDS)
+                .map(|k| String::from_utf8_lossy(line.field(i, j, k)).to_string())
+                .collect();
+            let row = dlq::row_line(i, line.deadline(i, j), &fields);
+            ctx.dead_letters
+                .reject(&action.template, &fields[0], &row, "panic", error);
+            recipients.push(fields[0].clone());
+        }
+        summary.failed[i] = recipients.len();
+
+        if ctx.webhook.is_some() && !recipients.is_empty() {
+            let deliveries = delivery::failed(recipients.len(), ErrorClass::Retryable, error);
+            let mut payload =
+                webhook::batch_results(&*ctx.clock, &action.template, &recipients, &deliveries);
+            if test {
+                payload["test"] = Value::Bool(true);
             }
+            post_results(ctx, payload);
         }
     }
+    summary.emit(&*ctx.clock, Duration::ZERO);
+    if !test {
+        status::record(&summary);
+    }
+    parser.reset();
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    let dev_mode = env::var("MAILROOM_DEBUG").unwrap_or_else(|_| "false".to_string()) == "true";
-    let outdir = env::var("MAILROOM_SES_OUTPUT_PATH").unwrap_or_else(|_| "./output".to_string());
-    let config_set_name = env::var("MAILROOM_SES_CONFIG_SET").unwrap_or_else(|_| "default".to_string());
-    let from_email = env::var("MAILROOM_SES_SOURCE").unwrap_or_else(|_| "noreply@localhost".to_string());
+// Sends the rows of the held lines as a flight. Returns the lines unless
+// destinations of theirs were parked for a retry; see `Input::land`.
+async fn flush(input: &mut Input, ctx: &mut Context) -> Vec<(Option<u64>, bool)> {
+    let held = std::mem::take(&mut input.held);
+    input.held_since = None;
+    if held.is_empty() {
+        return Vec::new();
+    }
+    if !ctx.config.dev_mode && ctx.config.checks_templates() {
+        for name in ctx
+            .templates
+            .validate(&ctx.client, &ctx.config.templates())
+            .await
+        {
+            log!("WARN: template {} no longer exists", name);
+        }
+    }
+    // Lines with failed rows are not recorded, so that the rows can be sent
+    // again from the dead-letter directory.
+    let id = input.next_flight;
+    input.next_flight += 1;
+    let mut flight = match AssertUnwindSafe(input.finalize(ctx, id))
+        .catch_unwind()
+        .await
+    {
+        Ok(flight) => flight,
+        Err(panic) => {
+            let error = panic_message(panic);
+            log!("ERROR: processing a line panicked: {}", error);
+            abandon(ctx, input.test, &mut input.parser, &error);
+            drop_retries(ctx, input.test, id);
+            Flight {
+                lines: Vec::new(),
+                parked: 0,
+                rounds: VecDeque::new(),
+                complete: false,
+            }
+        }
+    };
+    flight.lines = held;
+    input.land(ctx, id, flight)
+}
+
+// The message a panic was raised with.
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+// Drops the parked retries of flight `id` of the test or regular input after
+// its rows were abandoned, so that they aren't sent once more.
+fn drop_retries(ctx: &mut Context, test: bool, id: u64) {
+    let dropped = ctx
+        .retries
+        .retain(|retry| retry.flight != id || retry.batch.test != test);
+    if dropped > 0 {
+        log!(
+            "WARN: dropped {} parked retries of an abandoned flight",
+            dropped
+        );
+    }
+}
+
+// A drain in progress: when it has to end, and when it began with how many
+// rows had settled then, to tell how fast the rest goes out.
+struct Drain {
+    deadline: Instant,
+    started: Instant,
+    settled: usize,
+}
+
+// Reports the work left while draining as a JSON record to stdout and as a
+// log line, with when it should be done at the rate rows settled since the
+// drain began, or at the maximum send rate until any has.
+fn report_drain(ctx: &Context, input: &Input, pending: usize, drain: &Drain) {
+    let now = ctx.clock.now();
+    let (held, queued, queued_rows) = input.backlog();
+    let (retries, retry_rows) = ctx
+        .retries
+        .iter()
+        .filter(|retry| !retry.batch.test)
+        .fold((0, 0), |(n, rows), retry| {
+            (n + 1, rows + retry.pending.len())
+        });
+    let rows = held + queued_rows + retry_rows;
+    let elapsed = now.saturating_duration_since(drain.started).as_secs_f64();
+    let settled = status::settled() - drain.settled;
+    let rate = if settled > 0 && elapsed > 0.0 {
+        Some(settled as f64 / elapsed)
+    } else {
+        ctx.limiter.as_ref().map(RateLimiter::rate)
+    };
+    let eta = rate.map(|rate| Duration::from_secs_f64(rows as f64 / rate));
+    let left = drain.deadline.saturating_duration_since(now);
+    let record = serde_json::json!({
+        "type": "drain_status",
+        "timestamp": DateTime::<Utc>::from(ctx.clock.system()).to_rfc3339(),
+        "pending_bytes": pending,
+        "held_rows": held,
+        "queued_batches": queued,
+        "parked_retries": retries,
+        "remaining_rows": rows,
+        "eta_ms": eta.map(|eta| eta.as_millis() as u64),
+        "deadline_ms": left.as_millis() as u64,
+    });
+    println!("{}", record);
 
     log!(
-        "configured; debug={} config_set={} source={} output_path={}",
-        dev_mode,
-        config_set_name,
-        from_email,
-        outdir,
+        "draining; {} bytes of input pending, {} rows held, {} batches queued, {} retries parked, {} rows left, done in {}, {:.1} seconds left",
+        pending,
+        held,
+        queued,
+        retries,
+        rows,
+        eta.map_or("unknown time".to_string(), |eta| format!(
+            "{:.1} seconds",
+            eta.as_secs_f64()
+        )),
+        left.as_secs_f64()
     );
+}
+
+// Tells `source` how each processed line of its input went.
+fn acknowledge(source: &mut impl Source, lines: Vec<(Option<u64>, bool)>) {
+    for (line, complete) in lines {
+        match (line, complete) {
+            (Some(line), true) => source.ack(line),
+            (Some(line), false) => source.nack(line),
+            (None, _) => {}
+        }
+    }
+}
+
+// Reads the `--since` of an export, as RFC 3339 or seconds since the Unix
+// epoch.
+fn parse_since(since: &str) -> Option<DateTime<Utc>> {
+    match since.parse::<i64>() {
+        Ok(secs) => DateTime::from_timestamp(secs, 0),
+        Err(_) => DateTime::parse_from_rfc3339(since)
+            .ok()
+            .map(|at| at.with_timezone(&Utc)),
+    }
+}
+
+// Writes the partially read line to the handoff file so that the next
+// process can pick up where this one stopped.
+fn write_handoff(path: &Path, pending: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(pending)?;
+    file.sync_all()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<Error>> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // `config show [--resolved]` prints the effective configuration,
+    // `dlq list|retry|purge [ID...]` manages dead letters and `export` dumps
+    // the ledger, instead of running.
+    let command = match args.first().map(String::as_str) {
+        Some("config") if args.get(1).map(String::as_str) == Some("show") => {
+            args.drain(..2);
+            let resolved = args.iter().position(|a| a == "--resolved");
+            Command::Show(resolved.map(|i| args.remove(i)).is_some())
+        }
+        Some("dlq") => {
+            let action = args.get(1).cloned().unwrap_or_default();
+            if !["list", "retry", "purge"].contains(&action.as_str()) {
+                log!("ERROR: usage: sender dlq list|retry|purge [ID...]");
+                status::exit(Exit::Config);
+            }
+            let end = args
+                .iter()
+                .skip(2)
+                .position(|a| a.starts_with("--"))
+                .map_or(args.len(), |n| n + 2);
+            let ids = args.drain(..end).skip(2).collect();
+            Command::Dlq(action, ids)
+        }
+        Some("selftest") => {
+            args.remove(0);
+            Command::Selftest
+        }
+        Some("canary") => {
+            args.remove(0);
+            let action = take_option(&mut args, "--action");
+            let to = take_option(&mut args, "--to");
+            let (Some(action), Some(to)) = (action, to) else {
+                log!("ERROR: usage: sender canary --action ACTION --to ADDRESS");
+                status::exit(Exit::Config);
+            };
+            Command::Canary(action, to)
+        }
+        Some("export") => {
+            args.remove(0);
+            let since = take_option(&mut args, "--since");
+            let format = take_option(&mut args, "--format").unwrap_or_else(|| "csv".to_string());
+            let Some(since) = since.as_deref().and_then(parse_since) else {
+                log!("ERROR: usage: sender export --since TIME [--format csv|parquet]");
+                status::exit(Exit::Config);
+            };
+            if !["csv", "parquet"].contains(&format.as_str()) {
+                log!("ERROR: usage: sender export --since TIME [--format csv|parquet]");
+                status::exit(Exit::Config);
+            }
+            Command::Export(since, format)
+        }
+        _ => Command::Run,
+    };
+    // Everything that expires or schedules things reads the time from here.
+    let clock = clock::system();
+    logging::set_clock(clock.clone());
+
+    if matches!(command, Command::Run) {
+        status::enable(clock.clone());
+    }
+
+    let (config, problems) = Config::load(Layers::new(args));
+
+    if let Command::Show(resolved) = command {
+        config.show(resolved);
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            log!("ERROR: {}", problem);
+        }
+        log!(
+            "ERROR: invalid configuration; {} problem(s) found",
+            problems.len()
+        );
+        status::exit(Exit::Config);
+    }
+
+    if let Command::Show(_) = command {
+        return Ok(());
+    }
+    if let Err(e) = config.create_dirs() {
+        log!("ERROR: {}", e);
+        status::exit(Exit::Config);
+    }
 
-    if let Err(e) = fs::create_dir_all(&outdir) {
-        log!("ERROR: failed to create output directory {}: {}", outdir, e);
-        process::exit(1);
+    match command {
+        Command::Run | Command::Canary(..) | Command::Selftest | Command::Show(_) => {}
+        Command::Dlq(action, ids) => {
+            let dead_letters = DeadLetters::new(&config.dlq_path, None, clock.clone());
+            let result = match action.as_str() {
+                "list" => dead_letters.list().map(|_| None),
+                "retry" => dead_letters.retry(&ids).map(Some),
+                _ => dead_letters.purge(&ids).map(Some),
+            };
+            match result {
+                Ok(Some(n)) => log!("{} dead letter(s) removed", n),
+                Ok(None) => {}
+                Err(e) => {
+                    log!("ERROR: {}: {}", config.dlq_path, e);
+                    status::exit(Exit::Failure);
+                }
+            }
+            return Ok(());
+        }
+        Command::Export(since, format) => {
+            let Some(path) = &config.ledger_path else {
+                log!("ERROR: MAILROOM_LEDGER_PATH is not set");
+                status::exit(Exit::Config);
+            };
+            let ledger = Ledger::new(path, &config.transport, clock.clone());
+            match ledger.export(since, &format, io::stdout()) {
+                Ok(n) => log!("{} ledger entries exported", n),
+                Err(e) => {
+                    log!("ERROR: failed to export the ledger: {}", e);
+                    status::exit(Exit::Failure);
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    if let Ok(filter) = logging::Filter::parse(&config.log_filter) {
+        logging::init(filter);
     }
 
+    log!(
+        "configured; debug={} environment={} input={} transport={} ses_api={} config_set={} source={} output_path={} template_refresh_interval={}ms",
+        config.dev_mode,
+        config.environment.as_deref().unwrap_or("none"),
+        config.source,
+        config.transport,
+        config.ses_api,
+        config.config_set_name,
+        config.from_email,
+        config.outdir,
+        config.template_refresh_ms,
+    );
+
     let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
-    let config = aws_config::from_env().region(region_provider).load().await;
-    let client = Client::new(&config);
+    let sdk_config = aws_config::from_env().region(region_provider).load().await;
+    let sqs = aws_sdk_sqs::Client::new(&sdk_config);
+
+    let mut secrets = Secrets::new(aws_sdk_secretsmanager::Client::new(&sdk_config));
+
+    // With MAILROOM_AWS_CREDENTIALS the SES clients read the keys from the
+    // secret on every request rather than caching them, so that they keep
+    // working when the keys are rotated.
+    let ses_config = match resolve(
+        &mut secrets,
+        "MAILROOM_AWS_CREDENTIALS",
+        &config.aws_credentials,
+    )
+    .await
+    {
+        Some(secret) => sdk_config
+            .to_builder()
+            .credentials_provider(SharedCredentialsProvider::new(credentials::Rotating::new(
+                secret,
+            )))
+            .identity_cache(IdentityCache::no_cache())
+            .build(),
+        None => sdk_config.clone(),
+    };
+    let client = Client::new(&ses_config);
+
+    // Loaded up front where AWS is used, so that missing or expired
+    // credentials aren't mistaken for missing templates or an empty queue.
+    for (used, aws) in [
+        (!config.dev_mode && config.uses("ses"), &ses_config),
+        (config.source == "sqs", &sdk_config),
+        (!config.dev_mode && config.uses("pinpoint"), &sdk_config),
+    ] {
+        if !used {
+            continue;
+        }
+        let credentials = match aws.credentials_provider() {
+            Some(provider) => provider
+                .provide_credentials()
+                .await
+                .map_err(|e| aws_sdk_ses::error::DisplayErrorContext(e).to_string()),
+            None => Err("no credentials provider".to_string()),
+        };
+        if let Err(e) = credentials {
+            log!("ERROR: failed to load AWS credentials: {}", e);
+            status::exit(Exit::Credentials);
+        }
+    }
+    let database_url = match config.source.as_str() {
+        "outbox" => resolve(&mut secrets, "MAILROOM_DATABASE_URL", &config.database_url).await,
+        _ => None,
+    };
+    let primary = build_mailer(
+        &config.transport,
+        &config,
+        &mut secrets,
+        &sdk_config,
+        &ses_config,
+    )
+    .await;
+    // The watcher keeps a handle on the failover mailer to shift its
+    // traffic.
+    let (mailer, failover): (Box<dyn Mailer>, _) = match &config.failover_transport {
+        Some(transport) => {
+            let secondary =
+                build_mailer(transport, &config, &mut secrets, &sdk_config, &ses_config).await;
+            let failover = Arc::new(Failover::new(
+                (config.transport.clone(), primary),
+                (transport.clone(), secondary),
+                config.failover_after,
+                config.failback_after,
+            ));
+            (Box::new(failover.clone()), Some(failover))
+        }
+        None => (primary, None),
+    };
+    let mut mailers = HashMap::new();
+    for (transport, _) in config.splits.iter().flatten() {
+        if *transport != config.transport && !mailers.contains_key(transport) {
+            let mailer =
+                build_mailer(transport, &config, &mut secrets, &sdk_config, &ses_config).await;
+            mailers.insert(transport.clone(), mailer);
+        }
+    }
+
+    if matches!(command, Command::Canary(..) | Command::Selftest) && config.dev_mode {
+        log!("ERROR: canary and selftest send real emails; MAILROOM_DEBUG must be false");
+        status::exit(Exit::Config);
+    }
+
+    if let Command::Selftest = command {
+        status::exit(if canary::selftest(&*mailer, &config, &*clock).await {
+            Exit::Success
+        } else {
+            Exit::Failure
+        });
+    }
+
+    if let Command::Canary(name, to) = &command {
+        // Resolved once the configuration installed the actions.
+        let Some(action) = registry().position(name) else {
+            log!(
+                "ERROR: usage: sender canary --action {} --to ADDRESS",
+                registry().names().join("|")
+            );
+            status::exit(Exit::Config);
+        };
+        match canary::send(&*mailer, &config, &*clock, action, to).await {
+            Ok(message_id) => {
+                log!(
+                    "canary {} to {} accepted; message_id={}",
+                    registry()[action].template,
+                    to,
+                    message_id
+                );
+                return Ok(());
+            }
+            Err(e) => {
+                log!(
+                    "ERROR: canary {} to {} failed: {}",
+                    registry()[action].template,
+                    to,
+                    e
+                );
+                status::exit(Exit::Failure);
+            }
+        }
+    }
+
+    let mut lookups = (config.lookup_cache_ttl_ms > 0 && config.transport == "ses")
+        .then(|| Lookups::open(Path::new(&config.outdir).join(LOOKUPS_FILE), clock.clone()));
+
+    // The DKIM status is looked up in SES.
+    if !config.dev_mode && config.transport == "ses" {
+        let problems = domain::check_alignment(
+            &client,
+            &config.from_email,
+            lookups.as_mut(),
+            Duration::from_millis(config.lookup_cache_ttl_ms),
+        )
+        .await;
+        for problem in &problems {
+            log!("WARN: {}", problem);
+        }
+        if config.strict_domain && !problems.is_empty() {
+            log!(
+                "ERROR: {} cannot be sent from with DMARC alignment",
+                config.from_email
+            );
+            status::exit(Exit::Config);
+        }
+    }
+
+    if !config.dev_mode && config.transport == "ses" {
+        let problems = pools::check(&aws_sdk_sesv2::Client::new(&ses_config), &config).await;
+        for problem in &problems {
+            log!("ERROR: {}", problem);
+        }
+        if !problems.is_empty() {
+            status::exit(Exit::Config);
+        }
+    }
+
+    let mut templates = TemplateCache::new(
+        Duration::from_millis(config.template_refresh_ms),
+        config
+            .template_dir
+            .clone()
+            .filter(|_| config.renders_locally())
+            .map(Into::into),
+        lookups,
+        Duration::from_millis(config.lookup_cache_ttl_ms),
+        clock.clone(),
+    );
+
+    if !config.dev_mode && config.checks_templates() {
+        let missing = templates.validate(&client, &config.templates()).await;
+        if !missing.is_empty() {
+            log!("ERROR: templates not found: {}", missing.join(", "));
+            status::exit(Exit::Config);
+        }
+
+        // Every variable a template references must be filled from the
+        // action's fields, the globals or its default data; anything else
+        // would be rendered blank.
+        let mut drifted = false;
+        for (i, action) in registry().iter().enumerate() {
+            let known = action
+                .fields
+                .iter()
+                .cloned()
+                .chain(config.globals.keys().cloned())
+                .chain(config.default_data[i].keys().cloned())
+                .collect();
+            let variants = config.variants[i]
+                .iter()
+                .filter_map(|v| v.template.as_ref());
+            for name in [&action.template].into_iter().chain(variants) {
+                let unknown = templates.unknown_variables(&client, name, &known).await;
+                if !unknown.is_empty() {
+                    log!(
+                        "ERROR: template {} references variables that {} rows don't provide: {}",
+                        name,
+                        action.name,
+                        unknown.join(", ")
+                    );
+                    drifted = true;
+                }
+            }
+        }
+        if drifted {
+            status::exit(Exit::Config);
+        }
+    }
+
+    let webhook_secret = resolve(
+        &mut secrets,
+        "MAILROOM_RESULTS_WEBHOOK_SECRET",
+        &config.results_webhook_secret,
+    )
+    .await;
+    let field_key = resolve(&mut secrets, "MAILROOM_FIELD_KEY", &config.field_key).await;
+
+    // Keys given directly are validated with the rest of the configuration.
+    if let Some(Err(e)) = field_key
+        .as_ref()
+        .map(|key| crypto::FieldKey::from_hex(&key.get()))
+    {
+        log!("ERROR: MAILROOM_FIELD_KEY: {}", e);
+        status::exit(Exit::Config);
+    }
+
+    tokio::spawn(secrets.refresh(Duration::from_millis(config.secrets_refresh_ms)));
+
+    let webhook = config
+        .results_webhook_url
+        .clone()
+        .map(|url| Webhook::new(url, webhook_secret.clone(), config.results_webhook_retries));
+
+    let dead_letters =
+        DeadLetters::new(&config.dlq_path, config.dlq_file.as_deref(), clock.clone());
+
+    let alert_rate =
+        (config.alert_failure_rate > 0).then(|| config.alert_failure_rate as f64 / 100.0);
+    let stats = Arc::new(Mutex::new(Stats::new(
+        config.stats_window,
+        alert_rate,
+        clock.clone(),
+    )));
+    let gauges = Arc::new(Mutex::new(Gauges::new(clock.clone())));
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    let alerts = config
+        .alert_webhook_url
+        .clone()
+        .map(|url| Webhook::new(url, webhook_secret.clone(), config.results_webhook_retries));
+
+    if let Some(failover) = failover {
+        log!(
+            "failing over to {} after {} failed probes of {}, and back after {} healthy ones",
+            config.failover_transport.as_deref().unwrap_or_default(),
+            config.failover_after,
+            config.transport,
+            config.failback_after
+        );
+        tokio::spawn(failover::watch(
+            failover,
+            Duration::from_millis(config.failover_probe_interval_ms),
+            metrics.clone(),
+            alerts.clone(),
+            clock.clone(),
+        ));
+    }
+
+    let paused = Arc::new(Mutex::new(config.paused));
+    for (action, _) in registry()
+        .names()
+        .into_iter()
+        .zip(config.paused)
+        .filter(|(_, p)| *p)
+    {
+        log!(
+            "WARN: {} is paused; its rows go to the dead-letter directory",
+            action
+        );
+    }
+
+    let redirect = Arc::new(Mutex::new(config.redirect_to.clone()));
+    if let Some(to) = &config.redirect_to {
+        log!("WARN: redirecting every email to {}", to);
+    }
+
+    if config.allowlist.is_some() {
+        log!("only sending to recipients in MAILROOM_ALLOWLIST");
+    }
+
+    // Test rows injected through the admin endpoint. The sender is kept
+    // here so that the receiver stays open without an endpoint.
+    let (inject_tx, mut inject_rx) = tokio::sync::mpsc::channel(16);
+    // Requests to drain from the admin endpoint, kept open the same way.
+    let (drain_tx, mut drain_rx) = tokio::sync::mpsc::channel(1);
+
+    if let Some(addr) = config.admin_addr {
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => {
+                log!("admin endpoint listening on {}", addr);
+                let shared = admin::Shared {
+                    stats: stats.clone(),
+                    gauges: gauges.clone(),
+                    metrics: metrics.clone(),
+                    paused: paused.clone(),
+                    redirect: redirect.clone(),
+                    token: config.admin_token.clone(),
+                    inject: inject_tx.clone(),
+                    drain: drain_tx.clone(),
+                    clock: clock.clone(),
+                };
+                tokio::spawn(admin::serve(listener, shared));
+            }
+            Err(e) => {
+                log!("ERROR: failed to bind admin endpoint to {}: {}", addr, e);
+                status::exit(Exit::Failure);
+            }
+        }
+    }
+
+    let handoff_path = Path::new(&config.outdir).join(HANDOFF_FILE);
+
+    let open_journal = |name: &str, window_ms: u64| {
+        if window_ms == 0 {
+            return None;
+        }
+        let path = Path::new(&config.outdir).join(name);
+        let window = Duration::from_millis(window_ms);
+        match Seen::open(path.clone(), window, clock.clone()) {
+            Ok(seen) => Some(seen),
+            Err(e) => {
+                log!("ERROR: failed to open {}: {}", path.display(), e);
+                status::exit(Exit::Failure);
+            }
+        }
+    };
+    let seen = open_journal(SEEN_FILE, config.dedup_window_ms);
+    let receipts = open_journal(RECEIPTS_FILE, config.receipts_retention_ms);
+
+    let budget = (config.daily_budget > 0).then(|| {
+        let path = Path::new(&config.outdir).join(BUDGET_FILE);
+        Budget::open(path.clone(), config.daily_budget, clock.clone()).unwrap_or_else(|e| {
+            log!("ERROR: failed to open {}: {}", path.display(), e);
+            status::exit(Exit::Failure);
+        })
+    });
+
+    let samples = (config.samples_per_day > 0).then(|| {
+        Samples::new(
+            config.samples_path.clone().into(),
+            config.samples_per_day,
+            clock.clone(),
+        )
+    });
+
+    let ledger = config
+        .ledger_path
+        .as_ref()
+        .map(|path| Ledger::new(path, &config.transport, clock.clone()));
+
+    let suppressions = Suppressions::new(
+        config.suppression_file.as_deref(),
+        (config.ses_suppression_list && !config.dev_mode)
+            .then(|| aws_sdk_sesv2::Client::new(&ses_config)),
+        Duration::from_millis(config.suppression_cache_ttl_ms),
+        clock.clone(),
+    );
+
+    let limiter = match config.max_send_rate.as_str() {
+        _ if config.dev_mode => None,
+        "auto" => match client.get_send_quota().send().await {
+            Ok(quota) => {
+                log!(
+                    "SES maximum send rate is {} emails per second",
+                    quota.max_send_rate()
+                );
+                Some(quota.max_send_rate())
+            }
+            Err(e) => {
+                log!(
+                    "ERROR: failed to get the SES send quota: {}",
+                    aws_sdk_ses::error::DisplayErrorContext(e)
+                );
+                status::exit(Exit::Failure);
+            }
+        },
+        rate => rate.parse().ok(),
+    }
+    .filter(|rate| *rate > 0.0)
+    .map(|rate| RateLimiter::new(rate, clock.clone()));
+
+    let volume = Volume::new(
+        config.volume_factor as f64,
+        config.volume_min_rows,
+        clock.clone(),
+    );
+
+    let mut ctx = Context {
+        client,
+        mailer,
+        mailers,
+        config,
+        templates,
+        webhook,
+        backoff: Backoff::new(
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+            clock.clone(),
+        ),
+        limiter,
+        clock,
+        dead_letters,
+        stats,
+        alerts,
+        field_key,
+        seen,
+        receipts,
+        volume,
+        paused,
+        redirect,
+        samples,
+        budget,
+        ledger,
+        suppressions,
+        gauges,
+        metrics,
+        retries: Scheduler::new(),
+    };
+
+    let mut source = match (ctx.config.sqs_queue_url.clone(), database_url) {
+        (Some(queue_url), _) => source::Any::Sqs(source::sqs(sqs, queue_url)),
+        (None, Some(url)) => source::Any::Outbox(outbox::outbox(
+            url,
+            Duration::from_millis(ctx.config.outbox_lease_ms),
+            ctx.config.database_ca.clone(),
+        )),
+        _ => source::Any::Stdin(source::stdin()),
+    };
+    let mut input = Input::new(false);
+    let mut test_input = Input::new(true);
+    let mut pending = Vec::new();
+    match fs::read(&handoff_path) {
+        Ok(bytes) => {
+            log!(
+                "resuming {} bytes from {}",
+                bytes.len(),
+                handoff_path.display()
+            );
+            if let Err(e) = fs::remove_file(&handoff_path) {
+                log!("ERROR: failed to remove {}: {}", handoff_path.display(), e);
+                status::exit(Exit::Failure);
+            }
+            let lines = process(&mut input, &mut ctx, &bytes, &mut pending, false).await;
+            acknowledge(&mut source, lines);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            log!("ERROR: failed to read {}: {}", handoff_path.display(), e);
+            status::exit(Exit::Failure);
+        }
+    }
+
+    let (mut usr1, mut usr2, mut term, mut int) = match (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(usr1), Ok(usr2), Ok(term), Ok(int)) => (usr1, usr2, term, int),
+        (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+            log!("ERROR: failed to install signal handler: {}", e);
+            status::exit(Exit::Failure);
+        }
+    };
 
-    let mut parser = Parser::new();
-    let stdin = io::stdin();
-    let mut handle = stdin.lock();
     let mut buffer = [0; 8192];
 
+    // Set once SIGTERM or SIGINT is received; input is then consumed until
+    // the producer closes the pipe, the source ends it, or the deadline
+    // passes.
+    let mut drain: Option<Drain> = None;
+    let mut drain_status = tokio::time::interval(Duration::from_secs(1));
+    let batch_timeout = Duration::from_millis(ctx.config.batch_timeout_ms);
+    // Set once the input ended; the sender then only waits for parked
+    // retries.
+    let mut ended = false;
+
     loop {
-        match handle.read(&mut buffer) {
-            Ok(0) => {
-                log!("ERROR: end of input stream");
-                process::exit(1);
-            }
-            Ok(n) => {
-                for &byte in &buffer[..n] {
-                    if let Ok(ready) = parser.consume(byte) {
-                        if ready {
-                            parser
-                                .finalize(&client, &config_set_name, &from_email, &outdir, dev_mode)
-                                .await;
-                        }
-                    } else {
-                        log!("ERROR: failed to parse input");
-                        process::exit(1);
+        if ended && ctx.retries.is_empty() {
+            source.close().await;
+            if drain.is_some() && pending.is_empty() {
+                log!("drained");
+                status::exit(status::drained());
+            }
+            log!("ERROR: end of input stream");
+            status::exit(Exit::Failure);
+        }
+        let flush_at = input.flush_at(batch_timeout);
+        tokio::select! {
+            Some(row) = inject_rx.recv() => {
+                log!("processing injected test row");
+                let mut test_pending = Vec::new();
+                process(&mut test_input, &mut ctx, &row, &mut test_pending, false).await;
+            }
+            _ = usr1.recv() => {
+                let on = logging::toggle_debug();
+                eprintln!(
+                    "{} [SES] debug logging {}",
+                    logging::timestamp(),
+                    if on { "enabled" } else { "disabled" }
+                );
+            }
+            Some(retry) = ctx.retries.next(), if !ctx.retries.is_empty() => {
+                if retry.batch.test {
+                    test_input.resume(&mut ctx, retry).await;
+                } else {
+                    let lines = input.resume(&mut ctx, retry).await;
+                    acknowledge(&mut source, lines);
+                }
+            }
+            _ = usr2.recv() => {
+                // Lines waiting for retries and held lines go first, so that
+                // they are sent by the next sender; the rows of them already
+                // accepted were synced to the receipts journal after the
+                // attempt that sent them, and are skipped. SQS and the outbox
+                // deliver the lines not acknowledged again, so nothing read
+                // from them is handed off.
+                let mut unsent: Vec<u8> = Vec::new();
+                if let source::Any::Stdin(_) = source {
+                    unsent = input.in_flight().concat();
+                    unsent.extend(input.held.iter().flat_map(|line| &line.bytes));
+                    unsent.extend_from_slice(&pending);
+                }
+                source.close().await;
+                match write_handoff(&handoff_path, &unsent) {
+                    Ok(()) => {
+                        log!(
+                            "handing off; {} bytes written to {}",
+                            unsent.len(),
+                            handoff_path.display()
+                        );
+                        status::exit(Exit::Handoff);
+                    }
+                    Err(e) => {
+                        log!("ERROR: failed to write {}: {}", handoff_path.display(), e);
+                        status::exit(Exit::Failure);
                     }
                 }
             }
-            Err(e) => {
-                log!("ERROR: failed to read from stdin: {}", e);
-                process::exit(1);
+            Some(cause) = async {
+                tokio::select! {
+                    v = term.recv() => v.map(|()| "signal received"),
+                    v = int.recv() => v.map(|()| "signal received"),
+                    v = drain_rx.recv() => v.map(|()| "drain requested"),
+                }
+            }, if drain.is_none() =>
+            {
+                let timeout = Duration::from_millis(ctx.config.drain_timeout_ms);
+                log!("{}; draining input for up to {}ms", cause, ctx.config.drain_timeout_ms);
+                let now = ctx.clock.now();
+                drain = Some(Drain {
+                    deadline: now + timeout,
+                    started: now,
+                    settled: status::settled(),
+                });
+                source.drain();
+                drain_status.reset();
+                let lines = flush(&mut input, &mut ctx).await;
+                acknowledge(&mut source, lines);
+            }
+            _ = tokio::time::sleep_until(flush_at.map_or_else(tokio::time::Instant::now, tokio::time::Instant::from_std)),
+                if flush_at.is_some() =>
+            {
+                let lines = flush(&mut input, &mut ctx).await;
+                acknowledge(&mut source, lines);
+            }
+            _ = drain_status.tick(), if drain.is_some() => {
+                let Some(drain) = &drain else { continue };
+                if drain.deadline <= ctx.clock.now() {
+                    log!(
+                        "ERROR: drain deadline passed; {} bytes of input pending",
+                        pending.len()
+                    );
+                    source.close().await;
+                    status::exit(Exit::Failure);
+                }
+                report_drain(&ctx, &input, pending.len(), drain);
+            }
+            result = source.poll(&mut buffer), if !ended => match result {
+                Ok(0) => {
+                    let lines = flush(&mut input, &mut ctx).await;
+                    acknowledge(&mut source, lines);
+                    ended = true;
+                    if !ctx.retries.is_empty() {
+                        log!("input ended; waiting for {} parked retries", ctx.retries.len());
+                    }
+                }
+                Ok(n) => {
+                    let lines = process(&mut input, &mut ctx, &buffer[..n], &mut pending, true).await;
+                    acknowledge(&mut source, lines);
+                }
+                Err(e) => {
+                    log!("ERROR: failed to read input: {}", e);
+                    status::exit(Exit::Failure);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailroom_core::{escape, Registry};
+
+    // Values a field may hold that JSON must escape or carry as they are.
+    const VALUES: [&str; 6] = [
+        "say \"hi\"",
+        "C:\\Users\\jane\\",
+        "Zoë, 日本語 and 🚀",
+        "tab\tbell\u{7}nul\u{0}",
+        "line one\r\nline two",
+        "\\n is not a newline",
+    ];
+
+    fn names(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("field{}", i)).collect()
+    }
+
+    fn decode(data: &str) -> serde_json::Map<String, Value> {
+        match serde_json::from_str(data) {
+            Ok(Value::Object(map)) => map,
+            other => panic!("template data {:?} is not an object: {:?}", data, other),
+        }
+    }
+
+    #[test]
+    fn assigns_transports_by_their_shares() {
+        let (config, problems) = Config::load(Layers::new(
+            [
+                r#"--activation-transport-split={"ses":80,"postmark":20}"#,
+                "--postmark-server-token=t0ken",
+            ]
+            .map(String::from),
+        ));
+        assert!(problems.is_empty(), "{:?}", problems);
+        let action = registry().position("activation").unwrap();
+
+        let assigned: Vec<Option<usize>> = (0..1000)
+            .map(|n| assign_transport(&config, action, &format!("user{}@example.com", n)))
+            .collect();
+        let postmark = config.splits[action]
+            .iter()
+            .position(|(t, _)| t == "postmark");
+        let postmark = assigned.iter().filter(|t| **t == postmark).count();
+        assert!((150..250).contains(&postmark), "{} of 1000", postmark);
+        assert!(assigned.iter().all(Option::is_some));
+
+        // A recipient keeps its transport, however the address is cased.
+        assert_eq!(
+            assign_transport(&config, action, "User7@Example.com"),
+            assigned[7]
+        );
+
+        let other = (0..registry().len()).find(|&i| i != action).unwrap();
+        assert_eq!(assign_transport(&config, other, "user7@example.com"), None);
+    }
+
+    #[test]
+    fn template_data_escapes_what_json_requires() {
+        let names = names(VALUES.len());
+        let values = VALUES.iter().map(|v| v.to_string()).collect();
+        let data = template_data(serde_json::Map::new(), &names, values);
+
+        assert!(data.contains(r#""say \"hi\"""#));
+        assert!(data.contains(r#""C:\\Users\\jane\\""#));
+        assert!(data.contains(r#""tab\tbell\u0007nul\u0000""#));
+        assert!(data.contains(r#""line one\r\nline two""#));
+        // Unicode is carried as it is.
+        assert!(data.contains("Zoë, 日本語 and 🚀"));
+
+        let decoded = decode(&data);
+        for (name, value) in names.iter().zip(VALUES) {
+            assert_eq!(decoded[name], value);
+        }
+    }
+
+    #[test]
+    fn template_data_keeps_defaults_unless_a_field_replaces_them() {
+        let mut defaults = serde_json::Map::new();
+        defaults.insert(
+            "brand".to_string(),
+            Value::String("Acme \"Inc\"".to_string()),
+        );
+        defaults.insert("field0".to_string(), Value::String("default".to_string()));
+        let data = decode(&template_data(
+            defaults,
+            &names(1),
+            vec!["\u{1b}[31m".to_string()],
+        ));
+
+        assert_eq!(data["brand"], "Acme \"Inc\"");
+        assert_eq!(data["field0"], "\u{1b}[31m");
+    }
+
+    #[test]
+    fn fields_read_from_a_line_are_encoded_as_written() {
+        let registry = Registry::new(vec![mailroom_core::Action {
+            id: 1,
+            name: "notice".to_string(),
+            template: "noticev1".to_string(),
+            fields: names(3),
+        }])
+        .unwrap();
+        let mut parser = Parser::new(registry);
+        let mut line = String::new();
+        for values in VALUES.chunks(3) {
+            line.push_str("1,a@x.test");
+            for value in values {
+                line.push(',');
+                line.push_str(&escape(value));
+            }
+            line.push(',');
+        }
+        line.pop();
+        line.push('\n');
+        for &c in line.as_bytes() {
+            parser.consume(c).unwrap();
+        }
+
+        let batch = parser.batch();
+        assert_eq!(batch.rows(0), 2);
+        for (j, expected) in VALUES.chunks(3).enumerate() {
+            let values = (1..=3)
+                .map(|k| String::from_utf8_lossy(batch.field(0, j, k)).to_string())
+                .collect();
+            let data = decode(&template_data(serde_json::Map::new(), &names(3), values));
+            for (name, value) in names(3).iter().zip(expected) {
+                assert_eq!(data[name], *value);
             }
         }
     }
@@ -0,0 +1,194 @@
+use crate::delivery::Delivery;
+use mailroom_core::{registry, MAX_ACTIONS};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Upper bounds in seconds of the buckets of the send latency histogram.
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Latencies of the sends of one template, counted per bucket.
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+// Counters of everything the sender did since it started, served in the
+// Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    rows: [u64; MAX_ACTIONS],
+    batches: BTreeMap<String, u64>,
+    // By template, the provider's status and the outcome.
+    destinations: BTreeMap<(String, String, &'static str), u64>,
+    retries: BTreeMap<String, u64>,
+    latency: BTreeMap<String, Histogram>,
+    // Shifts of traffic by the provider shifted to, and whether traffic is
+    // on the failover one; unset without MAILROOM_FAILOVER_TRANSPORT.
+    failovers: BTreeMap<String, u64>,
+    failed_over: Option<bool>,
+}
+
+// Quotes a label value, escaping what the text format requires.
+fn label(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+impl Metrics {
+    // Records the rows of a line parsed, per action.
+    pub fn parsed(&mut self, action: usize, rows: usize) {
+        self.rows[action] += rows as u64;
+    }
+
+    // Records one request to the provider and its result for every
+    // destination in it.
+    pub fn sent(&mut self, template: &str, deliveries: &[Delivery], latency: Duration) {
+        *self.batches.entry(template.to_string()).or_default() += 1;
+        for delivery in deliveries {
+            let key = (
+                template.to_string(),
+                delivery.code.clone(),
+                delivery.outcome.as_str(),
+            );
+            *self.destinations.entry(key).or_default() += 1;
+        }
+
+        let seconds = latency.as_secs_f64();
+        let histogram = self.latency.entry(template.to_string()).or_default();
+        for (count, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    // Records destinations of a template parked to be sent again.
+    pub fn retried(&mut self, template: &str, destinations: usize) {
+        *self.retries.entry(template.to_string()).or_default() += destinations as u64;
+    }
+
+    // Records where traffic goes, and a shift of it to `to` when it moved.
+    pub fn failover(&mut self, to: Option<&str>, failed_over: bool) {
+        if let Some(to) = to {
+            *self.failovers.entry(to.to_string()).or_default() += 1;
+        }
+        self.failed_over = Some(failed_over);
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = Vec::new();
+
+        let name = "mailroom_rows_parsed_total";
+        header(&mut lines, name, "counter", "Input rows parsed.");
+        for (action, rows) in registry().names().into_iter().zip(self.rows) {
+            lines.push(format!("{}{{action={}}} {}", name, label(action), rows));
+        }
+
+        let name = "mailroom_batches_sent_total";
+        header(
+            &mut lines,
+            name,
+            "counter",
+            "Bulk requests sent to the provider.",
+        );
+        for (template, n) in &self.batches {
+            lines.push(format!("{}{{template={}}} {}", name, label(template), n));
+        }
+
+        let name = "mailroom_destinations_total";
+        header(
+            &mut lines,
+            name,
+            "counter",
+            "Destinations of the requests sent, by the provider's status and the outcome.",
+        );
+        for ((template, status, outcome), n) in &self.destinations {
+            lines.push(format!(
+                "{}{{template={},status={},outcome={}}} {}",
+                name,
+                label(template),
+                label(status),
+                label(outcome),
+                n
+            ));
+        }
+
+        let name = "mailroom_retries_total";
+        header(
+            &mut lines,
+            name,
+            "counter",
+            "Destinations sent again after failing transiently.",
+        );
+        for (template, n) in &self.retries {
+            lines.push(format!("{}{{template={}}} {}", name, label(template), n));
+        }
+
+        let name = "mailroom_send_duration_seconds";
+        header(
+            &mut lines,
+            name,
+            "histogram",
+            "Time the provider took to answer a bulk request.",
+        );
+        for (template, histogram) in &self.latency {
+            let template = label(template);
+            let bounds = LATENCY_BUCKETS.iter().map(f64::to_string);
+            let counts = histogram.buckets.iter();
+            for (bound, count) in bounds
+                .chain(["+Inf".to_string()])
+                .zip(counts.chain([&histogram.count]))
+            {
+                lines.push(format!(
+                    "{}_bucket{{template={},le=\"{}\"}} {}",
+                    name, template, bound, count
+                ));
+            }
+            lines.push(format!(
+                "{}_sum{{template={}}} {}",
+                name, template, histogram.sum
+            ));
+            lines.push(format!(
+                "{}_count{{template={}}} {}",
+                name, template, histogram.count
+            ));
+        }
+        if let Some(failed_over) = self.failed_over {
+            let name = "mailroom_failovers_total";
+            header(
+                &mut lines,
+                name,
+                "counter",
+                "Shifts of traffic between the primary and the failover provider.",
+            );
+            for (to, n) in &self.failovers {
+                lines.push(format!("{}{{to={}}} {}", name, label(to), n));
+            }
+
+            let name = "mailroom_failed_over";
+            header(
+                &mut lines,
+                name,
+                "gauge",
+                "Whether traffic goes to the failover provider.",
+            );
+            lines.push(format!("{} {}", name, u8::from(failed_over)));
+        }
+
+        lines.push(String::new());
+        lines.join("\n")
+    }
+}
+
+// Writes the HELP and TYPE lines of a metric.
+fn header(lines: &mut Vec<String>, name: &str, kind: &str, help: &str) {
+    lines.push(format!("# HELP {} {}", name, help));
+    lines.push(format!("# TYPE {} {}", name, kind));
+}
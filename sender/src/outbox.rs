@@ -0,0 +1,470 @@
+use crate::secrets::Secret;
+use crate::source::{Source, Units};
+use futures::StreamExt;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::ClientConfig;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_postgres::{AsyncMessage, Client};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+// Rows claimed per query.
+const OUTBOX_BATCH: i64 = 100;
+// How long to wait for a notification before looking for rows anyway, such
+// as ones whose lease expired.
+const OUTBOX_POLL: Duration = Duration::from_secs(5);
+const OUTBOX_RETRY: Duration = Duration::from_secs(5);
+
+// Claims pending rows, and rows whose lease expired because the sender that
+// claimed them stopped, oldest first. Rows locked by another sender are
+// skipped rather than waited for.
+const CLAIM: &str = "
+UPDATE outbox
+SET status = 'sending', claimed_at = EXTRACT(EPOCH FROM NOW()), attempts = attempts + 1
+WHERE id IN (
+    SELECT id FROM outbox
+    WHERE status = 'pending'
+       OR (status = 'sending' AND claimed_at < EXTRACT(EPOCH FROM NOW()) - $2::integer)
+    ORDER BY id
+    LIMIT $1
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING id, line";
+
+const SETTLE: &str = "
+UPDATE outbox
+SET status = $2::text::outbox_status, done_at = EXTRACT(EPOCH FROM NOW())
+WHERE id = ANY($1) AND status = 'sending'";
+
+// Reads lines from the rows of an `outbox` table, each holding one or more
+// lines, so that a producer can enqueue email in the same transaction as
+// the change that causes it. A task of its own holds the connection, claims
+// rows when asked to, woken up by notifications on the outbox_insert
+// channel, and marks rows sent or failed once all of their lines were
+// acknowledged, so that neither is cut short when the sender stops polling
+// to do something else. A row still claimed when its lease expires is
+// claimed again.
+pub struct Outbox {
+    // Keyed by row ID.
+    rows: Units<i64>,
+    requests: Option<mpsc::UnboundedSender<Request>>,
+    claimed: mpsc::UnboundedReceiver<Vec<(i64, String)>>,
+    task: JoinHandle<()>,
+    // Whether rows were asked for and not received yet.
+    claiming: bool,
+    draining: bool,
+}
+
+// What the sender asks of the outbox task.
+enum Request {
+    // Claim the next rows, answered once there are any, or with none once
+    // draining.
+    Claim,
+    // Write the status of settled rows, and whether one of their lines
+    // failed.
+    Settle(Vec<(i64, bool)>),
+    // Stop claiming rows.
+    Drain,
+}
+
+// How long writing the status of the last rows may take on exit.
+const OUTBOX_CLOSE: Duration = Duration::from_secs(10);
+
+pub fn outbox(url: Secret, lease: Duration, ca: Option<String>) -> Outbox {
+    let (requests, requests_rx) = mpsc::unbounded_channel();
+    let (claimed_tx, claimed) = mpsc::unbounded_channel();
+    let task = Task {
+        url,
+        lease,
+        ca,
+        connection: None,
+        requests: requests_rx,
+        claimed: claimed_tx,
+        settled: Vec::new(),
+        wanted: false,
+        draining: false,
+        closed: false,
+    };
+    Outbox {
+        rows: Units::new(),
+        requests: Some(requests),
+        claimed,
+        task: tokio::spawn(task.run()),
+        claiming: false,
+        draining: false,
+    }
+}
+
+// Uses TLS when the connection string asks for it with sslmode, verifying
+// the server against the certificates of the PEM bundle `ca`, such as the
+// RDS one, or else the Mozilla roots.
+fn tls(ca: Option<&str>) -> Result<MakeRustlsConnect, String> {
+    let mut roots = rustls::RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("cannot read {}: {}", path, e))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                return Err(format!("no certificates in {}", path));
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
+
+// The task holding the connection to the database.
+struct Task {
+    url: Secret,
+    lease: Duration,
+    ca: Option<String>,
+    connection: Option<(Client, mpsc::UnboundedReceiver<()>)>,
+    requests: mpsc::UnboundedReceiver<Request>,
+    claimed: mpsc::UnboundedSender<Vec<(i64, String)>>,
+    // Settled rows whose status is yet to be written.
+    settled: Vec<(i64, bool)>,
+    // Whether the sender waits for rows.
+    wanted: bool,
+    draining: bool,
+    // Whether the sender is exiting; the task ends once the status of every
+    // settled row was written.
+    closed: bool,
+}
+
+impl Task {
+    async fn run(mut self) {
+        loop {
+            let wait = match self.step().await {
+                Ok(()) => OUTBOX_POLL,
+                Err(e) => {
+                    log!("ERROR: outbox query failed: {}", e);
+                    self.connection = None;
+                    OUTBOX_RETRY
+                }
+            };
+            if self.closed && self.settled.is_empty() {
+                return;
+            }
+            self.wait(wait).await;
+        }
+    }
+
+    // Connects if needed, writes the status of settled rows and claims
+    // more if the sender waits for them.
+    async fn step(&mut self) -> Result<(), String> {
+        if self.wanted && self.draining {
+            self.wanted = false;
+            let _ = self.claimed.send(Vec::new());
+        }
+        if self.settled.is_empty() && !self.wanted {
+            return Ok(());
+        }
+        if self.connection.is_none() {
+            self.connect().await?;
+        }
+        self.settle().await?;
+        if self.wanted {
+            let rows = self.claim().await?;
+            if !rows.is_empty() {
+                self.wanted = false;
+                let _ = self.claimed.send(rows);
+            }
+        }
+        Ok(())
+    }
+
+    // Waits for a request or a notification, or until `timeout` passes.
+    async fn wait(&mut self, timeout: Duration) {
+        let notifications = self.connection.as_mut().map(|(_, n)| n);
+        let notified = async {
+            match notifications {
+                Some(notifications) => {
+                    let notified = notifications.recv().await;
+                    while notifications.try_recv().is_ok() {}
+                    notified
+                }
+                None => std::future::pending().await,
+            }
+        };
+        let request = tokio::select! {
+            request = self.requests.recv(), if !self.closed => request,
+            notification = notified => {
+                if notification.is_none() {
+                    self.connection = None;
+                }
+                return;
+            }
+            _ = tokio::time::sleep(timeout) => return,
+        };
+        match request {
+            Some(Request::Claim) => self.wanted = true,
+            Some(Request::Settle(rows)) => self.settled.extend(rows),
+            Some(Request::Drain) => self.draining = true,
+            None => {
+                self.closed = true;
+                self.draining = true;
+            }
+        }
+        // Takes the other requests already sent, so that they are dealt
+        // with in one step.
+        while let Ok(request) = self.requests.try_recv() {
+            match request {
+                Request::Claim => self.wanted = true,
+                Request::Settle(rows) => self.settled.extend(rows),
+                Request::Drain => self.draining = true,
+            }
+        }
+    }
+
+    // Connects and listens for new rows. Notifications are forwarded until
+    // the connection fails, which closes the channel.
+    async fn connect(&mut self) -> Result<(), String> {
+        let (client, mut connection) =
+            tokio_postgres::connect(&self.url.get(), tls(self.ca.as_deref())?)
+                .await
+                .map_err(|e| e.to_string())?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(AsyncMessage::Notification(_)) => {
+                        if tx.send(()).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log!("ERROR: outbox connection failed: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        client
+            .batch_execute("LISTEN outbox_insert")
+            .await
+            .map_err(|e| e.to_string())?;
+        log!("connected to the outbox");
+        self.connection = Some((client, rx));
+        Ok(())
+    }
+
+    // Writes the status of the settled rows. They are kept for the next
+    // attempt if that fails.
+    async fn settle(&mut self) -> Result<(), String> {
+        let Some((client, _)) = &self.connection else {
+            return Err("not connected".to_string());
+        };
+        for (status, failed) in [("sent", false), ("failed", true)] {
+            let ids: Vec<i64> = self
+                .settled
+                .iter()
+                .filter(|(_, f)| *f == failed)
+                .map(|(id, _)| *id)
+                .collect();
+            if !ids.is_empty() {
+                client
+                    .execute(SETTLE, &[&ids, &status])
+                    .await
+                    .map_err(|e| e.to_string())?;
+                self.settled.retain(|(_, f)| *f != failed);
+            }
+        }
+        Ok(())
+    }
+
+    // Claims the next rows, oldest first.
+    async fn claim(&mut self) -> Result<Vec<(i64, String)>, String> {
+        let Some((client, _)) = &self.connection else {
+            return Err("not connected".to_string());
+        };
+        let lease = i32::try_from(self.lease.as_secs()).unwrap_or(i32::MAX);
+        let mut rows: Vec<(i64, String)> = client
+            .query(CLAIM, &[&OUTBOX_BATCH, &lease])
+            .await
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        rows.sort_by_key(|(id, _)| *id);
+        Ok(rows)
+    }
+}
+
+impl Outbox {
+    // Hands the rows whose lines were all acknowledged to the task.
+    fn settle(&mut self) {
+        let settled = self.rows.take_settled();
+        if let (false, Some(requests)) = (settled.is_empty(), &self.requests) {
+            let _ = requests.send(Request::Settle(settled));
+        }
+    }
+}
+
+impl Source for Outbox {
+    async fn poll(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(n) = self.rows.read(buf) {
+                return Ok(n);
+            }
+            if !self.claiming {
+                let Some(requests) = self.requests.as_ref().filter(|_| !self.draining) else {
+                    return Ok(0);
+                };
+                let _ = requests.send(Request::Claim);
+                self.claiming = true;
+            }
+            // Cancel-safe: claimed rows stay in the channel until taken.
+            let Some(rows) = self.claimed.recv().await else {
+                return Err(io::Error::other("the outbox task stopped"));
+            };
+            self.claiming = false;
+            for (id, line) in rows {
+                self.rows.push(id, &line);
+            }
+            self.settle();
+        }
+    }
+
+    fn ack(&mut self, line: u64) {
+        self.rows.settle(line, false);
+        self.settle();
+    }
+
+    fn nack(&mut self, line: u64) {
+        self.rows.settle(line, true);
+        self.settle();
+    }
+
+    // Stops claiming rows. Rows already asked for are still read, so that
+    // none are left claimed.
+    fn drain(&mut self) {
+        self.draining = true;
+        if let Some(requests) = &self.requests {
+            let _ = requests.send(Request::Drain);
+        }
+    }
+
+    async fn close(&mut self) {
+        self.settle();
+        self.requests = None;
+        if tokio::time::timeout(OUTBOX_CLOSE, &mut self.task)
+            .await
+            .is_err()
+        {
+            // The rows are claimed again once their lease expires.
+            log!("ERROR: failed to write the status of the last outbox rows");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settles_a_row_once_all_of_its_lines_are_acknowledged() {
+        let mut rows = Units::new();
+        rows.push(41, "1,a@x.test,a,s,\n1,b@x.test,b,s,");
+        rows.push(42, "1,c@x.test,c,s,");
+        rows.push(43, "1,d@x.test,d,s,\n2,e@x.test,e,s,12345\n1,f@x.test,f,s,");
+        let mut buf = [0; 16];
+        while rows.read(&mut buf).is_some() {}
+
+        rows.settle(0, false);
+        rows.settle(2, false);
+        rows.settle(4, true);
+        rows.settle(3, false);
+        assert_eq!(rows.take_settled(), vec![(42, false)]);
+        rows.settle(1, false);
+        rows.settle(5, false);
+        assert_eq!(rows.take_settled(), vec![(41, false), (43, true)]);
+    }
+
+    // Claims and settles rows of an outbox in a schema of its own in the
+    // database at DATABASE_URL, if set.
+    #[tokio::test]
+    async fn claims_rows_and_writes_their_status() {
+        let Ok(url) = std::env::var("DATABASE_URL") else {
+            eprintln!("DATABASE_URL not set; skipping the outbox database test");
+            return;
+        };
+        let schema = format!("outbox_test_{}", std::process::id());
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        client
+            .batch_execute(&format!(
+                "CREATE SCHEMA {schema}; SET search_path TO {schema}; {}",
+                include_str!("../../migrations/1_outbox.up.sql")
+            ))
+            .await
+            .unwrap();
+        client
+            .batch_execute(
+                "INSERT INTO outbox (line) VALUES \
+                 (E'1,a@x.test,a,s,\\n1,b@x.test,b,s,'), ('1,c@x.test,c,s,'), ('1,d@x.test,d,s,')",
+            )
+            .await
+            .unwrap();
+
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{url}{separator}options=-csearch_path%3D{schema}");
+        let mut outbox = outbox(Secret::new(url), Duration::from_secs(60), None);
+        let mut read = Vec::new();
+        let mut buf = [0; 64];
+        while read.iter().filter(|&&b| b == b'\n').count() < 4 {
+            let n = tokio::time::timeout(Duration::from_secs(10), outbox.poll(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(
+            read,
+            b"1,a@x.test,a,s,\n1,b@x.test,b,s,\n1,c@x.test,c,s,\n1,d@x.test,d,s,\n"
+        );
+
+        // The last row is left claimed, to be claimed again once its lease
+        // expires.
+        outbox.ack(1);
+        outbox.nack(2);
+        outbox.ack(0);
+        outbox.close().await;
+
+        let statuses: Vec<(String, i32)> = client
+            .query("SELECT status::text, attempts FROM outbox ORDER BY id", &[])
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("sent".to_string(), 1),
+                ("failed".to_string(), 1),
+                ("sending".to_string(), 1)
+            ]
+        );
+
+        client
+            .batch_execute(&format!("DROP SCHEMA {schema} CASCADE"))
+            .await
+            .unwrap();
+    }
+}
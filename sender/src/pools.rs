@@ -0,0 +1,76 @@
+use crate::config::Config;
+use aws_sdk_sesv2::error::DisplayErrorContext;
+use aws_sdk_sesv2::Client;
+use mailroom_core::registry;
+use std::collections::HashMap;
+
+// Checks that the configuration sets of the actions given their own, or an
+// IP pool, exist and send from the IP pool expected of them, so that
+// transactional emails don't end up sharing the reputation of bulk ones.
+// Returns the problems found; a lookup that fails for another reason than
+// the configuration set missing is logged and not counted as one.
+pub async fn check(client: &Client, config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    // The IP pool of every configuration set looked up, None for the
+    // shared pool.
+    let mut pools: HashMap<&str, Option<String>> = HashMap::new();
+    for (i, action) in registry().iter().enumerate() {
+        if config.config_sets[i].is_none() && config.ip_pools[i].is_none() {
+            continue;
+        }
+        let set = config.config_set(i);
+        if !pools.contains_key(set) {
+            match client
+                .get_configuration_set()
+                .configuration_set_name(set)
+                .send()
+                .await
+            {
+                Ok(output) => {
+                    let pool = output
+                        .delivery_options()
+                        .and_then(|d| d.sending_pool_name())
+                        .map(str::to_string);
+                    pools.insert(set, pool);
+                }
+                Err(e)
+                    if e.as_service_error()
+                        .is_some_and(|e| e.is_not_found_exception()) =>
+                {
+                    problems.push(format!(
+                        "configuration set {} of {} does not exist",
+                        set, action.name
+                    ));
+                    continue;
+                }
+                Err(e) => {
+                    log!(
+                        "WARN: failed to look up configuration set {}: {}",
+                        set,
+                        DisplayErrorContext(&e)
+                    );
+                    continue;
+                }
+            }
+        }
+
+        let from = pools[set]
+            .as_ref()
+            .map_or("the shared IP pool".to_string(), |p| {
+                format!("IP pool {}", p)
+            });
+        match &config.ip_pools[i] {
+            Some(expected) if pools[set].as_ref() != Some(expected) => problems.push(format!(
+                "configuration set {} of {} sends from {}, not IP pool {}",
+                set, action.name, from, expected
+            )),
+            _ => log!(
+                "{} is sent with configuration set {} from {}",
+                action.name,
+                set,
+                from
+            ),
+        }
+    }
+    problems
+}
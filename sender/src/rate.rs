@@ -0,0 +1,49 @@
+use mailroom_core::clock::Clock;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Keeps sends under the maximum send rate of the SES account, as a token
+// bucket of one token per email that holds up to a second's worth of them.
+// A request for more emails than the bucket holds takes them anyway and
+// waits until the bucket would have refilled, so that batches are delayed
+// rather than refused.
+pub struct RateLimiter {
+    // Emails per second.
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, clock: Arc<dyn Clock>) -> Self {
+        let capacity = rate.max(1.0);
+        RateLimiter {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: clock.now(),
+            clock,
+        }
+    }
+
+    // Emails per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    // Takes the tokens of `emails` emails and returns how long to wait
+    // before sending them.
+    pub fn take(&mut self, emails: usize) -> Duration {
+        let now = self.clock.now();
+        let refilled = now.saturating_duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refilled).min(self.capacity) - emails as f64;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use aws_sdk_ses::error::DisplayErrorContext;
+use aws_sdk_ses::Client;
+use chrono::{DateTime, NaiveDate, Utc};
+use mailroom_core::clock::Clock;
+use mailroom_core::{registry, MAX_ACTIONS};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// Template fields whose values are replaced before a sample is rendered,
+// since they are credentials.
+const REDACTED_FIELDS: [&str; 2] = ["secret", "code"];
+const REDACTED: &str = "[redacted]";
+
+// Keeps the first emails of each template every day, rendered with their
+// credentials redacted, so that deliverability reviews can check what
+// customers received without archiving every email.
+pub struct Samples {
+    dir: PathBuf,
+    per_day: usize,
+    taken: [Option<(NaiveDate, usize)>; MAX_ACTIONS],
+    clock: Arc<dyn Clock>,
+}
+
+impl Samples {
+    pub fn new(dir: PathBuf, per_day: usize, clock: Arc<dyn Clock>) -> Self {
+        Samples {
+            dir,
+            per_day,
+            taken: [None; MAX_ACTIONS],
+            clock,
+        }
+    }
+
+    // Returns the path of the next sample of `action` if fewer than
+    // `per_day` were taken today. Samples already on disk count, so that a
+    // restart doesn't capture more.
+    pub fn take(&mut self, action: usize) -> Option<PathBuf> {
+        let today = DateTime::<Utc>::from(self.clock.system()).date_naive();
+        let dir = self.dir.join(&registry()[action].template);
+        let prefix = today.format("%Y-%m-%d-").to_string();
+
+        let taken = match self.taken[action] {
+            Some((date, n)) if date == today => n,
+            _ => fs::read_dir(&dir).map_or(0, |entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|e| e.file_name().to_string_lossy().starts_with(&prefix))
+                    .count()
+            }),
+        };
+        if taken >= self.per_day {
+            self.taken[action] = Some((today, taken));
+            return None;
+        }
+        self.taken[action] = Some((today, taken + 1));
+
+        Some(dir.join(format!("{}{}.eml", prefix, taken + 1)))
+    }
+}
+
+// Renders `template` with `data`, after redacting it, and writes the
+// result to `path`. The default template data is merged under `data` the
+// way SES does.
+pub async fn capture(
+    client: Client,
+    template: String,
+    default_data: String,
+    data: String,
+    path: PathBuf,
+) {
+    let mut merged: Map<String, Value> = serde_json::from_str(&default_data).unwrap_or_default();
+    merged.extend(serde_json::from_str::<Map<String, Value>>(&data).unwrap_or_default());
+    for name in REDACTED_FIELDS {
+        if let Some(value) = merged.get_mut(name) {
+            *value = Value::String(REDACTED.to_string());
+        }
+    }
+
+    let rendered = match client
+        .test_render_template()
+        .template_name(&template)
+        .template_data(Value::Object(merged).to_string())
+        .send()
+        .await
+    {
+        Ok(output) => output.rendered_template().unwrap_or_default().to_string(),
+        Err(e) => {
+            log!(
+                "WARN: failed to render sample of {}: {}",
+                template,
+                DisplayErrorContext(e)
+            );
+            return;
+        }
+    };
+
+    let written = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&path, rendered));
+    match written {
+        Ok(()) => log!("DEBUG: captured sample {}", path.display()),
+        Err(e) => log!("WARN: failed to write sample {}: {}", path.display(), e),
+    }
+}
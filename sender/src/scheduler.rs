@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::time::Duration;
+use tokio_util::time::delay_queue::{DelayQueue, Key};
+
+// Work deferred for a while, such as destinations waiting to be retried.
+// Entries share one timer wheel, so parking thousands of them costs neither
+// a task nor a timer each, and nothing is scanned to find the ones that are
+// due.
+pub struct Scheduler<T> {
+    queue: DelayQueue<()>,
+    // The parked items by their key in `queue`, so that they can be looked
+    // at while they wait.
+    items: HashMap<Key, T>,
+}
+
+impl<T> Scheduler<T> {
+    pub fn new() -> Self {
+        Scheduler {
+            queue: DelayQueue::new(),
+            items: HashMap::new(),
+        }
+    }
+
+    // Parks `item` until `delay` has passed.
+    pub fn defer(&mut self, item: T, delay: Duration) {
+        self.items.insert(self.queue.insert((), delay), item);
+    }
+
+    // Drops the parked items `keep` returns false for, and returns how many.
+    // The others stay due when they were.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) -> usize {
+        let before = self.items.len();
+        let queue = &mut self.queue;
+        self.items.retain(|key, item| {
+            let kept = keep(item);
+            if !kept {
+                queue.remove(key);
+            }
+            kept
+        });
+        before - self.items.len()
+    }
+
+    // The parked items, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // Waits for the next item that is due. Returns None at once when nothing
+    // is parked, so callers check `is_empty` first.
+    pub async fn next(&mut self) -> Option<T> {
+        let expired = poll_fn(|cx| self.queue.poll_expired(cx)).await?;
+        self.items.remove(&expired.key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn retain_drops_items_and_keeps_the_others_due() {
+        let mut scheduler = Scheduler::new();
+        scheduler.defer(1, Duration::from_millis(30));
+        scheduler.defer(2, Duration::ZERO);
+        scheduler.defer(3, Duration::from_millis(10));
+        scheduler.defer(4, Duration::ZERO);
+
+        assert_eq!(scheduler.retain(|&n| n % 2 == 1), 2);
+        assert_eq!(scheduler.len(), 2);
+        let mut left: Vec<_> = scheduler.iter().copied().collect();
+        left.sort();
+        assert_eq!(left, [1, 3]);
+        assert_eq!(scheduler.next().await, Some(3));
+        assert_eq!(scheduler.next().await, Some(1));
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.retain(|_| false), 0);
+    }
+}
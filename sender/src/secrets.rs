@@ -0,0 +1,121 @@
+use aws_sdk_secretsmanager::error::DisplayErrorContext;
+use aws_sdk_secretsmanager::Client;
+use serde_json::Value;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const PREFIX: &str = "secretsmanager:";
+const FILE_PREFIX: &str = "file:";
+
+// A secret value that may be replaced while the sender runs, when it is
+// rotated in Secrets Manager or in its file.
+#[derive(Clone)]
+pub struct Secret {
+    value: Arc<RwLock<String>>,
+}
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret {
+            value: Arc::new(RwLock::new(value)),
+        }
+    }
+
+    pub fn get(&self) -> String {
+        self.value.read().unwrap().clone()
+    }
+}
+
+// Whether a setting refers to a secret in Secrets Manager or a file rather
+// than holding the value itself.
+pub fn is_reference(value: &str) -> bool {
+    value.starts_with(PREFIX) || value.starts_with(FILE_PREFIX)
+}
+
+// Resolves settings of the form "secretsmanager:<secret-id>", or
+// "secretsmanager:<secret-id>#<key>" for one key of a JSON secret, and
+// "file:<path>" for the contents of a file, such as a mounted secret, and
+// keeps them up to date.
+pub struct Secrets {
+    client: Client,
+    resolved: Vec<(String, Secret)>,
+}
+
+impl Secrets {
+    pub fn new(client: Client) -> Self {
+        Secrets {
+            client,
+            resolved: Vec::new(),
+        }
+    }
+
+    async fn fetch(&self, reference: &str) -> Result<String, String> {
+        if let Some(path) = reference.strip_prefix(FILE_PREFIX) {
+            return fs::read_to_string(path)
+                .map(|contents| contents.trim_end().to_string())
+                .map_err(|e| format!("{}: {}", path, e));
+        }
+        let reference = reference.strip_prefix(PREFIX).unwrap_or(reference);
+        let (id, key) = match reference.split_once('#') {
+            Some((id, key)) => (id, Some(key)),
+            None => (reference, None),
+        };
+        let output = self
+            .client
+            .get_secret_value()
+            .secret_id(id)
+            .send()
+            .await
+            .map_err(|e| format!("{}: {}", id, DisplayErrorContext(e)))?;
+        let secret = output
+            .secret_string()
+            .ok_or_else(|| format!("{}: secret has no string value", id))?;
+
+        match key {
+            None => Ok(secret.to_string()),
+            Some(key) => {
+                let json: Value = serde_json::from_str(secret)
+                    .map_err(|e| format!("{}: secret is not a JSON object: {}", id, e))?;
+                json[key]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("{}: secret has no string key {:?}", id, key))
+            }
+        }
+    }
+
+    // Returns the secret a setting refers to, or the setting itself when it
+    // isn't a reference.
+    pub async fn resolve(&mut self, value: &str) -> Result<Secret, String> {
+        if !is_reference(value) {
+            return Ok(Secret::new(value.to_string()));
+        }
+        let secret = Secret::new(self.fetch(value).await?);
+        self.resolved.push((value.to_string(), secret.clone()));
+        Ok(secret)
+    }
+
+    // Re-fetches every resolved secret every `interval`. A secret that
+    // can't be fetched keeps its last value.
+    pub async fn refresh(self, interval: Duration) {
+        if self.resolved.is_empty() {
+            return;
+        }
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            for (reference, secret) in &self.resolved {
+                match self.fetch(reference).await {
+                    Ok(value) if value != secret.get() => {
+                        *secret.value.write().unwrap() = value;
+                        log!("secret {} rotated", reference);
+                    }
+                    Ok(_) => {}
+                    Err(e) => log!("WARN: failed to refresh secret: {}", e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use crate::outbox::Outbox;
+use aws_sdk_sqs::types::DeleteMessageBatchRequestEntry;
+use aws_sdk_sqs::Client;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::io;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Where input comes from: a stream of lines in the collector's format. A
+// source is told about every line once it has been processed, so that one
+// able to deliver input again knows what it may drop and what to keep.
+pub trait Source {
+    // Reads the next chunk of input into `buf`, returning 0 at the end of
+    // the input. A chunk may end in the middle of a line.
+    async fn poll(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    // Called once every row of line number `line` was sent or otherwise
+    // dealt with. Lines are numbered from 0 in the order `poll` returned
+    // them, and may be acknowledged in any order, since a line whose rows
+    // wait for a retry settles after the lines read after it.
+    fn ack(&mut self, _line: u64) {}
+
+    // Called when rows of line number `line` failed to send; they are kept
+    // in the dead-letter directory.
+    fn nack(&mut self, _line: u64) {}
+
+    // Called when the sender starts draining. A source that keeps the input
+    // it hasn't delivered yet can end the input here instead of waiting for
+    // the producer.
+    fn drain(&mut self) {}
+
+    // Called before the sender exits, once no more lines are acknowledged,
+    // to settle those that were.
+    async fn close(&mut self) {}
+}
+
+// Reads the collector's output from stdin. A pipe can't deliver a line
+// twice, so acknowledgements are ignored.
+pub struct Stdin {
+    stdin: tokio::io::Stdin,
+}
+
+pub fn stdin() -> Stdin {
+    Stdin {
+        stdin: tokio::io::stdin(),
+    }
+}
+
+impl Source for Stdin {
+    async fn poll(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdin.read(buf).await
+    }
+}
+
+// Input received in units of one or more lines, such as queue messages or
+// table rows, which are settled once all of their lines were acknowledged.
+pub struct Units<K> {
+    // Received input not yet returned by `poll`.
+    buffered: VecDeque<u8>,
+    // Number of the next line queued.
+    next_line: u64,
+    // The unit of every line queued and not acknowledged yet, by number.
+    lines: HashMap<u64, K>,
+    // Lines of each unit still to be acknowledged, and whether one of them
+    // failed.
+    open: HashMap<K, (usize, bool)>,
+    // Units whose lines were all acknowledged, and whether one failed.
+    settled: Vec<(K, bool)>,
+}
+
+impl<K: Clone + Eq + Hash> Units<K> {
+    pub fn new() -> Self {
+        Units {
+            buffered: VecDeque::new(),
+            next_line: 0,
+            lines: HashMap::new(),
+            open: HashMap::new(),
+            settled: Vec::new(),
+        }
+    }
+
+    // Queues the lines of `body`, which need not end with a newline. A
+    // unit without any is settled right away.
+    pub fn push(&mut self, key: K, body: &str) {
+        let body = body.trim_end_matches('\n');
+        if body.is_empty() {
+            self.settled.push((key, false));
+            return;
+        }
+        let mut count = 0;
+        for line in body.split('\n') {
+            let mut line = line.as_bytes().to_vec();
+            line.push(b'\n');
+            self.buffered.extend(&line);
+            self.lines.insert(self.next_line, key.clone());
+            self.next_line += 1;
+            count += 1;
+        }
+        self.open.insert(key, (count, false));
+    }
+
+    // Copies queued input into `buf`, if there is any.
+    pub fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.buffered.is_empty() {
+            return None;
+        }
+        let n = buf.len().min(self.buffered.len());
+        for (to, from) in buf.iter_mut().zip(self.buffered.drain(..n)) {
+            *to = from;
+        }
+        Some(n)
+    }
+
+    // Records the outcome of line number `line`, counted from the first
+    // line queued.
+    pub fn settle(&mut self, line: u64, failed: bool) {
+        let Some(key) = self.lines.remove(&line) else {
+            return;
+        };
+        let Some(unit) = self.open.get_mut(&key) else {
+            return;
+        };
+        unit.0 -= 1;
+        unit.1 |= failed;
+        if unit.0 == 0 {
+            let failed = unit.1;
+            self.open.remove(&key);
+            self.settled.push((key, failed));
+        }
+    }
+
+    // Takes the units settled so far, and whether one of their lines failed.
+    pub fn take_settled(&mut self) -> Vec<(K, bool)> {
+        std::mem::take(&mut self.settled)
+    }
+}
+
+// Receives lines from an SQS queue, each message holding one or more lines.
+// Messages are received by a task of their own, so that nothing received is
+// lost when the sender stops polling to do something else, and deleted by
+// another once all of their lines were acknowledged. A message with a line
+// that failed is left on the queue, to be received again after its
+// visibility timeout and moved to the queue's dead-letter queue by its
+// redrive policy if it keeps failing; the receipts journal keeps the rows
+// that were sent from being sent again.
+pub struct Sqs {
+    // Keyed by receipt handle.
+    messages: Units<String>,
+    received: mpsc::Receiver<Vec<(String, String)>>,
+    receiver: JoinHandle<()>,
+    // Receipt handles of the messages to delete.
+    delete: Option<mpsc::UnboundedSender<String>>,
+    deleter: JoinHandle<()>,
+    draining: bool,
+}
+
+// Messages received and deleted per request; the SQS maximum.
+const SQS_BATCH: i32 = 10;
+const SQS_WAIT: i32 = 20;
+const SQS_RETRY: Duration = Duration::from_secs(5);
+// How long deleting the last messages may take on exit.
+const SQS_CLOSE: Duration = Duration::from_secs(10);
+
+pub fn sqs(client: Client, queue_url: String) -> Sqs {
+    // A single batch is received ahead, so that messages don't wait out
+    // their visibility timeout in the channel.
+    let (received_tx, received) = mpsc::channel(1);
+    let (delete, delete_rx) = mpsc::unbounded_channel();
+    Sqs {
+        messages: Units::new(),
+        received,
+        receiver: tokio::spawn(receive(client.clone(), queue_url.clone(), received_tx)),
+        delete: Some(delete),
+        deleter: tokio::spawn(delete_messages(client, queue_url, delete_rx)),
+        draining: false,
+    }
+}
+
+// Receives messages until the channel closes, as their bodies and receipt
+// handles.
+async fn receive(client: Client, queue_url: String, tx: mpsc::Sender<Vec<(String, String)>>) {
+    loop {
+        let output = match client
+            .receive_message()
+            .queue_url(&queue_url)
+            .max_number_of_messages(SQS_BATCH)
+            .wait_time_seconds(SQS_WAIT)
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(e) => {
+                log!(
+                    "ERROR: failed to receive from SQS: {}",
+                    aws_sdk_sqs::Error::from(e)
+                );
+                tokio::time::sleep(SQS_RETRY).await;
+                continue;
+            }
+        };
+        let messages: Vec<(String, String)> = output
+            .messages()
+            .iter()
+            .filter_map(|m| Some((m.body()?.to_string(), m.receipt_handle()?.to_string())))
+            .collect();
+        if !messages.is_empty() && tx.send(messages).await.is_err() {
+            return;
+        }
+    }
+}
+
+// Deletes the messages whose receipt handles are sent, until the channel
+// closes. A handle is kept until the request deleting it succeeds; one SQS
+// rejects is logged and dropped, since its message is received again later
+// and the receipts journal keeps its rows from being sent twice.
+async fn delete_messages(
+    client: Client,
+    queue_url: String,
+    mut rx: mpsc::UnboundedReceiver<String>,
+) {
+    let mut batch: Vec<String> = Vec::new();
+    loop {
+        if batch.is_empty() {
+            match rx.recv().await {
+                Some(receipt) => batch.push(receipt),
+                None => return,
+            }
+        }
+        while batch.len() < SQS_BATCH as usize {
+            match rx.try_recv() {
+                Ok(receipt) => batch.push(receipt),
+                Err(_) => break,
+            }
+        }
+
+        let mut request = client.delete_message_batch().queue_url(&queue_url);
+        for (i, receipt) in batch.iter().enumerate() {
+            match DeleteMessageBatchRequestEntry::builder()
+                .id(i.to_string())
+                .receipt_handle(receipt)
+                .build()
+            {
+                Ok(entry) => request = request.entries(entry),
+                Err(e) => log!("ERROR: failed to delete SQS message: {}", e),
+            }
+        }
+        match request.send().await {
+            Ok(output) => {
+                for failed in output.failed() {
+                    log!(
+                        "ERROR: failed to delete SQS message: {}: {}",
+                        failed.code(),
+                        failed.message().unwrap_or_default()
+                    );
+                }
+                batch.clear();
+            }
+            Err(e) => {
+                log!(
+                    "ERROR: failed to delete {} SQS messages: {}",
+                    batch.len(),
+                    aws_sdk_sqs::Error::from(e)
+                );
+                tokio::time::sleep(SQS_RETRY).await;
+            }
+        }
+    }
+}
+
+impl Sqs {
+    // Hands the messages whose lines were all sent to the deleting task.
+    fn delete(&mut self) {
+        let Some(delete) = &self.delete else {
+            return;
+        };
+        for (receipt, failed) in self.messages.take_settled() {
+            if failed {
+                log!("WARN: leaving an SQS message with rows that failed to send on the queue");
+                continue;
+            }
+            let _ = delete.send(receipt);
+        }
+    }
+}
+
+impl Source for Sqs {
+    async fn poll(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(n) = self.messages.read(buf) {
+                return Ok(n);
+            }
+            if self.draining {
+                return Ok(0);
+            }
+            // Cancel-safe: a batch stays in the channel until taken.
+            match self.received.recv().await {
+                Some(messages) => {
+                    for (body, receipt) in messages {
+                        self.messages.push(receipt, &body);
+                    }
+                    self.delete();
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+
+    fn ack(&mut self, line: u64) {
+        self.messages.settle(line, false);
+        self.delete();
+    }
+
+    fn nack(&mut self, line: u64) {
+        self.messages.settle(line, true);
+        self.delete();
+    }
+
+    // Stops receiving; messages received but not read yet become visible
+    // again after their visibility timeout.
+    fn drain(&mut self) {
+        self.draining = true;
+        self.receiver.abort();
+    }
+
+    async fn close(&mut self) {
+        self.receiver.abort();
+        self.delete();
+        self.delete = None;
+        if tokio::time::timeout(SQS_CLOSE, &mut self.deleter)
+            .await
+            .is_err()
+        {
+            log!("ERROR: failed to delete the last SQS messages; they are received again");
+        }
+    }
+}
+
+// The source chosen by MAILROOM_SOURCE.
+pub enum Any {
+    Stdin(Stdin),
+    Sqs(Sqs),
+    Outbox(Outbox),
+}
+
+impl Source for Any {
+    async fn poll(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Any::Stdin(source) => source.poll(buf).await,
+            Any::Sqs(source) => source.poll(buf).await,
+            Any::Outbox(source) => source.poll(buf).await,
+        }
+    }
+
+    fn ack(&mut self, line: u64) {
+        match self {
+            Any::Stdin(source) => source.ack(line),
+            Any::Sqs(source) => source.ack(line),
+            Any::Outbox(source) => source.ack(line),
+        }
+    }
+
+    fn nack(&mut self, line: u64) {
+        match self {
+            Any::Stdin(source) => source.nack(line),
+            Any::Sqs(source) => source.nack(line),
+            Any::Outbox(source) => source.nack(line),
+        }
+    }
+
+    fn drain(&mut self) {
+        match self {
+            Any::Stdin(source) => source.drain(),
+            Any::Sqs(source) => source.drain(),
+            Any::Outbox(source) => source.drain(),
+        }
+    }
+
+    async fn close(&mut self) {
+        match self {
+            Any::Stdin(source) => source.close().await,
+            Any::Sqs(source) => source.close().await,
+            Any::Outbox(source) => source.close().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads everything queued, as `poll` would.
+    fn read_all(units: &mut Units<&'static str>) -> Vec<u8> {
+        let mut buf = [0; 4];
+        let mut read = Vec::new();
+        while let Some(n) = units.read(&mut buf) {
+            read.extend_from_slice(&buf[..n]);
+        }
+        read
+    }
+
+    #[test]
+    fn settles_a_unit_once_all_of_its_lines_are_acknowledged() {
+        let mut units = Units::new();
+        units.push("a", "1,a@x.test,a,s,\n1,b@x.test,b,s,\n");
+        units.push("b", "1,c@x.test,c,s,");
+        assert_eq!(
+            read_all(&mut units),
+            b"1,a@x.test,a,s,\n1,b@x.test,b,s,\n1,c@x.test,c,s,\n"
+        );
+
+        units.settle(0, false);
+        assert!(units.take_settled().is_empty());
+        units.settle(1, false);
+        units.settle(2, false);
+        assert_eq!(units.take_settled(), vec![("a", false), ("b", false)]);
+    }
+
+    #[test]
+    fn settles_lines_acknowledged_out_of_order_after_parked_retries() {
+        let mut units = Units::new();
+        units.push("a", "1,a@x.test,a,s,");
+        units.push("b", "1,b@x.test,b,s,");
+        units.push("c", "1,c@x.test,c,s,\n1,d@x.test,d,s,");
+        read_all(&mut units);
+
+        // Line 0 waits for a retry while the lines after it are sent.
+        units.settle(1, false);
+        units.settle(3, false);
+        assert_eq!(units.take_settled(), vec![("b", false)]);
+        units.settle(2, true);
+        assert_eq!(units.take_settled(), vec![("c", true)]);
+        units.settle(0, false);
+        assert_eq!(units.take_settled(), vec![("a", false)]);
+    }
+
+    #[test]
+    fn ignores_lines_that_did_not_come_from_a_unit() {
+        let mut units = Units::new();
+        units.push("a", "1,a@x.test,a,s,");
+        units.push("b", "");
+        assert_eq!(units.take_settled(), vec![("b", false)]);
+        read_all(&mut units);
+
+        units.settle(7, false);
+        units.settle(0, false);
+        units.settle(0, true);
+        assert_eq!(units.take_settled(), vec![("a", false)]);
+    }
+
+    #[tokio::test]
+    async fn deletes_only_the_sqs_messages_whose_lines_were_all_sent() {
+        let (received_tx, received) = mpsc::channel(1);
+        let (delete, mut deleted) = mpsc::unbounded_channel();
+        let mut sqs = Sqs {
+            messages: Units::new(),
+            received,
+            receiver: tokio::spawn(async {}),
+            delete: Some(delete),
+            deleter: tokio::spawn(async {}),
+            draining: false,
+        };
+        received_tx
+            .send(vec![
+                ("1,a@x.test,a,s,".to_string(), "a".to_string()),
+                (
+                    "1,b@x.test,b,s,\n1,c@x.test,c,s,".to_string(),
+                    "b".to_string(),
+                ),
+            ])
+            .await
+            .unwrap();
+        let mut buf = [0; 64];
+        assert!(sqs.poll(&mut buf).await.unwrap() > 0);
+
+        sqs.ack(0);
+        sqs.nack(1);
+        sqs.ack(2);
+        assert_eq!(deleted.try_recv().ok(), Some("a".to_string()));
+        assert!(deleted.try_recv().is_err());
+    }
+}
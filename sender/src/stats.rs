@@ -0,0 +1,100 @@
+use chrono::{DateTime, Utc};
+use mailroom_core::clock::Clock;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+// Outcomes of the most recent destinations of one template.
+#[derive(Default)]
+struct Window {
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    sent_total: u64,
+    failed_total: u64,
+    alerting: bool,
+}
+
+// Rolling success and failure rates per template, over the last `window`
+// destinations of each.
+pub struct Stats {
+    window: usize,
+    alert_rate: Option<f64>,
+    templates: HashMap<String, Window>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Stats {
+    pub fn new(window: usize, alert_rate: Option<f64>, clock: Arc<dyn Clock>) -> Self {
+        Stats {
+            window,
+            alert_rate,
+            templates: HashMap::new(),
+            clock,
+        }
+    }
+
+    // Records the outcome of one bulk send. Returns an alert payload when the
+    // template's failure rate has just risen above the alert threshold.
+    pub fn record(&mut self, template: &str, sent: usize, failed: usize) -> Option<Value> {
+        let w = self.templates.entry(template.to_string()).or_default();
+        w.sent_total += sent as u64;
+        w.failed_total += failed as u64;
+
+        let outcomes = std::iter::repeat_n(false, sent).chain(std::iter::repeat_n(true, failed));
+        for failed in outcomes {
+            w.outcomes.push_back(failed);
+            w.failures += failed as usize;
+            if w.outcomes.len() > self.window && w.outcomes.pop_front() == Some(true) {
+                w.failures -= 1;
+            }
+        }
+
+        let threshold = self.alert_rate?;
+        // Rates over a partly filled window are too noisy to alert on.
+        if w.outcomes.len() < self.window {
+            return None;
+        }
+        let rate = w.failures as f64 / w.outcomes.len() as f64;
+        let was_alerting = w.alerting;
+        w.alerting = rate > threshold;
+        if !w.alerting || was_alerting {
+            return None;
+        }
+
+        Some(json!({
+            "type": "failure_rate",
+            "template": template,
+            "failure_rate": rate,
+            "threshold": threshold,
+            "window": self.window,
+            "timestamp": DateTime::<Utc>::from(self.clock.system()).to_rfc3339(),
+        }))
+    }
+
+    pub fn to_json(&self) -> Value {
+        let templates: Map<String, Value> = self
+            .templates
+            .iter()
+            .map(|(name, w)| {
+                let n = w.outcomes.len();
+                let failure_rate = if n == 0 {
+                    0.0
+                } else {
+                    w.failures as f64 / n as f64
+                };
+                (
+                    name.clone(),
+                    json!({
+                        "sent_total": w.sent_total,
+                        "failed_total": w.failed_total,
+                        "window": n,
+                        "success_rate": if n == 0 { 0.0 } else { 1.0 - failure_rate },
+                        "failure_rate": failure_rate,
+                        "alerting": w.alerting,
+                    }),
+                )
+            })
+            .collect();
+        json!({ "templates": templates })
+    }
+}
@@ -0,0 +1,170 @@
+use crate::Summary;
+use chrono::{DateTime, Utc};
+use mailroom_core::clock::Clock;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::Instant;
+
+// How the sender ended. The exit codes are part of its interface, so that
+// wrapper scripts can tell outcomes apart without reading the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    // Drained after SIGTERM or SIGINT with every line sent, or a command
+    // succeeded.
+    Success,
+    // Anything else that stopped the sender: an unreadable input or journal,
+    // a drain deadline that passed, a failed canary.
+    Failure,
+    // The configuration is invalid, or doesn't match the SES account.
+    Config,
+    // Pending input was handed off on SIGUSR2.
+    Handoff,
+    // AWS credentials or a secret could not be loaded.
+    Credentials,
+    // A line could not be parsed.
+    ParseAbort,
+    // Drained, but the rows of some lines failed to send.
+    DrainedWithFailures,
+}
+
+impl Exit {
+    pub fn code(self) -> i32 {
+        match self {
+            Exit::Success => 0,
+            Exit::Failure => 1,
+            Exit::Config => 2,
+            Exit::Handoff => 3,
+            Exit::Credentials => 4,
+            Exit::ParseAbort => 5,
+            Exit::DrainedWithFailures => 6,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Exit::Success => "success",
+            Exit::Failure => "failure",
+            Exit::Config => "config_error",
+            Exit::Handoff => "handoff",
+            Exit::Credentials => "credentials_error",
+            Exit::ParseAbort => "parse_abort",
+            Exit::DrainedWithFailures => "drained_with_failures",
+        }
+    }
+}
+
+// Counts over the whole run, for the report written on exit.
+#[derive(Default)]
+struct Totals {
+    lines: usize,
+    // Lines with rows that failed to send or were diverted.
+    incomplete: usize,
+    duplicates: usize,
+    // Malformed rows skipped with MAILROOM_PARSE_ERRORS=skip-row.
+    skipped_rows: usize,
+    rows: usize,
+    sent: usize,
+    failed: usize,
+    diverted: usize,
+    filtered: usize,
+    expired: usize,
+    suppressed: usize,
+    retried: usize,
+    throttled: usize,
+}
+
+static REPORT: AtomicBool = AtomicBool::new(false);
+// The clock the report reads the time from, and when the sender started.
+static STARTED: OnceLock<(Arc<dyn Clock>, Instant)> = OnceLock::new();
+static TOTALS: LazyLock<Mutex<Totals>> = LazyLock::new(Default::default);
+
+// Writes the report on exit from now on. Commands that print to stdout
+// themselves don't.
+pub fn enable(clock: Arc<dyn Clock>) {
+    STARTED.get_or_init(|| {
+        let now = clock.now();
+        (clock, now)
+    });
+    REPORT.store(true, Ordering::Relaxed);
+}
+
+// Adds the counts of a batch summary.
+pub fn record(summary: &Summary) {
+    let mut totals = TOTALS.lock().unwrap();
+    totals.rows += summary.rows;
+    totals.sent += summary.sent.iter().sum::<usize>();
+    totals.failed += summary.failed.iter().sum::<usize>();
+    totals.diverted += summary.diverted.iter().sum::<usize>();
+    totals.filtered += summary.filtered.iter().sum::<usize>();
+    totals.expired += summary.expired.iter().sum::<usize>();
+    totals.suppressed += summary.suppressed.iter().sum::<usize>();
+    totals.retried += summary.retried;
+    totals.throttled += summary.throttled;
+}
+
+// Records a processed line, and whether all of its rows went out.
+pub fn line(complete: bool) {
+    let mut totals = TOTALS.lock().unwrap();
+    totals.lines += 1;
+    totals.incomplete += !complete as usize;
+}
+
+pub fn duplicate() {
+    TOTALS.lock().unwrap().duplicates += 1;
+}
+
+pub fn skipped_row() {
+    TOTALS.lock().unwrap().skipped_rows += 1;
+}
+
+// Rows settled so far, whichever way they went.
+pub fn settled() -> usize {
+    let totals = TOTALS.lock().unwrap();
+    totals.sent
+        + totals.failed
+        + totals.diverted
+        + totals.filtered
+        + totals.expired
+        + totals.suppressed
+}
+
+// The outcome of a drain: whether any line had rows that didn't go out.
+pub fn drained() -> Exit {
+    if TOTALS.lock().unwrap().incomplete > 0 {
+        Exit::DrainedWithFailures
+    } else {
+        Exit::Success
+    }
+}
+
+// Exits with the code of `exit`, after writing the report as a JSON record
+// to stdout if enabled.
+pub fn exit(exit: Exit) -> ! {
+    if let (true, Some((clock, started))) = (REPORT.load(Ordering::Relaxed), STARTED.get()) {
+        let totals = TOTALS.lock().unwrap();
+        let record = serde_json::json!({
+            "type": "exit_report",
+            "timestamp": DateTime::<Utc>::from(clock.system()).to_rfc3339(),
+            "status": exit.as_str(),
+            "exit_code": exit.code(),
+            "uptime_ms": clock.now().duration_since(*started).as_millis() as u64,
+            "lines": totals.lines,
+            "incomplete_lines": totals.incomplete,
+            "duplicate_lines": totals.duplicates,
+            "skipped_rows": totals.skipped_rows,
+            "rows": totals.rows,
+            "sent": totals.sent,
+            "failed": totals.failed,
+            "diverted": totals.diverted,
+            "filtered": totals.filtered,
+            "expired": totals.expired,
+            "suppressed": totals.suppressed,
+            "retried": totals.retried,
+            "throttled": totals.throttled,
+        });
+        println!("{}", record);
+        log!("exiting; status={} code={}", exit.as_str(), exit.code());
+    }
+    process::exit(exit.code())
+}
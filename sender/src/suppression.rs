@@ -0,0 +1,199 @@
+use aws_sdk_sesv2::error::DisplayErrorContext;
+use aws_sdk_sesv2::Client;
+use futures::StreamExt;
+use mailroom_core::clock::Clock;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+// Lookups of the SES suppression list made at once.
+const CONCURRENT_LOOKUPS: usize = 8;
+
+// Recipients that are not sent to, because their address can't be
+// delivered to or already bounced or complained, so that they don't count
+// against the account's bounce and complaint rates.
+pub struct Suppressions {
+    // A file of addresses and domains, one per line, reloaded when it
+    // changes.
+    file: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    addresses: HashSet<String>,
+    domains: HashSet<String>,
+    // The account-level suppression list of SES, with the reason of each
+    // address looked up, or None if it isn't listed, and when.
+    ses: Option<Client>,
+    ttl: Duration,
+    cache: HashMap<String, (Option<String>, Instant)>,
+    clock: Arc<dyn Clock>,
+}
+
+// Whether `to` is an address SES can send to: a local part of up to 64
+// characters, and a domain of dot-separated labels of letters, digits and
+// hyphens, without a leading or trailing hyphen.
+pub fn is_valid_recipient(to: &str) -> bool {
+    let Some((local, domain)) = to.rsplit_once('@') else {
+        return false;
+    };
+    let label = |l: &str| {
+        !l.is_empty()
+            && l.len() <= 63
+            && !l.starts_with('-')
+            && !l.ends_with('-')
+            && l.chars().all(|c| c.is_alphanumeric() || c == '-')
+    };
+    to.len() <= 254
+        && !local.is_empty()
+        && local.len() <= 64
+        && !local.contains(|c: char| c.is_whitespace() || c.is_control() || c == '@')
+        && domain.contains('.')
+        && domain.split('.').all(label)
+}
+
+impl Suppressions {
+    pub fn new(
+        file: Option<&str>,
+        ses: Option<Client>,
+        ttl: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let mut suppressions = Suppressions {
+            file: file.map(PathBuf::from),
+            modified: None,
+            addresses: HashSet::new(),
+            domains: HashSet::new(),
+            ses,
+            ttl,
+            cache: HashMap::new(),
+            clock,
+        };
+        suppressions.reload();
+        suppressions
+    }
+
+    // Reads the file again if it was modified since it was last read. A
+    // file that can't be read keeps the entries read before.
+    fn reload(&mut self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == self.modified {
+            return;
+        }
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                log!("WARN: failed to read {}: {}", path.display(), e);
+                return;
+            }
+        };
+        self.addresses.clear();
+        self.domains.clear();
+        for entry in contents.lines().map(str::trim) {
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            if entry.contains('@') {
+                self.addresses.insert(entry.to_lowercase());
+            } else {
+                self.domains.insert(entry.to_lowercase());
+            }
+        }
+        self.modified = modified;
+        log!(
+            "loaded {} suppressed address(es) and {} domain(s) from {}",
+            self.addresses.len(),
+            self.domains.len(),
+            path.display()
+        );
+    }
+
+    // Reloads the file if needed and looks up the recipients the SES
+    // suppression list wasn't checked for recently, so that `check`
+    // doesn't wait. A failed lookup is logged and the recipient treated
+    // as not listed, since SES rejects suppressed addresses itself.
+    pub async fn refresh(&mut self, recipients: &[String]) {
+        self.reload();
+        let Some(client) = &self.ses else {
+            return;
+        };
+        let (now, ttl) = (self.clock.now(), self.ttl);
+        self.cache
+            .retain(|_, (_, at)| now.duration_since(*at) < ttl);
+
+        let mut missing: Vec<String> = recipients
+            .iter()
+            .map(|to| to.to_lowercase())
+            .filter(|to| is_valid_recipient(to) && !self.listed(to) && !self.cache.contains_key(to))
+            .collect();
+        missing.sort();
+        missing.dedup();
+
+        let lookups = futures::stream::iter(missing)
+            .map(|to| async move {
+                let result = client
+                    .get_suppressed_destination()
+                    .email_address(&to)
+                    .send()
+                    .await;
+                (to, result)
+            })
+            .buffer_unordered(CONCURRENT_LOOKUPS)
+            .collect::<Vec<_>>()
+            .await;
+        for (to, result) in lookups {
+            let reason = match result {
+                Ok(output) => Some(
+                    output
+                        .suppressed_destination()
+                        .map_or("listed".to_string(), |d| d.reason().as_str().to_lowercase()),
+                ),
+                Err(e)
+                    if e.as_service_error()
+                        .is_some_and(|e| e.is_not_found_exception()) =>
+                {
+                    None
+                }
+                Err(e) => {
+                    log!(
+                        "WARN: failed to look up {} on the SES suppression list: {}",
+                        to,
+                        DisplayErrorContext(&e)
+                    );
+                    continue;
+                }
+            };
+            self.cache.insert(to, (reason, now));
+        }
+    }
+
+    // Whether the lowercase address `to` or its domain is in the file.
+    fn listed(&self, to: &str) -> bool {
+        let domain = to.rsplit_once('@').map_or("", |(_, d)| d);
+        self.addresses.contains(to) || self.domains.contains(domain)
+    }
+
+    // Why `to` must not be sent to, if it must not: the class of the
+    // rejection, "invalid" or "suppressed", and a description.
+    pub fn check(&self, to: &str) -> Option<(&'static str, String)> {
+        if !is_valid_recipient(to) {
+            return Some(("invalid", "invalid recipient address".to_string()));
+        }
+        let to = to.to_lowercase();
+        if self.listed(&to) {
+            return Some((
+                "suppressed",
+                "listed in MAILROOM_SUPPRESSION_FILE".to_string(),
+            ));
+        }
+        match self.cache.get(&to) {
+            Some((Some(reason), _)) => Some((
+                "suppressed",
+                format!("on the SES account suppression list ({})", reason),
+            )),
+            _ => None,
+        }
+    }
+}
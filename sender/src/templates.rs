@@ -0,0 +1,277 @@
+use crate::lookups::Lookups;
+use aws_sdk_ses::types::Template;
+use aws_sdk_ses::Client;
+use mailroom_core::clock::Clock;
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct Entry {
+    template: Option<Template>,
+    // Hash of the parts, to log when a refresh finds the template changed.
+    // SES has no conditional GetTemplate, so every refresh fetches it whole.
+    content_hash: u64,
+    fetched_at: Instant,
+}
+
+// Caches GetTemplate responses so that validation doesn't hit the SES API on
+// every lookup, or, with a template directory, the files read from it.
+// Entries older than `ttl` are refreshed on access; when the refresh fails
+// the last known copy is served instead.
+pub struct TemplateCache {
+    entries: HashMap<String, Entry>,
+    ttl: Duration,
+    dir: Option<PathBuf>,
+    // Where templates fetched from SES are kept across restarts, and for
+    // how long a copy kept there is reused.
+    lookups: Option<Lookups>,
+    lookup_ttl: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+// Reads template `name` from `<dir>/<name>.json`, a file in the format SES
+// CreateTemplate takes, so that the same file can be uploaded to SES.
+pub fn read(dir: &Path, name: &str) -> Result<Option<Template>, String> {
+    let path = dir.join(format!("{}.json", name));
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    let value: Value = serde_json::from_str(&content)
+        .map_err(|e| format!("{}: invalid JSON: {}", path.display(), e))?;
+    let part = |key: &str| {
+        value["Template"][key]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| format!("{}: Template.{} is missing", path.display(), key))
+    };
+    Template::builder()
+        .template_name(name)
+        .subject_part(part("SubjectPart")?)
+        .set_text_part(part("TextPart").ok())
+        .set_html_part(part("HtmlPart").ok())
+        .build()
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+fn content_hash(template: &Option<Template>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    if let Some(t) = template {
+        t.subject_part().hash(&mut hasher);
+        t.text_part().hash(&mut hasher);
+        t.html_part().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// Returns the top-level variables a template references: `name` for
+// {{name}}, {{{name}}}, {{#if name}} and {{name.first}}. Variables inside
+// {{#each}} and {{#with}} blocks are relative to the block and are skipped.
+pub fn variables(template: &Template) -> BTreeSet<String> {
+    let mut vars = BTreeSet::new();
+    for part in [
+        template.subject_part(),
+        template.text_part(),
+        template.html_part(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        let mut scoped: usize = 0;
+        let mut rest = part;
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                break;
+            };
+            let tag =
+                rest[start + 2..start + end].trim_matches(|c| c == '{' || c == '}' || c == '~');
+            rest = &rest[start + end + 2..];
+
+            let mut tokens = tag.split_whitespace();
+            let Some(first) = tokens.next() else {
+                continue;
+            };
+            if first.starts_with('!') || first == "else" {
+                continue;
+            }
+            if let Some(block) = first.strip_prefix('/') {
+                if block == "each" || block == "with" {
+                    scoped = scoped.saturating_sub(1);
+                }
+                continue;
+            }
+            let name = match first.strip_prefix('#') {
+                Some(helper) => {
+                    let outer = scoped == 0;
+                    if helper == "each" || helper == "with" {
+                        scoped += 1;
+                    }
+                    match tokens.next() {
+                        Some(name) if outer => name,
+                        _ => continue,
+                    }
+                }
+                None if scoped > 0 => continue,
+                None => first,
+            };
+
+            let name = name.split('.').next().unwrap_or(name);
+            if !name.is_empty() && name != "this" && !name.starts_with('@') {
+                vars.insert(name.to_string());
+            }
+        }
+    }
+    vars
+}
+
+impl TemplateCache {
+    pub fn new(
+        ttl: Duration,
+        dir: Option<PathBuf>,
+        lookups: Option<Lookups>,
+        lookup_ttl: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        TemplateCache {
+            entries: HashMap::new(),
+            ttl,
+            dir,
+            lookups,
+            lookup_ttl,
+            clock,
+        }
+    }
+
+    // Takes template `name` from the lookups kept across restarts, if a copy
+    // fetched within `lookup_ttl` is there.
+    fn restore(&mut self, name: &str) -> bool {
+        let Some((response, age)) = self
+            .lookups
+            .as_ref()
+            .and_then(|l| l.get(&format!("template:{}", name), self.lookup_ttl))
+        else {
+            return false;
+        };
+        let part = |key: &str| response[key].as_str().map(str::to_string);
+        let Ok(template) = Template::builder()
+            .template_name(name)
+            .set_subject_part(part("subject"))
+            .set_text_part(part("text"))
+            .set_html_part(part("html"))
+            .build()
+        else {
+            return false;
+        };
+        let template = Some(template);
+        let now = self.clock.now();
+        self.entries.insert(
+            name.to_string(),
+            Entry {
+                content_hash: content_hash(&template),
+                template,
+                fetched_at: now.checked_sub(age).unwrap_or(now),
+            },
+        );
+        true
+    }
+
+    async fn fetch(&self, client: &Client, name: &str) -> Result<Option<Template>, String> {
+        if let Some(dir) = &self.dir {
+            return read(dir, name);
+        }
+        match client.get_template().template_name(name).send().await {
+            Ok(output) => Ok(output.template().cloned()),
+            Err(err) => match err.as_service_error() {
+                Some(e) if e.is_template_does_not_exist_exception() => Ok(None),
+                _ => Err(format!("{}", aws_sdk_ses::Error::from(err))),
+            },
+        }
+    }
+
+    pub async fn get(&mut self, client: &Client, name: &str) -> Option<&Template> {
+        let stale = match self.entries.get(name) {
+            Some(entry) => self.clock.now().saturating_duration_since(entry.fetched_at) >= self.ttl,
+            None => !self.restore(name),
+        };
+
+        if stale {
+            match self.fetch(client, name).await {
+                Ok(template) => {
+                    if let (Some(lookups), Some(t), None) =
+                        (&mut self.lookups, &template, &self.dir)
+                    {
+                        lookups.put(
+                            &format!("template:{}", name),
+                            json!({
+                                "subject": t.subject_part(),
+                                "text": t.text_part(),
+                                "html": t.html_part(),
+                            }),
+                        );
+                    }
+                    let hash = content_hash(&template);
+                    if let Some(prev) = self.entries.get(name) {
+                        if prev.content_hash != hash {
+                            log!("template {} changed; hash={:016x}", name, hash);
+                        }
+                    }
+                    self.entries.insert(
+                        name.to_string(),
+                        Entry {
+                            template,
+                            content_hash: hash,
+                            fetched_at: self.clock.now(),
+                        },
+                    );
+                }
+                Err(e) if self.entries.contains_key(name) => {
+                    log!(
+                        "WARN: failed to refresh template {}, using cached copy: {}",
+                        name,
+                        e
+                    );
+                }
+                Err(e) => {
+                    log!("WARN: failed to fetch template {}: {}", name, e);
+                    return None;
+                }
+            }
+        }
+
+        self.entries.get(name).and_then(|e| e.template.as_ref())
+    }
+
+    // Checks that every template exists; returns the names of missing ones.
+    // Templates that couldn't be fetched at all are not reported as missing.
+    pub async fn validate(&mut self, client: &Client, names: &[&str]) -> Vec<String> {
+        let mut missing = Vec::new();
+        for name in names {
+            if self.get(client, name).await.is_none() && self.entries.contains_key(*name) {
+                missing.push(name.to_string());
+            }
+        }
+        missing
+    }
+
+    // Returns the variables template `name` references that are not in
+    // `known`. Templates that couldn't be fetched have none.
+    pub async fn unknown_variables(
+        &mut self,
+        client: &Client,
+        name: &str,
+        known: &BTreeSet<String>,
+    ) -> Vec<String> {
+        match self.get(client, name).await {
+            Some(template) => variables(template).difference(known).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+}
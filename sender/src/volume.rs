@@ -0,0 +1,141 @@
+use chrono::{DateTime, Utc};
+use mailroom_core::clock::Clock;
+use mailroom_core::{registry, MAX_ACTIONS};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const BUCKET: Duration = Duration::from_secs(60);
+// Weight of the latest minute in the baseline.
+const ALPHA: f64 = 0.1;
+
+#[derive(Clone, Copy)]
+struct Rate {
+    started: Instant,
+    count: usize,
+    // Moving average of rows per minute, once a full minute has been seen.
+    baseline: Option<f64>,
+    alerted: bool,
+}
+
+// Tracks input rows per minute for each action against a moving-average
+// baseline, to catch an upstream bug suddenly producing far more rows than
+// usual.
+pub struct Volume {
+    factor: f64,
+    min_rows: usize,
+    rates: [Rate; MAX_ACTIONS],
+    clock: Arc<dyn Clock>,
+}
+
+impl Volume {
+    pub fn new(factor: f64, min_rows: usize, clock: Arc<dyn Clock>) -> Self {
+        Volume {
+            factor,
+            min_rows,
+            rates: [Rate {
+                started: clock.now(),
+                count: 0,
+                baseline: None,
+                alerted: false,
+            }; MAX_ACTIONS],
+            clock,
+        }
+    }
+
+    // Records `rows` input rows for `action`. Returns an alert payload when
+    // the current minute has just exceeded `factor` times the baseline.
+    pub fn record(&mut self, action: usize, rows: usize) -> Option<Value> {
+        let now = self.clock.now();
+        let rate = &mut self.rates[action];
+
+        // Close the minutes that have passed, idle ones counting as zero.
+        let mut elapsed = now.saturating_duration_since(rate.started).as_secs() / BUCKET.as_secs();
+        while elapsed > 0 {
+            rate.baseline = Some(match rate.baseline {
+                Some(b) => b * (1.0 - ALPHA) + rate.count as f64 * ALPHA,
+                None => rate.count as f64,
+            });
+            rate.started += BUCKET;
+            rate.count = 0;
+            rate.alerted = false;
+            elapsed -= 1;
+            // Past an hour of idling the baseline has decayed anyway.
+            if elapsed > 60 {
+                rate.started = now;
+                break;
+            }
+        }
+
+        rate.count += rows;
+
+        let baseline = rate.baseline?;
+        if self.factor <= 0.0
+            || rate.alerted
+            || rate.count < self.min_rows
+            || (rate.count as f64) <= baseline.max(1.0) * self.factor
+        {
+            return None;
+        }
+        rate.alerted = true;
+
+        Some(json!({
+            "type": "input_volume",
+            "action": registry()[action].name,
+            "rows_per_minute": rate.count,
+            "baseline": baseline,
+            "timestamp": DateTime::<Utc>::from(self.clock.system()).to_rfc3339(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailroom_core::clock::ManualClock;
+    use std::time::UNIX_EPOCH;
+
+    fn clock() -> Arc<ManualClock> {
+        // 2023-11-14T22:13:20Z.
+        Arc::new(ManualClock::new(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        ))
+    }
+
+    #[test]
+    fn alerts_once_a_minute_exceeds_the_baseline() {
+        let clock = clock();
+        let mut volume = Volume::new(10.0, 100, clock.clone());
+
+        // No baseline before a full minute was seen.
+        assert_eq!(volume.record(0, 20), None);
+        clock.advance(BUCKET);
+        assert_eq!(volume.record(0, 30), None);
+        clock.advance(BUCKET);
+        // The baseline moves a tenth of the way to the last minute.
+        assert_eq!(volume.record(0, 20), None);
+        clock.advance(Duration::from_secs(30));
+
+        let alert = volume.record(0, 500).unwrap();
+        assert_eq!(alert["rows_per_minute"], 520);
+        assert_eq!(alert["baseline"], 21.0);
+        assert_eq!(alert["timestamp"], "2023-11-14T22:15:50+00:00");
+        assert_eq!(volume.record(0, 500), None, "alerted once a minute");
+    }
+
+    #[test]
+    fn counts_idle_minutes_as_zero() {
+        let clock = clock();
+        let mut volume = Volume::new(10.0, 100, clock.clone());
+
+        volume.record(0, 100);
+        clock.advance(BUCKET);
+        volume.record(0, 0);
+        // Five idle minutes pull the baseline of 100 down to 59.049.
+        clock.advance(BUCKET * 5);
+        assert_eq!(volume.record(0, 500), None);
+        let baseline = volume.rates[0].baseline.unwrap();
+        assert!((baseline - 59.049).abs() < 1e-9, "{}", baseline);
+        assert_eq!(volume.record(0, 91).unwrap()["rows_per_minute"], 591);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::delivery::Delivery;
+use crate::secrets::Secret;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, KeyInit, Mac};
+use mailroom_core::clock::Clock;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::time::Duration;
+
+const SIGNATURE_HEADER: &str = "X-Mailroom-Signature";
+
+// Posts batch results to an HTTP endpoint. When a secret is configured, the
+// body is signed with HMAC-SHA256 and the hex digest sent in the
+// X-Mailroom-Signature header.
+#[derive(Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+    secret: Option<Secret>,
+    retries: u32,
+}
+
+impl Webhook {
+    pub fn new(url: String, secret: Option<Secret>, retries: u32) -> Self {
+        Webhook {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("failed to build HTTP client"),
+            url,
+            secret,
+            retries,
+        }
+    }
+
+    fn sign(&self, body: &[u8]) -> Option<String> {
+        let secret = self.secret.as_ref()?.get();
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+        mac.update(body);
+        Some(hex::encode(mac.finalize().into_bytes()))
+    }
+
+    // Delivers `payload`, retrying with exponential backoff on connection
+    // errors and non-2xx responses.
+    pub async fn post(&self, payload: &Value) -> Result<(), String> {
+        let body = payload.to_string();
+        let signature = self.sign(body.as_bytes());
+
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .client
+                .post(&self.url)
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            let err = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => format!("unexpected status {}", response.status()),
+                Err(e) => e.to_string(),
+            };
+
+            if attempt >= self.retries {
+                return Err(format!("{} (after {} attempts)", err, attempt + 1));
+            }
+
+            tokio::time::sleep(Duration::from_millis(500 << attempt.min(10))).await;
+            attempt += 1;
+        }
+    }
+}
+
+// Builds the webhook payload for one bulk send: the outcome of each
+// recipient, along with the status the provider reported.
+pub fn batch_results(
+    clock: &dyn Clock,
+    template: &str,
+    recipients: &[String],
+    deliveries: &[Delivery],
+) -> Value {
+    let results: Vec<Value> = recipients
+        .iter()
+        .zip(deliveries)
+        .map(|(to, delivery)| {
+            json!({
+                "to": to,
+                "outcome": delivery.outcome.as_str(),
+                "status": delivery.code,
+                "class": delivery.class.map(|c| c.as_str()),
+                "message_id": delivery.message_id,
+                "error": delivery.error,
+            })
+        })
+        .collect();
+
+    json!({
+        "template": template,
+        "timestamp": DateTime::<Utc>::from(clock.system()).to_rfc3339(),
+        "results": results,
+    })
+}
+
+// Builds the webhook payload for recipients that were not sent to, such as
+// those outside the allowlist ("filtered") or whose deadline passed
+// ("expired").
+pub fn skipped_results(
+    clock: &dyn Clock,
+    template: &str,
+    outcome: &str,
+    recipients: &[String],
+) -> Value {
+    let mut status = outcome.to_string();
+    status[..1].make_ascii_uppercase();
+    let results: Vec<Value> = recipients
+        .iter()
+        .map(|to| {
+            json!({"to": to, "outcome": outcome, "status": status, "class": null, "error": null})
+        })
+        .collect();
+
+    json!({
+        "template": template,
+        "timestamp": DateTime::<Utc>::from(clock.system()).to_rfc3339(),
+        "results": results,
+    })
+}